@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE state_entries;
+DROP TABLE rate_limit_buckets;
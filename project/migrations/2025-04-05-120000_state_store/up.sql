@@ -0,0 +1,15 @@
+-- Token buckets shared by every server instance
+CREATE TABLE rate_limit_buckets (
+    key VARCHAR(255) PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- Generic expiring key/value entries (token revocations, sticky sessions)
+CREATE TABLE state_entries (
+    key VARCHAR(255) PRIMARY KEY,
+    value TEXT NOT NULL,
+    expires_at TIMESTAMP
+);
+
+CREATE INDEX state_entries_expires_at_idx ON state_entries (expires_at);
@@ -0,0 +1,7 @@
+-- Failed login tracking, keyed by "user:<name>" or "ip:<address>"
+CREATE TABLE login_throttles (
+    subject VARCHAR(255) PRIMARY KEY,
+    failed_count INTEGER NOT NULL DEFAULT 0,
+    last_failed_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    locked_until TIMESTAMP
+);
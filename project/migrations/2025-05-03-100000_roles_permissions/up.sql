@@ -0,0 +1,56 @@
+-- Roles and the permissions they grant. Routes check permissions, so new
+-- roles can be added here without code changes.
+CREATE TABLE roles (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(50) NOT NULL UNIQUE,
+    description TEXT NOT NULL DEFAULT '',
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE permissions (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(100) NOT NULL UNIQUE,
+    description TEXT NOT NULL DEFAULT ''
+);
+
+CREATE TABLE role_permissions (
+    role_id INTEGER NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+    permission_id INTEGER NOT NULL REFERENCES permissions(id) ON DELETE CASCADE,
+    PRIMARY KEY (role_id, permission_id)
+);
+
+CREATE TABLE user_roles (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role_id INTEGER NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, role_id)
+);
+
+CREATE INDEX user_roles_role_id_idx ON user_roles (role_id);
+
+INSERT INTO roles (name, description) VALUES
+    ('User', 'Regular account'),
+    ('Admin', 'Full administrative access');
+
+INSERT INTO permissions (name, description) VALUES
+    ('private:read', 'View the private page'),
+    ('admin:read', 'View the admin page'),
+    ('users:read', 'List user accounts'),
+    ('users:write', 'Create user accounts with any role'),
+    ('sessions:revoke', 'Revoke the sessions of any user'),
+    ('accounts:unlock', 'Lift login lockouts');
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT roles.id, permissions.id FROM roles, permissions
+WHERE roles.name = 'Admin'
+   OR (roles.name = 'User' AND permissions.name = 'private:read');
+
+-- users.role stays as the role named in access tokens; user_roles is what
+-- permissions are resolved from. Unknown role strings used to mean User
+UPDATE users SET role = 'Admin' WHERE lower(role) = 'admin';
+UPDATE users SET role = 'User' WHERE role <> 'Admin';
+
+ALTER TABLE users
+    ADD CONSTRAINT users_role_fkey FOREIGN KEY (role) REFERENCES roles(name) ON UPDATE CASCADE;
+
+INSERT INTO user_roles (user_id, role_id)
+SELECT users.id, roles.id FROM users JOIN roles ON roles.name = users.role;
@@ -0,0 +1,13 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM roles WHERE name IN ('Moderator', 'Auditor');
+
+ALTER TABLE users ADD COLUMN role VARCHAR(50) NOT NULL DEFAULT 'User';
+UPDATE users SET role = 'Admin'
+WHERE id IN (
+    SELECT user_roles.user_id FROM user_roles
+    JOIN roles ON roles.id = user_roles.role_id
+    WHERE roles.name = 'Admin'
+);
+ALTER TABLE users ALTER COLUMN role DROP DEFAULT;
+ALTER TABLE users
+    ADD CONSTRAINT users_role_fkey FOREIGN KEY (role) REFERENCES roles(name) ON UPDATE CASCADE;
@@ -0,0 +1,15 @@
+-- Users can now hold several roles, so user_roles becomes the only record
+-- of who holds what and users.role goes away.
+ALTER TABLE users DROP CONSTRAINT users_role_fkey;
+ALTER TABLE users DROP COLUMN role;
+
+INSERT INTO roles (name, description) VALUES
+    ('Moderator', 'Manages other users'' sessions and lockouts'),
+    ('Auditor', 'Read-only access to accounts and the admin page');
+
+-- Inherited grants (e.g. Moderator from User) come from the configured
+-- role hierarchy, so only each role's own permissions are listed here
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT roles.id, permissions.id FROM roles, permissions
+WHERE (roles.name = 'Moderator' AND permissions.name IN ('sessions:revoke', 'accounts:unlock'))
+   OR (roles.name = 'Auditor' AND permissions.name IN ('users:read', 'admin:read'));
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM permissions WHERE name = 'roles:write';
+ALTER TABLE roles DROP COLUMN require_2fa;
+DROP TABLE recovery_codes;
+DROP TABLE user_totp;
@@ -0,0 +1,31 @@
+-- TOTP (RFC 6238) second factor. A secret is pending until the user proves
+-- possession with a first code; last_used_step stops a code being replayed.
+CREATE TABLE user_totp (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    secret VARCHAR(64) NOT NULL,
+    confirmed_at TIMESTAMP,
+    last_used_step BIGINT,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- One-time recovery codes; only the SHA-256 of each code is stored
+CREATE TABLE recovery_codes (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash VARCHAR(64) NOT NULL,
+    used_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
+
+-- Holders of a role with require_2fa set must complete a second factor
+-- at login, enrolling on the spot if they have not yet
+ALTER TABLE roles ADD COLUMN require_2fa BOOLEAN NOT NULL DEFAULT FALSE;
+
+INSERT INTO permissions (name, description) VALUES
+    ('roles:write', 'Change role settings such as required 2FA');
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT roles.id, permissions.id FROM roles, permissions
+WHERE roles.name = 'Admin' AND permissions.name = 'roles:write';
@@ -0,0 +1,14 @@
+-- Address account mail such as password resets is sent to
+ALTER TABLE users ADD COLUMN email VARCHAR(255) UNIQUE;
+
+-- Single-use password reset tokens; only the SHA-256 of each is stored
+CREATE TABLE password_reset_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX password_reset_tokens_user_id_idx ON password_reset_tokens (user_id);
@@ -0,0 +1,16 @@
+-- Set once the user proves they receive mail at users.email
+ALTER TABLE users ADD COLUMN email_verified_at TIMESTAMP;
+
+-- Single-use verification tokens; only the SHA-256 of each is stored.
+-- The address is kept so a token only ever verifies the address it was sent to
+CREATE TABLE email_verification_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    email VARCHAR(255) NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX email_verification_tokens_user_id_idx ON email_verification_tokens (user_id);
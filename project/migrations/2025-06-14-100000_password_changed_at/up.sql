@@ -0,0 +1,2 @@
+-- When the user last chose a new password, by changing or resetting it
+ALTER TABLE users ADD COLUMN password_changed_at TIMESTAMP;
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM permissions WHERE name = 'oauth:clients';
+DROP TABLE oauth_refresh_tokens;
+DROP TABLE oauth_authorization_codes;
+DROP TABLE oauth_clients;
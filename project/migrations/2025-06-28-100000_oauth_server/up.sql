@@ -0,0 +1,50 @@
+-- Applications allowed to obtain tokens from our OAuth2 endpoints. Public
+-- clients have no secret and must use PKCE; for confidential ones only the
+-- SHA-256 of the secret is stored.
+CREATE TABLE oauth_clients (
+    id SERIAL PRIMARY KEY,
+    client_id VARCHAR(64) NOT NULL UNIQUE,
+    name VARCHAR(100) NOT NULL,
+    secret_hash VARCHAR(64),
+    redirect_uris TEXT[] NOT NULL,
+    grant_types TEXT[] NOT NULL,
+    -- Permissions tokens for this client may carry
+    scopes TEXT[] NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- Single-use authorization codes, redeemed at /oauth/token
+CREATE TABLE oauth_authorization_codes (
+    id SERIAL PRIMARY KEY,
+    oauth_client_id INTEGER NOT NULL REFERENCES oauth_clients(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash VARCHAR(64) NOT NULL UNIQUE,
+    redirect_uri TEXT NOT NULL,
+    scopes TEXT[] NOT NULL,
+    -- S256 PKCE challenge, if the client sent one
+    code_challenge VARCHAR(128),
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- Refresh tokens issued to clients; rotated on every use
+CREATE TABLE oauth_refresh_tokens (
+    id SERIAL PRIMARY KEY,
+    oauth_client_id INTEGER NOT NULL REFERENCES oauth_clients(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    scopes TEXT[] NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    revoked_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX oauth_refresh_tokens_client_user_idx ON oauth_refresh_tokens (oauth_client_id, user_id);
+
+INSERT INTO permissions (name, description) VALUES
+    ('oauth:clients', 'Register and remove OAuth clients');
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT roles.id, permissions.id FROM roles, permissions
+WHERE roles.name = 'Admin' AND permissions.name = 'oauth:clients';
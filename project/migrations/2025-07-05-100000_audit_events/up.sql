@@ -0,0 +1,29 @@
+-- Append-only record of security-relevant events: logins, account and role
+-- changes, token revocations and admin page access.
+CREATE TABLE audit_events (
+    id SERIAL PRIMARY KEY,
+    -- e.g. 'login', 'user.create', 'user.roles'
+    action VARCHAR(64) NOT NULL,
+    -- 'success' or 'failure'
+    outcome VARCHAR(16) NOT NULL,
+    -- Username or OAuth client that acted; NULL when nobody was authenticated
+    actor VARCHAR(255),
+    -- What was acted on, e.g. the user whose roles changed
+    target VARCHAR(255),
+    details TEXT,
+    ip VARCHAR(64),
+    user_agent VARCHAR(512),
+    request_id VARCHAR(64),
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX audit_events_created_at_idx ON audit_events (created_at);
+CREATE INDEX audit_events_actor_idx ON audit_events (actor);
+CREATE INDEX audit_events_target_idx ON audit_events (target);
+
+INSERT INTO permissions (name, description) VALUES
+    ('audit:read', 'Query the audit log');
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT roles.id, permissions.id FROM roles, permissions
+WHERE roles.name = 'Admin' AND permissions.name = 'audit:read';
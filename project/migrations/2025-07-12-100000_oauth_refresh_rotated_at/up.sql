@@ -0,0 +1,3 @@
+-- When a refresh token was exchanged for a new one. Only presenting a rotated
+-- token again counts as reuse; tokens revoked by the client are just invalid
+ALTER TABLE oauth_refresh_tokens ADD COLUMN rotated_at TIMESTAMP;
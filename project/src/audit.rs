@@ -0,0 +1,248 @@
+use crate::{db, errors, models, rate_limit, security};
+use log::error;
+use std::sync::Arc;
+use warp::{reject, Filter, Rejection};
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_USER_AGENT_CHARS: usize = 512;
+
+/// Settings from the `[audit]` section of the server config
+#[derive(Clone, Debug)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    pub default_page_size: i64,
+    pub max_page_size: i64,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            enabled: true,
+            default_page_size: 50,
+            max_page_size: 200,
+        }
+    }
+}
+
+impl AuditConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = AuditConfig::default();
+        let section = match config.get("audit") {
+            Some(section) => section,
+            None => return defaults,
+        };
+
+        let max_page_size = section
+            .get("max_page_size")
+            .and_then(|v| v.as_integer())
+            .map(|v| v.max(1))
+            .unwrap_or(defaults.max_page_size);
+        AuditConfig {
+            enabled: section
+                .get("enabled")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.enabled),
+            default_page_size: section
+                .get("default_page_size")
+                .and_then(|v| v.as_integer())
+                .unwrap_or(defaults.default_page_size)
+                .clamp(1, max_page_size),
+            max_page_size,
+        }
+    }
+}
+
+const SUCCESS: &str = "success";
+const FAILURE: &str = "failure";
+
+/// Where a request came from, stored with every event it causes
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    pub ip: String,
+    pub user_agent: Option<String>,
+    pub request_id: String,
+}
+
+// A caller-supplied request ID is kept so events can be matched with the
+// caller's own logs, as long as it is short and plain
+fn well_formed_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// Records security-relevant events in the `audit_events` table. Failing to
+/// record an event is logged but never fails the request that caused it
+pub struct AuditLog {
+    config: AuditConfig,
+}
+
+impl AuditLog {
+    pub fn new(config: AuditConfig) -> Self {
+        AuditLog { config }
+    }
+
+    fn record(
+        &self,
+        conn: &mut db::DbConnection,
+        context: Option<&RequestContext>,
+        event: models::NewAuditEvent,
+    ) {
+        if !self.config.enabled {
+            return;
+        }
+
+        let event = match context {
+            Some(context) => models::NewAuditEvent {
+                ip: Some(context.ip.clone()),
+                user_agent: context.user_agent.clone(),
+                request_id: Some(context.request_id.clone()),
+                ..event
+            },
+            None => event,
+        };
+        if let Err(e) = db::AuditRepository::create(conn, &event) {
+            error!(
+                "Failed to record audit event {} for request {}: {}",
+                event.action,
+                event.request_id.as_deref().unwrap_or("-"),
+                e
+            );
+        }
+    }
+
+    /// One page of events matching the query, newest first
+    pub fn search(
+        &self,
+        conn: &mut db::DbConnection,
+        query: &models::AuditQuery,
+    ) -> Result<models::AuditPage, Rejection> {
+        if let Some(outcome) = &query.outcome {
+            if outcome != SUCCESS && outcome != FAILURE {
+                return Err(reject::custom(errors::CustomError::InvalidRequestError(
+                    "outcome must be success or failure".to_string(),
+                )));
+            }
+        }
+
+        let page = query.page.unwrap_or(1).max(1);
+        let per_page = query
+            .per_page
+            .unwrap_or(self.config.default_page_size)
+            .clamp(1, self.config.max_page_size);
+        let (events, total) =
+            db::AuditRepository::search(conn, query, (page - 1) * per_page, per_page).map_err(
+                |e| {
+                    error!("Failed to query audit events: {}", e);
+                    reject::custom(errors::CustomError::InternalError)
+                },
+            )?;
+
+        Ok(models::AuditPage {
+            events,
+            page,
+            per_page,
+            total,
+        })
+    }
+}
+
+/// The audit log together with the context of the current request, handed
+/// to handlers by `with_audit`
+#[derive(Clone)]
+pub struct Audit {
+    log: Arc<AuditLog>,
+    // None for events outside any request, such as command line actions
+    context: Option<RequestContext>,
+}
+
+impl Audit {
+    /// For events caused by the server itself or a command line tool rather
+    /// than a request; they are stored without IP, user agent or request ID
+    pub fn without_request(log: Arc<AuditLog>) -> Self {
+        Audit { log, context: None }
+    }
+
+    fn event(
+        &self,
+        conn: &mut db::DbConnection,
+        action: &str,
+        outcome: &str,
+        actor: Option<&str>,
+        target: Option<&str>,
+        details: Option<String>,
+    ) {
+        self.log.record(
+            conn,
+            self.context.as_ref(),
+            models::NewAuditEvent {
+                action: action.to_string(),
+                outcome: outcome.to_string(),
+                actor: actor.map(str::to_string),
+                target: target.map(str::to_string),
+                details,
+                ip: None,
+                user_agent: None,
+                request_id: None,
+            },
+        );
+    }
+
+    /// Record that `actor` (if anyone was authenticated) did `action` to `target`
+    pub fn success(
+        &self,
+        conn: &mut db::DbConnection,
+        action: &str,
+        actor: Option<&str>,
+        target: Option<&str>,
+        details: Option<String>,
+    ) {
+        self.event(conn, action, SUCCESS, actor, target, details);
+    }
+
+    /// Record an attempt at `action` that was refused
+    pub fn failure(
+        &self,
+        conn: &mut db::DbConnection,
+        action: &str,
+        actor: Option<&str>,
+        target: Option<&str>,
+        details: Option<String>,
+    ) {
+        self.event(conn, action, FAILURE, actor, target, details);
+    }
+}
+
+/// Extract an `Audit` for the request. The client address is resolved the
+/// same way as for rate limits; the request ID comes from `X-Request-Id`
+/// when the caller sent a usable one and is generated otherwise
+pub fn with_audit(
+    log: Arc<AuditLog>,
+    limiter: Arc<rate_limit::RateLimiter>,
+) -> impl Filter<Extract = (Audit,), Error = Rejection> + Clone {
+    rate_limit::with_client_ip(limiter)
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::header::optional::<String>(REQUEST_ID_HEADER))
+        .and(warp::any().map(move || log.clone()))
+        .map(
+            |ip: String,
+             user_agent: Option<String>,
+             request_id: Option<String>,
+             log: Arc<AuditLog>| {
+                let request_id = request_id
+                    .filter(|id| well_formed_request_id(id))
+                    .unwrap_or_else(|| security::generate_token()[..32].to_string());
+                Audit {
+                    log,
+                    context: Some(RequestContext {
+                        ip,
+                        user_agent: user_agent
+                            .map(|agent| agent.chars().take(MAX_USER_AGENT_CHARS).collect()),
+                        request_id,
+                    }),
+                }
+            },
+        )
+}
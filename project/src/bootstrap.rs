@@ -0,0 +1,136 @@
+use crate::{audit, db, hashing, models, password_policy, security};
+use log::{error, info, warn};
+use std::{env, io};
+
+/// Handle command line subcommands. Returns true when a command ran and the
+/// server should not be started.
+///
+/// `create-admin <username>` creates an Admin account, reading the password
+/// from `BOOTSTRAP_ADMIN_PASSWORD` or, failing that, from stdin.
+pub fn run_command(db_pool: &db::DbPool, audit: &audit::Audit) -> bool {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(|a| a.as_str()) {
+        Some("create-admin") => {
+            let username = match args.get(1) {
+                Some(username) => username.clone(),
+                None => {
+                    eprintln!("Usage: create-admin <username>");
+                    std::process::exit(2);
+                }
+            };
+
+            let password = match env::var("BOOTSTRAP_ADMIN_PASSWORD") {
+                Ok(password) => password,
+                Err(_) => {
+                    eprintln!("Password for {}:", username);
+                    let mut line = String::new();
+                    io::stdin()
+                        .read_line(&mut line)
+                        .expect("Failed to read password from stdin");
+                    line.trim_end_matches(['\r', '\n']).to_string()
+                }
+            };
+
+            if let Err(message) = create_admin(db_pool, audit, "cli", &username, &password) {
+                eprintln!("{}", message);
+                std::process::exit(1);
+            }
+            println!("Admin {} created", username);
+            true
+        }
+        Some(other) => {
+            eprintln!("Unknown command: {}", other);
+            std::process::exit(2);
+        }
+        None => false,
+    }
+}
+
+/// Create the first admin from `BOOTSTRAP_ADMIN_USERNAME` and
+/// `BOOTSTRAP_ADMIN_PASSWORD` when no Admin account exists yet
+pub fn ensure_admin_from_env(db_pool: &db::DbPool, audit: &audit::Audit) {
+    let (username, password) = match (
+        env::var("BOOTSTRAP_ADMIN_USERNAME"),
+        env::var("BOOTSTRAP_ADMIN_PASSWORD"),
+    ) {
+        (Ok(username), Ok(password)) => (username, password),
+        _ => return,
+    };
+
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return;
+        }
+    };
+
+    match db::UserRepository::exists_with_role(&mut conn, security::ADMIN_ROLE) {
+        Ok(true) => {
+            info!("Admin account already present, skipping bootstrap admin");
+        }
+        Ok(false) => match create_admin(db_pool, audit, "system", &username, &password) {
+            Ok(()) => warn!(target: "security", "Bootstrap admin {} created", username),
+            Err(message) => error!("{}", message),
+        },
+        Err(e) => error!("Failed to look up admin accounts: {}", e),
+    }
+}
+
+// `actor` names what created the admin in the audit log: "cli" for the
+// create-admin command, "system" for the environment bootstrap
+fn create_admin(
+    db_pool: &db::DbPool,
+    audit: &audit::Audit,
+    actor: &str,
+    username: &str,
+    password: &str,
+) -> Result<(), String> {
+    if username.is_empty() || password.is_empty() {
+        return Err("Admin username and password must not be empty".to_string());
+    }
+
+    let violations = password_policy::policy().violations(password, username);
+    if !violations.is_empty() {
+        let rules = violations
+            .iter()
+            .map(|v| format!("password {}", v.message))
+            .collect::<Vec<_>>();
+        return Err(format!("Admin password rejected: {}", rules.join("; ")));
+    }
+
+    let mut conn = db_pool
+        .get()
+        .map_err(|e| format!("Failed to get database connection: {}", e))?;
+
+    if db::UserRepository::find_by_username(&mut conn, username).is_some() {
+        return Err(format!("User {} already exists", username));
+    }
+
+    let role = db::RoleRepository::find_by_name(&mut conn, security::ADMIN_ROLE)
+        .map_err(|e| format!("Failed to look up the admin role: {}", e))?
+        .ok_or_else(|| {
+            format!(
+                "Role {} is missing, run the migrations",
+                security::ADMIN_ROLE
+            )
+        })?;
+
+    let password_hash = hashing::hash_password(password).map_err(|e| e.to_string())?;
+    let new_user = models::NewUser {
+        username: username.to_string(),
+        password: password_hash,
+        email: None,
+    };
+
+    db::UserRepository::create_user_with_roles(&mut conn, &new_user, &[role])
+        .map_err(|e| format!("Failed to create admin {}: {}", username, e))?;
+    audit.success(
+        &mut conn,
+        "user.create",
+        Some(actor),
+        Some(username),
+        Some(format!("roles: {}", security::ADMIN_ROLE)),
+    );
+    Ok(())
+}
@@ -0,0 +1,369 @@
+use hyper::body::Bytes;
+use log::{debug, error};
+use std::{
+    io::{self, Write},
+    sync::Arc,
+};
+use warp::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    pub fn from_str(encoding: &str) -> Option<Encoding> {
+        match encoding.trim().to_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Settings from the `[compression]` section of the server config
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub proxy: bool,
+    pub min_size: usize,
+    pub level: u32,
+    pub algorithms: Vec<Encoding>,
+    pub mime_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: false,
+            proxy: false,
+            min_size: 1024,
+            level: 6,
+            algorithms: vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip],
+            mime_types: vec![
+                "text/*".to_string(),
+                "application/json".to_string(),
+                "application/javascript".to_string(),
+            ],
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = CompressionConfig::default();
+        let section = match config.get("compression") {
+            Some(section) => section,
+            None => return defaults,
+        };
+
+        let algorithms = section
+            .get("algorithms")
+            .and_then(|v| v.as_array())
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .filter_map(Encoding::from_str)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or(defaults.algorithms);
+
+        let mime_types = section
+            .get("mime_types")
+            .and_then(|v| v.as_array())
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .map(|v| v.to_lowercase())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or(defaults.mime_types);
+
+        CompressionConfig {
+            enabled: section
+                .get("enabled")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.enabled),
+            proxy: section
+                .get("proxy")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.proxy),
+            min_size: section
+                .get("min_size")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.max(0) as usize)
+                .unwrap_or(defaults.min_size),
+            level: section
+                .get("level")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.clamp(1, 9) as u32)
+                .unwrap_or(defaults.level),
+            algorithms,
+            mime_types,
+        }
+    }
+
+    fn allows_mime(&self, content_type: &str) -> bool {
+        // Only the essence of the media type matters, e.g. "text/html; charset=utf-8"
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+
+        self.mime_types.iter().any(|allowed| {
+            if let Some(prefix) = allowed.strip_suffix("/*") {
+                essence.split('/').next() == Some(prefix)
+            } else {
+                *allowed == essence
+            }
+        })
+    }
+}
+
+/// Pick the best encoding from an `Accept-Encoding` header, honouring q-values
+/// and using the configured algorithm order to break ties
+pub fn negotiate(accept_encoding: &str, config: &CompressionConfig) -> Option<Encoding> {
+    let mut wildcard_q: Option<f32> = None;
+    let mut accepted: Vec<(Encoding, f32)> = Vec::new();
+
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let q = parts
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if name == "*" {
+            wildcard_q = Some(q);
+        } else if let Some(encoding) = Encoding::from_str(name) {
+            accepted.push((encoding, q));
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in &config.algorithms {
+        let q = accepted
+            .iter()
+            .find(|(e, _)| e == encoding)
+            .map(|(_, q)| *q)
+            .or(wildcard_q)
+            .unwrap_or(0.0);
+
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((*encoding, q));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+pub fn compress(data: &[u8], encoding: Encoding, level: u32) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(
+                Vec::with_capacity(data.len() / 2),
+                flate2::Compression::new(level),
+            );
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut output = Vec::with_capacity(data.len() / 2);
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut output, 4096, level, 22);
+                writer.write_all(data)?;
+                writer.flush()?;
+            }
+            Ok(output)
+        }
+        Encoding::Zstd => zstd::encode_all(data, level as i32),
+    }
+}
+
+// Whether a Vary header already lists `name`, or `*` which covers every header
+fn varies_on(headers: &HeaderMap, name: &str) -> bool {
+    headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|listed| listed == "*" || listed.eq_ignore_ascii_case(name))
+}
+
+/// Compress a buffered body in place if the response qualifies. Returns the
+/// body that should be sent, updating `Content-Encoding`, `Content-Length`
+/// and `Vary` on the way
+pub fn encode_body(
+    status: StatusCode,
+    headers: &mut HeaderMap,
+    body: Bytes,
+    accept_encoding: Option<&str>,
+    config: &CompressionConfig,
+) -> Bytes {
+    // Make caches aware that the representation depends on Accept-Encoding
+    if config.enabled && !varies_on(headers, "accept-encoding") {
+        headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+
+    if !config.enabled
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || body.len() < config.min_size
+        || headers.contains_key(header::CONTENT_ENCODING)
+    {
+        return body;
+    }
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if !config.allows_mime(content_type) {
+        return body;
+    }
+
+    let encoding = match accept_encoding.and_then(|ae| negotiate(ae, config)) {
+        Some(encoding) => encoding,
+        None => return body,
+    };
+
+    match compress(&body, encoding, config.level) {
+        Ok(compressed) => {
+            debug!(
+                "Compressed response with {}: {} -> {} bytes",
+                encoding.as_str(),
+                body.len(),
+                compressed.len()
+            );
+            headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.as_str()),
+            );
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+            Bytes::from(compressed)
+        }
+        Err(e) => {
+            error!(
+                "Failed to compress response with {}: {}",
+                encoding.as_str(),
+                e
+            );
+            body
+        }
+    }
+}
+
+pub async fn compress_response(
+    response: Response,
+    accept_encoding: Option<String>,
+    config: Arc<CompressionConfig>,
+) -> Response {
+    if !config.enabled {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body_bytes = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Failed to read response body for compression: {}", e);
+            return Response::from_parts(parts, hyper::Body::empty());
+        }
+    };
+
+    let body_bytes = encode_body(
+        parts.status,
+        &mut parts.headers,
+        body_bytes,
+        accept_encoding.as_deref(),
+        &config,
+    );
+
+    Response::from_parts(parts, body_bytes.into())
+}
+
+/// Wrap a set of routes so that their replies are compressed according to the
+/// client's `Accept-Encoding` header
+pub fn with_compression<F, T>(
+    filter: F,
+    config: Arc<CompressionConfig>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    warp::header::optional::<String>("accept-encoding")
+        .and(filter)
+        .and(warp::any().map(move || config.clone()))
+        .then(
+            |accept_encoding: Option<String>, reply: T, config: Arc<CompressionConfig>| async move {
+                compress_response(reply.into_response(), accept_encoding, config).await
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vary(headers: &HeaderMap) -> Vec<&str> {
+        headers
+            .get_all(header::VARY)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    fn encode_with_vary(existing: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in existing {
+            headers.append(header::VARY, HeaderValue::from_static(value));
+        }
+        encode_body(
+            StatusCode::OK,
+            &mut headers,
+            Bytes::from_static(b"short"),
+            Some("gzip"),
+            &CompressionConfig {
+                enabled: true,
+                ..CompressionConfig::default()
+            },
+        );
+        headers
+    }
+
+    #[test]
+    fn vary_gains_accept_encoding_once() {
+        assert_eq!(vary(&encode_with_vary(&[])), vec!["accept-encoding"]);
+        assert_eq!(
+            vary(&encode_with_vary(&["origin"])),
+            vec!["origin", "accept-encoding"]
+        );
+        assert_eq!(
+            vary(&encode_with_vary(&["Origin, Accept-Encoding"])),
+            vec!["Origin, Accept-Encoding"]
+        );
+        assert_eq!(vary(&encode_with_vary(&["*"])), vec!["*"]);
+    }
+}
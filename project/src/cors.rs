@@ -0,0 +1,429 @@
+use crate::errors;
+use log::{debug, warn};
+use std::{convert::Infallible, sync::Arc};
+use warp::{
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+/// Settings from the `[cors]` section of the server config, shared by the
+/// backends and the proxy
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    // Exact origins, lowercased
+    pub origins: Vec<String>,
+    // Origins containing `*`, which matches any run of characters but `/`
+    pub origin_patterns: Vec<String>,
+    // A lone "*" entry; never combined with credentials
+    pub any_origin: bool,
+    pub methods: Vec<Method>,
+    // Lowercased
+    pub headers: Vec<String>,
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_secs: Option<u64>,
+}
+
+impl Default for CorsConfig {
+    // Same-origin only until origins are configured
+    fn default() -> Self {
+        CorsConfig {
+            origins: Vec::new(),
+            origin_patterns: Vec::new(),
+            any_origin: false,
+            methods: vec![Method::GET, Method::POST, Method::PUT, Method::DELETE],
+            headers: vec!["authorization".to_string(), "content-type".to_string()],
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age_secs: Some(600),
+        }
+    }
+}
+
+fn string_list(value: &toml::Value) -> Option<Vec<String>> {
+    value.as_array().map(|values| {
+        values
+            .iter()
+            .filter_map(|v| v.as_str().map(|v| v.trim().to_string()))
+            .filter(|v| !v.is_empty())
+            .collect()
+    })
+}
+
+impl CorsConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = CorsConfig::default();
+        let section = match config.get("cors") {
+            Some(section) => section,
+            None => return defaults,
+        };
+        let list = |name: &str| section.get(name).and_then(string_list);
+
+        let mut origins = Vec::new();
+        let mut origin_patterns = Vec::new();
+        let mut any_origin = false;
+        for origin in list("allowed_origins").unwrap_or_default() {
+            let origin = origin.to_lowercase();
+            if origin == "*" {
+                any_origin = true;
+            } else if origin.contains('*') {
+                origin_patterns.push(origin);
+            } else {
+                origins.push(origin.trim_end_matches('/').to_string());
+            }
+        }
+
+        let methods = match list("allowed_methods") {
+            Some(methods) => methods
+                .iter()
+                .filter_map(
+                    |method| match Method::from_bytes(method.to_uppercase().as_bytes()) {
+                        Ok(method) => Some(method),
+                        Err(_) => {
+                            warn!("Ignoring invalid CORS method '{}'", method);
+                            None
+                        }
+                    },
+                )
+                .collect(),
+            None => defaults.methods,
+        };
+
+        let mut allow_credentials = section
+            .get("allow_credentials")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(defaults.allow_credentials);
+        // Any site could then act with the visitor's cookies
+        if any_origin && allow_credentials {
+            warn!("CORS allow_credentials is ignored while any origin is allowed");
+            allow_credentials = false;
+        }
+
+        CorsConfig {
+            origins,
+            origin_patterns,
+            any_origin,
+            methods,
+            headers: list("allowed_headers")
+                .map(|headers| headers.iter().map(|h| h.to_lowercase()).collect())
+                .unwrap_or(defaults.headers),
+            exposed_headers: list("exposed_headers").unwrap_or(defaults.exposed_headers),
+            allow_credentials,
+            max_age_secs: match section.get("max_age_secs").and_then(|v| v.as_integer()) {
+                Some(secs) if secs > 0 => Some(secs as u64),
+                Some(_) => None,
+                None => defaults.max_age_secs,
+            },
+        }
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        let origin = origin.to_lowercase();
+        self.any_origin
+            || self.origins.contains(&origin)
+            || self
+                .origin_patterns
+                .iter()
+                .any(|pattern| glob_match(pattern.as_bytes(), origin.as_bytes()))
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m.as_str() == method)
+    }
+
+    fn allows_headers(&self, requested: &str) -> bool {
+        requested
+            .split(',')
+            .map(|h| h.trim().to_lowercase())
+            .filter(|h| !h.is_empty())
+            .all(|h| self.headers.contains(&h))
+    }
+
+    // Headers every response to an allowed origin gets. The origin is echoed
+    // rather than sending "*", so responses vary by it
+    fn insert_origin_headers(&self, origin: &str, headers: &mut HeaderMap) {
+        if let Ok(origin) = HeaderValue::from_str(origin) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        headers.append(header::VARY, HeaderValue::from_static("origin"));
+    }
+
+    /// Add the CORS headers for the request's origin to an actual (not
+    /// preflight) response. Responses to other origins get none, so the
+    /// browser keeps them from the calling page
+    pub fn apply(&self, origin: Option<&str>, headers: &mut HeaderMap) {
+        let origin = match origin {
+            Some(origin) if self.allows_origin(origin) => origin,
+            _ => {
+                headers.append(header::VARY, HeaderValue::from_static("origin"));
+                return;
+            }
+        };
+
+        self.insert_origin_headers(origin, headers);
+        if !self.exposed_headers.is_empty() {
+            if let Ok(exposed) = HeaderValue::from_str(&self.exposed_headers.join(", ")) {
+                headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+            }
+        }
+    }
+
+    /// Answer a preflight request. Refusals carry no CORS headers, which is
+    /// what makes the browser block the actual request
+    pub fn preflight(
+        &self,
+        origin: &str,
+        request_method: &str,
+        request_headers: Option<&str>,
+    ) -> Response {
+        let refusal = if !self.allows_origin(origin) {
+            Some("origin not allowed")
+        } else if !self.allows_method(request_method) {
+            Some("method not allowed")
+        } else if !request_headers.is_none_or(|requested| self.allows_headers(requested)) {
+            Some("headers not allowed")
+        } else {
+            None
+        };
+        if let Some(reason) = refusal {
+            debug!(
+                "CORS preflight from {} for {} refused: {}",
+                origin, request_method, reason
+            );
+            return errors::reply_with_status(StatusCode::FORBIDDEN, "CORS request not allowed")
+                .into_response();
+        }
+
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        self.insert_origin_headers(origin, headers);
+        let methods = self
+            .methods
+            .iter()
+            .map(|m| m.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Ok(methods) = HeaderValue::from_str(&methods) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if let Ok(allowed) = HeaderValue::from_str(&self.headers.join(", ")) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+        }
+        if let Some(max_age) = self.max_age_secs {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+        }
+        headers.append(
+            header::VARY,
+            HeaderValue::from_static(
+                "access-control-request-method, access-control-request-headers",
+            ),
+        );
+        response
+    }
+}
+
+// `*` matches any run of characters other than `/`, so a pattern can never
+// reach past the host and port of an origin
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_match(rest, &text[i..])),
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// Answer CORS preflight requests and add CORS headers to every other reply,
+/// error replies included. Wraps routes that have already been recovered
+pub fn with_cors<F, T>(
+    filter: F,
+    config: Arc<CorsConfig>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (T,), Error = Infallible> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    let preflight_config = config.clone();
+    let preflight = warp::options()
+        .and(warp::header::<String>("origin"))
+        .and(warp::header::<String>("access-control-request-method"))
+        .and(warp::header::optional::<String>(
+            "access-control-request-headers",
+        ))
+        .and(warp::any().map(move || preflight_config.clone()))
+        .map(
+            |origin: String,
+             request_method: String,
+             request_headers: Option<String>,
+             config: Arc<CorsConfig>| {
+                config.preflight(&origin, &request_method, request_headers.as_deref())
+            },
+        );
+
+    let actual = warp::header::optional::<String>("origin")
+        .and(filter)
+        .and(warp::any().map(move || config.clone()))
+        .map(
+            |origin: Option<String>, reply: T, config: Arc<CorsConfig>| {
+                let mut response = reply.into_response();
+                config.apply(origin.as_deref(), response.headers_mut());
+                response
+            },
+        );
+
+    // Anything that is not a preflight falls through to the routes
+    preflight.or(actual).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(section: &str) -> CorsConfig {
+        let config: toml::Value = toml::from_str(&format!("[cors]\n{}", section)).unwrap();
+        CorsConfig::from_toml(&config)
+    }
+
+    fn example() -> CorsConfig {
+        config(
+            r#"
+            allowed_origins = ["https://app.example.com", "http://127.0.0.1:*"]
+            allowed_methods = ["GET", "POST"]
+            allowed_headers = ["Content-Type", "X-CSRF-Token"]
+            exposed_headers = ["X-Request-Id"]
+            allow_credentials = true
+            "#,
+        )
+    }
+
+    fn header(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+        headers.get(name).map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn preflight_allows_configured_origin_method_and_headers() {
+        let response = example().preflight(
+            "https://app.example.com",
+            "POST",
+            Some("content-type, X-CSRF-Token"),
+        );
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            header(headers, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            header(headers, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            Some("true")
+        );
+        assert_eq!(
+            header(headers, header::ACCESS_CONTROL_ALLOW_METHODS),
+            Some("GET, POST")
+        );
+        assert_eq!(
+            header(headers, header::ACCESS_CONTROL_ALLOW_HEADERS),
+            Some("content-type, x-csrf-token")
+        );
+        assert_eq!(header(headers, header::ACCESS_CONTROL_MAX_AGE), Some("600"));
+    }
+
+    #[test]
+    fn preflight_refuses_other_origin_method_or_headers() {
+        let config = example();
+        for response in [
+            config.preflight("https://evil.example.com", "GET", None),
+            config.preflight("https://app.example.com", "DELETE", None),
+            config.preflight("https://app.example.com", "POST", Some("x-other")),
+            config.preflight(
+                "https://app.example.com",
+                "POST",
+                Some("content-type, x-other"),
+            ),
+        ] {
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            assert!(response
+                .headers()
+                .keys()
+                .all(|name| !name.as_str().starts_with("access-control-")));
+        }
+    }
+
+    #[test]
+    fn glob_star_stays_within_host_and_port() {
+        let pattern = b"http://127.0.0.1:*";
+        assert!(glob_match(pattern, b"http://127.0.0.1:3000"));
+        assert!(!glob_match(pattern, b"http://127.0.0.1.evil.com"));
+        assert!(!glob_match(pattern, b"http://127.0.0.1:3000/evil"));
+
+        let pattern = b"https://*.example.com";
+        assert!(glob_match(pattern, b"https://app.example.com"));
+        assert!(!glob_match(pattern, b"https://evil.com/.example.com"));
+        assert!(!glob_match(pattern, b"https://example.com"));
+    }
+
+    #[test]
+    fn patterns_match_origins_case_insensitively() {
+        let config = example();
+        assert!(config.allows_origin("HTTP://127.0.0.1:8080"));
+        assert!(!config.allows_origin("http://127.0.0.1.evil.com"));
+        assert!(!config.allows_origin("https://app.example.com.evil.com"));
+    }
+
+    #[test]
+    fn any_origin_never_allows_credentials() {
+        let config = config(
+            r#"
+            allowed_origins = ["*"]
+            allow_credentials = true
+            "#,
+        );
+        assert!(config.any_origin);
+        assert!(!config.allow_credentials);
+
+        let mut headers = HeaderMap::new();
+        config.apply(Some("https://anyone.example.com"), &mut headers);
+        assert_eq!(
+            header(&headers, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://anyone.example.com")
+        );
+        assert_eq!(
+            header(&headers, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            None
+        );
+    }
+
+    #[test]
+    fn apply_gives_disallowed_origin_only_vary() {
+        let config = example();
+        for origin in [Some("https://evil.example.com"), None] {
+            let mut headers = HeaderMap::new();
+            config.apply(origin, &mut headers);
+            assert_eq!(headers.len(), 1);
+            assert_eq!(header(&headers, header::VARY), Some("origin"));
+        }
+    }
+
+    #[test]
+    fn apply_echoes_allowed_origin() {
+        let mut headers = HeaderMap::new();
+        example().apply(Some("https://app.example.com"), &mut headers);
+        assert_eq!(
+            header(&headers, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            header(&headers, header::ACCESS_CONTROL_EXPOSE_HEADERS),
+            Some("X-Request-Id")
+        );
+        assert_eq!(header(&headers, header::VARY), Some("origin"));
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{errors, security};
+use cookie::{Cookie, SameSite};
+use log::warn;
+use std::sync::OnceLock;
+use warp::{http::Method, reject, Filter, Rejection};
+
+pub const COOKIE_NAME: &str = "csrf_token";
+pub const HEADER_NAME: &str = "x-csrf-token";
+
+/// Settings from the `[csrf]` section of the server config
+#[derive(Clone, Debug)]
+pub struct CsrfConfig {
+    pub enabled: bool,
+}
+
+impl Default for CsrfConfig {
+    fn default() -> Self {
+        CsrfConfig { enabled: true }
+    }
+}
+
+impl CsrfConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = CsrfConfig::default();
+        let section = match config.get("csrf") {
+            Some(section) => section,
+            None => return defaults,
+        };
+
+        CsrfConfig {
+            enabled: section
+                .get("enabled")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.enabled),
+        }
+    }
+}
+
+static CONFIG: OnceLock<CsrfConfig> = OnceLock::new();
+
+/// Install the CSRF settings
+pub fn init_config(config: CsrfConfig) {
+    if CONFIG.set(config).is_err() {
+        warn!("CSRF config already initialized");
+    }
+}
+
+pub fn config() -> &'static CsrfConfig {
+    CONFIG.get_or_init(CsrfConfig::default)
+}
+
+// Same shape as security::generate_token
+fn well_formed(token: &str) -> bool {
+    token.len() == 64 && token.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Token to embed in a rendered page. The browser's existing token is kept,
+/// so pages open in other tabs stay valid
+pub fn page_token(existing: Option<String>) -> String {
+    existing
+        .filter(|token| well_formed(token))
+        .unwrap_or_else(security::generate_token)
+}
+
+/// Cookie holding the token the page was rendered with. Pages read the token
+/// from the template, so scripts never need the cookie itself
+pub fn cookie(token: String) -> Cookie<'static> {
+    let mut cookie = Cookie::new(COOKIE_NAME, token);
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    cookie.set_same_site(SameSite::Strict);
+    cookie
+}
+
+// Compares in constant time, so the token cannot be guessed byte by byte
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+// Whether the request would be authenticated by a cookie the browser sends
+// on its own. A Bearer token only counts when it is the credential actually
+// used; an API key is only looked at when no token cookie was sent at all
+fn uses_cookie_credentials(
+    jwt_cookie: bool,
+    refresh_cookie: bool,
+    authorization: Option<&str>,
+) -> bool {
+    let bearer = authorization.and_then(security::bearer_token).is_some();
+    refresh_cookie || (jwt_cookie && !(bearer && security::jwt_config().prefer_bearer))
+}
+
+/// Reject POST, PUT, PATCH and DELETE requests that ride on session cookies
+/// without the page's token in the `X-CSRF-Token` header matching the
+/// `csrf_token` cookie (double submit). Calls authenticated by a Bearer
+/// token or API key carry nothing a forged request could borrow, so they
+/// are exempt, as are requests without session cookies
+pub fn with_csrf_protection() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::cookie::optional::<String>("jwt"))
+        .and(warp::cookie::optional::<String>("refresh_token"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::cookie::optional::<String>(COOKIE_NAME))
+        .and(warp::header::optional::<String>(HEADER_NAME))
+        .and_then(
+            |method: Method,
+             path: warp::path::FullPath,
+             jwt: Option<String>,
+             refresh_token: Option<String>,
+             authorization: Option<String>,
+             cookie_token: Option<String>,
+             header_token: Option<String>| async move {
+                let unsafe_method = matches!(
+                    method,
+                    Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+                );
+                if !config().enabled
+                    || !unsafe_method
+                    || !uses_cookie_credentials(
+                        jwt.is_some(),
+                        refresh_token.is_some(),
+                        authorization.as_deref(),
+                    )
+                {
+                    return Ok(());
+                }
+
+                match (cookie_token, header_token) {
+                    (Some(cookie_token), Some(header_token))
+                        if tokens_match(&cookie_token, header_token.trim()) =>
+                    {
+                        Ok(())
+                    }
+                    _ => {
+                        warn!(
+                            target: "security",
+                            "CSRF check failed for {} {}",
+                            method,
+                            path.as_str()
+                        );
+                        Err(reject::custom(errors::CustomError::CsrfError))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
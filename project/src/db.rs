@@ -1,10 +1,22 @@
-use crate::models::{NewUser, User};
-use crate::schema::users;
+use crate::models::{
+    ApiKey, AuditEvent, AuditQuery, LoginThrottle, NewApiKey, NewAuditEvent,
+    NewEmailVerificationToken, NewExternalIdentity, NewOAuthAuthorizationCode, NewOAuthClient,
+    NewOAuthRefreshToken, NewPasswordResetToken, NewRecoveryCode, NewRefreshToken, NewUser,
+    OAuthAuthorizationCode, OAuthClient, OAuthRefreshToken, RateLimitBucket, RefreshToken, Role,
+    StateEntry, User, UserRole, UserTotp,
+};
 use crate::schema::users::dsl::*;
+use crate::schema::{
+    api_keys, audit_events, email_verification_tokens, external_identities, login_throttles,
+    oauth_authorization_codes, oauth_clients, oauth_refresh_tokens, password_reset_tokens,
+    permissions, rate_limit_buckets, recovery_codes, refresh_tokens, role_permissions, roles,
+    state_entries, user_roles, user_totp, users,
+};
+use chrono::NaiveDateTime;
 use diesel::{
-    pg::PgConnection,
+    pg::{Pg, PgConnection},
     r2d2::{self, ConnectionManager, Pool, PooledConnection},
-    ExpressionMethods, QueryDsl, RunQueryDsl,
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl,
 };
 use log::error;
 use std::env;
@@ -35,13 +47,91 @@ impl UserRepository {
         }
     }
 
-    pub fn create_user(
+    // Insert the user and grant it its roles in one transaction
+    pub fn create_user_with_roles(
         conn: &mut DbConnection,
         new_user: &NewUser,
+        granted: &[Role],
     ) -> Result<User, diesel::result::Error> {
-        diesel::insert_into(users::table)
-            .values(new_user)
-            .get_result::<User>(conn)
+        conn.transaction(|conn| {
+            let user = diesel::insert_into(users::table)
+                .values(new_user)
+                .get_result::<User>(conn)?;
+            for granted_role in granted {
+                RoleRepository::assign(conn, user.id, granted_role.id)?;
+            }
+            Ok(user)
+        })
+    }
+
+    pub fn find_by_email(
+        conn: &mut DbConnection,
+        address: &str,
+    ) -> Result<Option<User>, diesel::result::Error> {
+        users
+            .filter(email.eq(address))
+            .first::<User>(conn)
+            .optional()
+    }
+
+    pub fn update_password(
+        conn: &mut PgConnection,
+        user_id: i32,
+        password_hash: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(users.find(user_id))
+            .set(password.eq(password_hash))
+            .execute(conn)
+    }
+
+    // A new password chosen by the user, as opposed to a re-hash of the old one
+    pub fn change_password(
+        conn: &mut PgConnection,
+        user_id: i32,
+        password_hash: &str,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(users.find(user_id))
+            .set((password.eq(password_hash), password_changed_at.eq(now)))
+            .execute(conn)
+    }
+
+    // Only marks the address the token was sent to, in case it changed since
+    pub fn mark_email_verified(
+        conn: &mut PgConnection,
+        user_id: i32,
+        address: &str,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(users.find(user_id).filter(email.eq(address)))
+            .set(email_verified_at.eq(now))
+            .execute(conn)
+    }
+
+    pub fn list(conn: &mut DbConnection) -> Result<Vec<User>, diesel::result::Error> {
+        users.order(id.asc()).load::<User>(conn)
+    }
+
+    pub fn find_by_id(conn: &mut PgConnection, user_id: i32) -> Option<User> {
+        match users.find(user_id).first::<User>(conn) {
+            Ok(user) => Some(user),
+            Err(e) => {
+                error!("Error finding user by id: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn exists_with_role(
+        conn: &mut DbConnection,
+        role_name: &str,
+    ) -> Result<bool, diesel::result::Error> {
+        diesel::select(diesel::dsl::exists(
+            user_roles::table
+                .inner_join(roles::table)
+                .filter(roles::name.eq(role_name)),
+        ))
+        .get_result(conn)
     }
 
     pub fn count_users(conn: &mut DbConnection) -> i64 {
@@ -54,3 +144,892 @@ impl UserRepository {
         }
     }
 }
+
+// Shared state used by the Postgres-backed state store
+pub struct StateRepository;
+
+impl StateRepository {
+    // Fetch the bucket and lock its row until the surrounding transaction ends,
+    // creating it with `initial` tokens if it does not exist yet
+    pub fn lock_bucket(
+        conn: &mut PgConnection,
+        initial: &RateLimitBucket,
+    ) -> Result<RateLimitBucket, diesel::result::Error> {
+        diesel::insert_into(rate_limit_buckets::table)
+            .values(initial)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        rate_limit_buckets::table
+            .find(&initial.key)
+            .for_update()
+            .first::<RateLimitBucket>(conn)
+    }
+
+    pub fn save_bucket(
+        conn: &mut PgConnection,
+        bucket: &RateLimitBucket,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(rate_limit_buckets::table.find(&bucket.key))
+            .set(bucket)
+            .execute(conn)
+    }
+
+    pub fn find_entry(
+        conn: &mut DbConnection,
+        entry_key: &str,
+        now: NaiveDateTime,
+    ) -> Result<Option<StateEntry>, diesel::result::Error> {
+        state_entries::table
+            .find(entry_key)
+            .filter(
+                state_entries::expires_at
+                    .is_null()
+                    .or(state_entries::expires_at.gt(now)),
+            )
+            .first::<StateEntry>(conn)
+            .optional()
+    }
+
+    pub fn upsert_entry(
+        conn: &mut DbConnection,
+        entry: &StateEntry,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::insert_into(state_entries::table)
+            .values(entry)
+            .on_conflict(state_entries::key)
+            .do_update()
+            .set(entry)
+            .execute(conn)
+    }
+
+    pub fn delete_entry(
+        conn: &mut DbConnection,
+        entry_key: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::delete(state_entries::table.find(entry_key)).execute(conn)
+    }
+
+    pub fn purge(
+        conn: &mut DbConnection,
+        now: NaiveDateTime,
+        idle_bucket_cutoff: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        let entries =
+            diesel::delete(state_entries::table.filter(state_entries::expires_at.le(now)))
+                .execute(conn)?;
+        let buckets = diesel::delete(
+            rate_limit_buckets::table.filter(rate_limit_buckets::updated_at.lt(idle_bucket_cutoff)),
+        )
+        .execute(conn)?;
+
+        Ok(entries + buckets)
+    }
+}
+
+// Failed login tracking used for brute-force protection
+pub struct LoginThrottleRepository;
+
+impl LoginThrottleRepository {
+    pub fn find_locked(
+        conn: &mut DbConnection,
+        subjects: &[String],
+        now: NaiveDateTime,
+    ) -> Result<Vec<LoginThrottle>, diesel::result::Error> {
+        login_throttles::table
+            .filter(login_throttles::subject.eq_any(subjects))
+            .filter(login_throttles::locked_until.gt(now))
+            .load::<LoginThrottle>(conn)
+    }
+
+    // Fetch the throttle row and lock it until the surrounding transaction ends,
+    // creating an empty one if the subject has no failures yet
+    pub fn lock(
+        conn: &mut PgConnection,
+        throttle_subject: &str,
+        now: NaiveDateTime,
+    ) -> Result<LoginThrottle, diesel::result::Error> {
+        diesel::insert_into(login_throttles::table)
+            .values(&LoginThrottle {
+                subject: throttle_subject.to_string(),
+                failed_count: 0,
+                last_failed_at: now,
+                locked_until: None,
+            })
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        login_throttles::table
+            .find(throttle_subject)
+            .for_update()
+            .first::<LoginThrottle>(conn)
+    }
+
+    pub fn save(
+        conn: &mut PgConnection,
+        throttle: &LoginThrottle,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(login_throttles::table.find(&throttle.subject))
+            .set(throttle)
+            .execute(conn)
+    }
+
+    pub fn clear(
+        conn: &mut DbConnection,
+        subjects: &[String],
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::delete(login_throttles::table.filter(login_throttles::subject.eq_any(subjects)))
+            .execute(conn)
+    }
+}
+
+// Refresh tokens issued alongside access tokens
+pub struct RefreshTokenRepository;
+
+impl RefreshTokenRepository {
+    pub fn create(
+        conn: &mut PgConnection,
+        new_token: &NewRefreshToken,
+    ) -> Result<RefreshToken, diesel::result::Error> {
+        diesel::insert_into(refresh_tokens::table)
+            .values(new_token)
+            .get_result::<RefreshToken>(conn)
+    }
+
+    // Look the token up and lock its row until the surrounding transaction ends
+    pub fn lock_by_hash(
+        conn: &mut PgConnection,
+        hash: &str,
+    ) -> Result<Option<RefreshToken>, diesel::result::Error> {
+        refresh_tokens::table
+            .filter(refresh_tokens::token_hash.eq(hash))
+            .for_update()
+            .first::<RefreshToken>(conn)
+            .optional()
+    }
+
+    pub fn mark_used(
+        conn: &mut PgConnection,
+        token_id: i32,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(refresh_tokens::table.find(token_id))
+            .set(refresh_tokens::used_at.eq(now))
+            .execute(conn)
+    }
+
+    pub fn revoke_all_for_user(
+        conn: &mut PgConnection,
+        owner_id: i32,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(
+            refresh_tokens::table
+                .filter(refresh_tokens::user_id.eq(owner_id))
+                .filter(refresh_tokens::revoked_at.is_null()),
+        )
+        .set(refresh_tokens::revoked_at.eq(now))
+        .execute(conn)
+    }
+
+    pub fn find_by_hash(
+        conn: &mut PgConnection,
+        hash: &str,
+    ) -> Result<Option<RefreshToken>, diesel::result::Error> {
+        refresh_tokens::table
+            .filter(refresh_tokens::token_hash.eq(hash))
+            .first::<RefreshToken>(conn)
+            .optional()
+    }
+
+    pub fn revoke_family(
+        conn: &mut PgConnection,
+        family: &str,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(
+            refresh_tokens::table
+                .filter(refresh_tokens::family_id.eq(family))
+                .filter(refresh_tokens::revoked_at.is_null()),
+        )
+        .set(refresh_tokens::revoked_at.eq(now))
+        .execute(conn)
+    }
+}
+
+// Roles and the permissions they grant
+pub struct RoleRepository;
+
+impl RoleRepository {
+    // Role names are matched case-insensitively so "admin" finds "Admin"
+    pub fn find_by_name(
+        conn: &mut DbConnection,
+        role_name: &str,
+    ) -> Result<Option<Role>, diesel::result::Error> {
+        Ok(roles::table
+            .load::<Role>(conn)?
+            .into_iter()
+            .find(|r| r.name.eq_ignore_ascii_case(role_name)))
+    }
+
+    pub fn assign(
+        conn: &mut PgConnection,
+        owner_id: i32,
+        granted_role_id: i32,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::insert_into(user_roles::table)
+            .values(&UserRole {
+                user_id: owner_id,
+                role_id: granted_role_id,
+            })
+            .on_conflict_do_nothing()
+            .execute(conn)
+    }
+
+    pub fn all(conn: &mut DbConnection) -> Result<Vec<Role>, diesel::result::Error> {
+        roles::table.order(roles::name.asc()).load::<Role>(conn)
+    }
+
+    // Names of the roles granted directly to the user, without inheritance
+    pub fn roles_for_user(
+        conn: &mut DbConnection,
+        owner_id: i32,
+    ) -> Result<Vec<String>, diesel::result::Error> {
+        user_roles::table
+            .inner_join(roles::table)
+            .filter(user_roles::user_id.eq(owner_id))
+            .select(roles::name)
+            .order(roles::name.asc())
+            .load::<String>(conn)
+    }
+
+    // (user id, role name) for every grant, for listing users with their roles
+    pub fn all_grants(
+        conn: &mut DbConnection,
+    ) -> Result<Vec<(i32, String)>, diesel::result::Error> {
+        user_roles::table
+            .inner_join(roles::table)
+            .select((user_roles::user_id, roles::name))
+            .order(roles::name.asc())
+            .load::<(i32, String)>(conn)
+    }
+
+    // Replace the user's roles with exactly the given set
+    pub fn set_user_roles(
+        conn: &mut DbConnection,
+        owner_id: i32,
+        granted: &[Role],
+    ) -> Result<(), diesel::result::Error> {
+        conn.transaction(|conn| {
+            diesel::delete(user_roles::table.filter(user_roles::user_id.eq(owner_id)))
+                .execute(conn)?;
+            for granted_role in granted {
+                Self::assign(conn, owner_id, granted_role.id)?;
+            }
+            Ok(())
+        })
+    }
+
+    // Whether any of the named roles grants the permission directly. Callers
+    // pass the effective role set, so inherited grants are covered too
+    pub fn roles_grant_permission(
+        conn: &mut DbConnection,
+        role_names: &[String],
+        permission: &str,
+    ) -> Result<bool, diesel::result::Error> {
+        diesel::select(diesel::dsl::exists(
+            roles::table
+                .inner_join(role_permissions::table.inner_join(permissions::table))
+                .filter(roles::name.eq_any(role_names))
+                .filter(permissions::name.eq(permission)),
+        ))
+        .get_result(conn)
+    }
+
+    // Every permission granted directly to any of the named roles
+    pub fn permissions_for_roles(
+        conn: &mut DbConnection,
+        role_names: &[String],
+    ) -> Result<Vec<String>, diesel::result::Error> {
+        roles::table
+            .inner_join(role_permissions::table.inner_join(permissions::table))
+            .filter(roles::name.eq_any(role_names))
+            .select(permissions::name)
+            .distinct()
+            .load::<String>(conn)
+    }
+
+    // Whether any of the named roles requires a second factor at login
+    pub fn any_requires_two_factor(
+        conn: &mut DbConnection,
+        role_names: &[String],
+    ) -> Result<bool, diesel::result::Error> {
+        diesel::select(diesel::dsl::exists(
+            roles::table
+                .filter(roles::name.eq_any(role_names))
+                .filter(roles::require_2fa.eq(true)),
+        ))
+        .get_result(conn)
+    }
+
+    pub fn set_require_two_factor(
+        conn: &mut DbConnection,
+        role_id: i32,
+        required: bool,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(roles::table.find(role_id))
+            .set(roles::require_2fa.eq(required))
+            .execute(conn)
+    }
+}
+
+// TOTP secrets and recovery codes for two-factor authentication
+pub struct TwoFactorRepository;
+
+impl TwoFactorRepository {
+    pub fn find(
+        conn: &mut DbConnection,
+        owner_id: i32,
+    ) -> Result<Option<UserTotp>, diesel::result::Error> {
+        user_totp::table
+            .find(owner_id)
+            .first::<UserTotp>(conn)
+            .optional()
+    }
+
+    // Store a new unconfirmed secret, replacing any earlier pending one
+    pub fn save_pending(
+        conn: &mut DbConnection,
+        owner_id: i32,
+        new_secret: &str,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::insert_into(user_totp::table)
+            .values((
+                user_totp::user_id.eq(owner_id),
+                user_totp::secret.eq(new_secret),
+                user_totp::created_at.eq(now),
+            ))
+            .on_conflict(user_totp::user_id)
+            .do_update()
+            .set((
+                user_totp::secret.eq(new_secret),
+                user_totp::confirmed_at.eq(None::<NaiveDateTime>),
+                user_totp::last_used_step.eq(None::<i64>),
+                user_totp::created_at.eq(now),
+            ))
+            .execute(conn)
+    }
+
+    // Record the time step of an accepted code. Returns 0 if that step or a
+    // later one was already used, so each code is only accepted once
+    pub fn use_step(
+        conn: &mut DbConnection,
+        owner_id: i32,
+        step: i64,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(
+            user_totp::table.find(owner_id).filter(
+                user_totp::last_used_step
+                    .is_null()
+                    .or(user_totp::last_used_step.lt(step)),
+            ),
+        )
+        .set(user_totp::last_used_step.eq(step))
+        .execute(conn)
+    }
+
+    pub fn confirm(
+        conn: &mut DbConnection,
+        owner_id: i32,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(user_totp::table.find(owner_id))
+            .set(user_totp::confirmed_at.eq(now))
+            .execute(conn)
+    }
+
+    pub fn delete(conn: &mut DbConnection, owner_id: i32) -> Result<(), diesel::result::Error> {
+        conn.transaction(|conn| {
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(owner_id)))
+                .execute(conn)?;
+            diesel::delete(user_totp::table.find(owner_id)).execute(conn)?;
+            Ok(())
+        })
+    }
+
+    // Replace every recovery code of the user with the given hashes
+    pub fn replace_recovery_codes(
+        conn: &mut DbConnection,
+        owner_id: i32,
+        hashes: &[String],
+    ) -> Result<(), diesel::result::Error> {
+        let codes = hashes
+            .iter()
+            .map(|hash| NewRecoveryCode {
+                user_id: owner_id,
+                code_hash: hash.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        conn.transaction(|conn| {
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(owner_id)))
+                .execute(conn)?;
+            diesel::insert_into(recovery_codes::table)
+                .values(&codes)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    // Mark an unused recovery code as used. Returns 0 if there was none
+    pub fn use_recovery_code(
+        conn: &mut DbConnection,
+        owner_id: i32,
+        hash: &str,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(
+            recovery_codes::table
+                .filter(recovery_codes::user_id.eq(owner_id))
+                .filter(recovery_codes::code_hash.eq(hash))
+                .filter(recovery_codes::used_at.is_null()),
+        )
+        .set(recovery_codes::used_at.eq(now))
+        .execute(conn)
+    }
+}
+
+// API keys for machine clients
+pub struct ApiKeyRepository;
+
+impl ApiKeyRepository {
+    pub fn create(
+        conn: &mut DbConnection,
+        new_key: &NewApiKey,
+    ) -> Result<ApiKey, diesel::result::Error> {
+        diesel::insert_into(api_keys::table)
+            .values(new_key)
+            .get_result::<ApiKey>(conn)
+    }
+
+    pub fn find_by_hash(
+        conn: &mut DbConnection,
+        hash: &str,
+    ) -> Result<Option<ApiKey>, diesel::result::Error> {
+        api_keys::table
+            .filter(api_keys::key_hash.eq(hash))
+            .first::<ApiKey>(conn)
+            .optional()
+    }
+
+    pub fn list_for_user(
+        conn: &mut DbConnection,
+        owner_id: i32,
+    ) -> Result<Vec<ApiKey>, diesel::result::Error> {
+        api_keys::table
+            .filter(api_keys::user_id.eq(owner_id))
+            .order(api_keys::created_at.desc())
+            .load::<ApiKey>(conn)
+    }
+
+    // Record a use, at most once a minute per key to spare the database
+    pub fn touch(
+        conn: &mut DbConnection,
+        key_id: i32,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(
+            api_keys::table.find(key_id).filter(
+                api_keys::last_used_at
+                    .is_null()
+                    .or(api_keys::last_used_at.lt(now - chrono::Duration::minutes(1))),
+            ),
+        )
+        .set(api_keys::last_used_at.eq(now))
+        .execute(conn)
+    }
+
+    // Revoke one of the owner's keys. Returns 0 if it has no such active key
+    pub fn revoke(
+        conn: &mut DbConnection,
+        owner_id: i32,
+        key_id: i32,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(
+            api_keys::table
+                .find(key_id)
+                .filter(api_keys::user_id.eq(owner_id))
+                .filter(api_keys::revoked_at.is_null()),
+        )
+        .set(api_keys::revoked_at.eq(now))
+        .execute(conn)
+    }
+}
+
+// Single-use tokens for the forgotten password flow
+pub struct PasswordResetRepository;
+
+impl PasswordResetRepository {
+    // Store a new token, invalidating any the user still had outstanding
+    pub fn create(
+        conn: &mut DbConnection,
+        new_token: &NewPasswordResetToken,
+        now: NaiveDateTime,
+    ) -> Result<(), diesel::result::Error> {
+        conn.transaction(|conn| {
+            diesel::update(
+                password_reset_tokens::table
+                    .filter(password_reset_tokens::user_id.eq(new_token.user_id))
+                    .filter(password_reset_tokens::used_at.is_null()),
+            )
+            .set(password_reset_tokens::used_at.eq(now))
+            .execute(conn)?;
+            diesel::insert_into(password_reset_tokens::table)
+                .values(new_token)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    // Owner of an unused, unexpired token, without spending it
+    pub fn find_valid(
+        conn: &mut DbConnection,
+        hash: &str,
+        now: NaiveDateTime,
+    ) -> Result<Option<i32>, diesel::result::Error> {
+        password_reset_tokens::table
+            .filter(password_reset_tokens::token_hash.eq(hash))
+            .filter(password_reset_tokens::used_at.is_null())
+            .filter(password_reset_tokens::expires_at.gt(now))
+            .select(password_reset_tokens::user_id)
+            .first::<i32>(conn)
+            .optional()
+    }
+
+    // Mark an unused, unexpired token as used and return its owner's id.
+    // The single update makes sure a token can only be spent once
+    pub fn consume(
+        conn: &mut PgConnection,
+        hash: &str,
+        now: NaiveDateTime,
+    ) -> Result<Option<i32>, diesel::result::Error> {
+        diesel::update(
+            password_reset_tokens::table
+                .filter(password_reset_tokens::token_hash.eq(hash))
+                .filter(password_reset_tokens::used_at.is_null())
+                .filter(password_reset_tokens::expires_at.gt(now)),
+        )
+        .set(password_reset_tokens::used_at.eq(now))
+        .returning(password_reset_tokens::user_id)
+        .get_result::<i32>(conn)
+        .optional()
+    }
+}
+
+// Single-use tokens proving a user receives mail at their address
+pub struct EmailVerificationRepository;
+
+impl EmailVerificationRepository {
+    // Store a new token, invalidating any the user still had outstanding
+    pub fn create(
+        conn: &mut DbConnection,
+        new_token: &NewEmailVerificationToken,
+        now: NaiveDateTime,
+    ) -> Result<(), diesel::result::Error> {
+        conn.transaction(|conn| {
+            diesel::update(
+                email_verification_tokens::table
+                    .filter(email_verification_tokens::user_id.eq(new_token.user_id))
+                    .filter(email_verification_tokens::used_at.is_null()),
+            )
+            .set(email_verification_tokens::used_at.eq(now))
+            .execute(conn)?;
+            diesel::insert_into(email_verification_tokens::table)
+                .values(new_token)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    // Mark an unused, unexpired token as used and return the user id and
+    // address it was issued for
+    pub fn consume(
+        conn: &mut PgConnection,
+        hash: &str,
+        now: NaiveDateTime,
+    ) -> Result<Option<(i32, String)>, diesel::result::Error> {
+        diesel::update(
+            email_verification_tokens::table
+                .filter(email_verification_tokens::token_hash.eq(hash))
+                .filter(email_verification_tokens::used_at.is_null())
+                .filter(email_verification_tokens::expires_at.gt(now)),
+        )
+        .set(email_verification_tokens::used_at.eq(now))
+        .returning((
+            email_verification_tokens::user_id,
+            email_verification_tokens::email,
+        ))
+        .get_result::<(i32, String)>(conn)
+        .optional()
+    }
+}
+
+// Links between local users and accounts at OpenID Connect providers
+pub struct ExternalIdentityRepository;
+
+impl ExternalIdentityRepository {
+    pub fn find_user(
+        conn: &mut DbConnection,
+        identity_issuer: &str,
+        identity_subject: &str,
+    ) -> Result<Option<User>, diesel::result::Error> {
+        external_identities::table
+            .inner_join(users::table)
+            .filter(external_identities::issuer.eq(identity_issuer))
+            .filter(external_identities::subject.eq(identity_subject))
+            .select(users::all_columns)
+            .first::<User>(conn)
+            .optional()
+    }
+
+    pub fn link(
+        conn: &mut PgConnection,
+        identity: &NewExternalIdentity,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::insert_into(external_identities::table)
+            .values(identity)
+            .execute(conn)
+    }
+}
+
+// Applications registered to use our OAuth2 endpoints
+pub struct OAuthClientRepository;
+
+impl OAuthClientRepository {
+    pub fn create(
+        conn: &mut DbConnection,
+        new_client: &NewOAuthClient,
+    ) -> Result<OAuthClient, diesel::result::Error> {
+        diesel::insert_into(oauth_clients::table)
+            .values(new_client)
+            .get_result::<OAuthClient>(conn)
+    }
+
+    pub fn find(
+        conn: &mut DbConnection,
+        public_id: &str,
+    ) -> Result<Option<OAuthClient>, diesel::result::Error> {
+        oauth_clients::table
+            .filter(oauth_clients::client_id.eq(public_id))
+            .first::<OAuthClient>(conn)
+            .optional()
+    }
+
+    pub fn list(conn: &mut DbConnection) -> Result<Vec<OAuthClient>, diesel::result::Error> {
+        oauth_clients::table
+            .order(oauth_clients::created_at.desc())
+            .load::<OAuthClient>(conn)
+    }
+
+    // Its codes and refresh tokens go with it
+    pub fn delete(
+        conn: &mut DbConnection,
+        public_id: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::delete(oauth_clients::table.filter(oauth_clients::client_id.eq(public_id)))
+            .execute(conn)
+    }
+}
+
+// Authorization codes and refresh tokens handed out to OAuth clients
+pub struct OAuthTokenRepository;
+
+impl OAuthTokenRepository {
+    pub fn create_code(
+        conn: &mut DbConnection,
+        new_code: &NewOAuthAuthorizationCode,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::insert_into(oauth_authorization_codes::table)
+            .values(new_code)
+            .execute(conn)
+    }
+
+    // Look the code up and lock its row until the surrounding transaction ends
+    pub fn lock_code(
+        conn: &mut PgConnection,
+        hash: &str,
+    ) -> Result<Option<OAuthAuthorizationCode>, diesel::result::Error> {
+        oauth_authorization_codes::table
+            .filter(oauth_authorization_codes::code_hash.eq(hash))
+            .for_update()
+            .first::<OAuthAuthorizationCode>(conn)
+            .optional()
+    }
+
+    pub fn mark_code_used(
+        conn: &mut PgConnection,
+        code_id: i32,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(oauth_authorization_codes::table.find(code_id))
+            .set(oauth_authorization_codes::used_at.eq(now))
+            .execute(conn)
+    }
+
+    pub fn create_refresh_token(
+        conn: &mut PgConnection,
+        new_token: &NewOAuthRefreshToken,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::insert_into(oauth_refresh_tokens::table)
+            .values(new_token)
+            .execute(conn)
+    }
+
+    pub fn find_refresh_token(
+        conn: &mut DbConnection,
+        hash: &str,
+    ) -> Result<Option<OAuthRefreshToken>, diesel::result::Error> {
+        oauth_refresh_tokens::table
+            .filter(oauth_refresh_tokens::token_hash.eq(hash))
+            .first::<OAuthRefreshToken>(conn)
+            .optional()
+    }
+
+    // Look the token up and lock its row until the surrounding transaction ends
+    pub fn lock_refresh_token(
+        conn: &mut PgConnection,
+        hash: &str,
+    ) -> Result<Option<OAuthRefreshToken>, diesel::result::Error> {
+        oauth_refresh_tokens::table
+            .filter(oauth_refresh_tokens::token_hash.eq(hash))
+            .for_update()
+            .first::<OAuthRefreshToken>(conn)
+            .optional()
+    }
+
+    pub fn revoke_refresh_token(
+        conn: &mut PgConnection,
+        token_id: i32,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(
+            oauth_refresh_tokens::table
+                .find(token_id)
+                .filter(oauth_refresh_tokens::revoked_at.is_null()),
+        )
+        .set(oauth_refresh_tokens::revoked_at.eq(now))
+        .execute(conn)
+    }
+
+    // Retire a token that was exchanged for a new one, so that presenting
+    // it again is recognised as reuse
+    pub fn rotate_refresh_token(
+        conn: &mut PgConnection,
+        token_id: i32,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(
+            oauth_refresh_tokens::table
+                .find(token_id)
+                .filter(oauth_refresh_tokens::revoked_at.is_null()),
+        )
+        .set((
+            oauth_refresh_tokens::revoked_at.eq(now),
+            oauth_refresh_tokens::rotated_at.eq(now),
+        ))
+        .execute(conn)
+    }
+
+    // Everything the user has granted to one client
+    pub fn revoke_grant(
+        conn: &mut PgConnection,
+        client_pk: i32,
+        owner_id: i32,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(
+            oauth_refresh_tokens::table
+                .filter(oauth_refresh_tokens::oauth_client_id.eq(client_pk))
+                .filter(oauth_refresh_tokens::user_id.eq(owner_id))
+                .filter(oauth_refresh_tokens::revoked_at.is_null()),
+        )
+        .set(oauth_refresh_tokens::revoked_at.eq(now))
+        .execute(conn)
+    }
+
+    pub fn revoke_all_for_user(
+        conn: &mut PgConnection,
+        owner_id: i32,
+        now: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(
+            oauth_refresh_tokens::table
+                .filter(oauth_refresh_tokens::user_id.eq(owner_id))
+                .filter(oauth_refresh_tokens::revoked_at.is_null()),
+        )
+        .set(oauth_refresh_tokens::revoked_at.eq(now))
+        .execute(conn)
+    }
+}
+
+// Security-relevant events, newest first when queried
+pub struct AuditRepository;
+
+impl AuditRepository {
+    pub fn create(
+        conn: &mut DbConnection,
+        event: &NewAuditEvent,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::insert_into(audit_events::table)
+            .values(event)
+            .execute(conn)
+    }
+
+    // Events matching every filter that is set
+    fn matching(query: &AuditQuery) -> audit_events::BoxedQuery<'_, Pg> {
+        let mut events = audit_events::table.into_boxed();
+        if let Some(action) = &query.action {
+            events = events.filter(audit_events::action.eq(action));
+        }
+        if let Some(outcome) = &query.outcome {
+            events = events.filter(audit_events::outcome.eq(outcome));
+        }
+        if let Some(actor) = &query.actor {
+            events = events.filter(audit_events::actor.eq(actor));
+        }
+        if let Some(target) = &query.target {
+            events = events.filter(audit_events::target.eq(target));
+        }
+        if let Some(ip) = &query.ip {
+            events = events.filter(audit_events::ip.eq(ip));
+        }
+        if let Some(request_id) = &query.request_id {
+            events = events.filter(audit_events::request_id.eq(request_id));
+        }
+        if let Some(since) = query.since {
+            events = events.filter(audit_events::created_at.ge(since));
+        }
+        if let Some(until) = query.until {
+            events = events.filter(audit_events::created_at.lt(until));
+        }
+        events
+    }
+
+    // One page of matching events and the total number of matches
+    pub fn search(
+        conn: &mut DbConnection,
+        query: &AuditQuery,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(Vec<AuditEvent>, i64), diesel::result::Error> {
+        let total = Self::matching(query).count().get_result::<i64>(conn)?;
+        let events = Self::matching(query)
+            .order((audit_events::created_at.desc(), audit_events::id.desc()))
+            .offset(offset)
+            .limit(limit)
+            .load::<AuditEvent>(conn)?;
+        Ok((events, total))
+    }
+}
@@ -0,0 +1,177 @@
+use crate::{
+    db, errors,
+    mailer::{self, Email, Mailer},
+    models, security,
+};
+use chrono::{Duration, Utc};
+use diesel::Connection;
+use log::{error, info, warn};
+use std::sync::{Arc, OnceLock};
+use warp::{reject, Rejection};
+
+/// Settings from the `[email_verification]` section of the server config
+#[derive(Clone, Debug)]
+pub struct EmailVerificationConfig {
+    // Refuse logins from accounts without a verified address
+    pub required_for_login: bool,
+    pub token_ttl_secs: i64,
+    // Link sent in the email; `{token}` is replaced with the token
+    pub verify_url: String,
+}
+
+impl Default for EmailVerificationConfig {
+    fn default() -> Self {
+        EmailVerificationConfig {
+            required_for_login: false,
+            token_ttl_secs: 86400,
+            verify_url: "http://127.0.0.1:8447/verify?token={token}".to_string(),
+        }
+    }
+}
+
+impl EmailVerificationConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = EmailVerificationConfig::default();
+        let section = match config.get("email_verification") {
+            Some(section) => section,
+            None => return defaults,
+        };
+
+        EmailVerificationConfig {
+            required_for_login: section
+                .get("required_for_login")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.required_for_login),
+            token_ttl_secs: section
+                .get("token_ttl_secs")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.clamp(300, 7 * 86400))
+                .unwrap_or(defaults.token_ttl_secs),
+            verify_url: section
+                .get("verify_url")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string())
+                .unwrap_or(defaults.verify_url),
+        }
+    }
+}
+
+static CONFIG: OnceLock<EmailVerificationConfig> = OnceLock::new();
+
+/// Install the email verification settings
+pub fn init_config(config: EmailVerificationConfig) {
+    if CONFIG.set(config).is_err() {
+        warn!("Email verification config already initialized");
+    }
+}
+
+pub fn config() -> &'static EmailVerificationConfig {
+    CONFIG.get_or_init(EmailVerificationConfig::default)
+}
+
+fn internal_error(context: &str, e: impl std::fmt::Display) -> Rejection {
+    error!("{}: {}", context, e);
+    reject::custom(errors::CustomError::InternalError)
+}
+
+/// Refuse a login that has passed the password check when verified
+/// addresses are required and the user has none
+pub fn check_login(user: &models::User) -> Result<(), Rejection> {
+    if config().required_for_login && user.email_verified_at.is_none() {
+        info!("Login refused for {}: email not verified", user.username);
+        return Err(reject::custom(errors::CustomError::EmailNotVerifiedError));
+    }
+    Ok(())
+}
+
+/// Mail a verification link to the user's address. Does nothing for users
+/// without an address or whose address is already verified
+pub fn send_verification(
+    conn: &mut db::DbConnection,
+    mailer: Arc<dyn Mailer>,
+    user: &models::User,
+) -> Result<(), Rejection> {
+    let email = match (&user.email, user.email_verified_at) {
+        (Some(email), None) => email.clone(),
+        _ => return Ok(()),
+    };
+
+    let token = security::generate_token();
+    let now = Utc::now().naive_utc();
+    let new_token = models::NewEmailVerificationToken {
+        user_id: user.id,
+        email: email.clone(),
+        token_hash: security::hash_token(&token),
+        expires_at: now + Duration::seconds(config().token_ttl_secs),
+    };
+    db::EmailVerificationRepository::create(conn, &new_token, now)
+        .map_err(|e| internal_error("Failed to store email verification token", e))?;
+
+    mailer::send_in_background(
+        mailer,
+        Email {
+            to: email,
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Please confirm this address for the account {} by opening this link \
+                 within {} hours:\n{}\n\n\
+                 If you did not create this account, you can ignore this message.",
+                user.username,
+                config().token_ttl_secs / 3600,
+                config().verify_url.replace("{token}", &token)
+            ),
+        },
+    );
+
+    info!("Verification mail queued for {}", user.username);
+    Ok(())
+}
+
+/// Resend the verification link for an address, if it belongs to an
+/// account still awaiting verification. Callers answer the same way either way
+pub fn resend(
+    conn: &mut db::DbConnection,
+    mailer: Arc<dyn Mailer>,
+    email: &str,
+) -> Result<(), Rejection> {
+    let email = match mailer::normalize_address(email) {
+        Some(email) => email,
+        None => return Ok(()),
+    };
+
+    match db::UserRepository::find_by_email(conn, &email)
+        .map_err(|e| internal_error("Failed to look up email address", e))?
+    {
+        Some(user) => send_verification(conn, mailer, &user),
+        None => Ok(()),
+    }
+}
+
+/// Spend a verification token, marking the address it was sent to verified
+pub fn verify(conn: &mut db::DbConnection, token: &str) -> Result<models::User, Rejection> {
+    let now = Utc::now().naive_utc();
+    let hash = security::hash_token(token.trim());
+
+    let user = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            let (user_id, email) = match db::EmailVerificationRepository::consume(conn, &hash, now)?
+            {
+                Some(consumed) => consumed,
+                None => return Ok(None),
+            };
+            // The address changed since the token was sent
+            if db::UserRepository::mark_email_verified(conn, user_id, &email, now)? == 0 {
+                return Ok(None);
+            }
+            Ok(db::UserRepository::find_by_id(conn, user_id))
+        })
+        .map_err(|e| internal_error("Failed to verify email address", e))?
+        .ok_or_else(|| {
+            reject::custom(errors::CustomError::InvalidRequestError(
+                "invalid or expired verification token".to_string(),
+            ))
+        })?;
+
+    warn!(target: "security", "Email address verified for {}", user.username);
+    Ok(user)
+}
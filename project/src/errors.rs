@@ -1,10 +1,16 @@
+use crate::{password_policy::PolicyViolation, rate_limit::RateLimitDecision};
 use log::error;
 use serde::Serialize;
 use std::convert::Infallible;
 use thiserror::Error;
-use warp::{http::StatusCode, reply::WithStatus, Rejection, Reply};
+use warp::{
+    http::{HeaderValue, StatusCode},
+    reply::{Response, WithStatus},
+    Rejection, Reply,
+};
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum CustomError {
     #[error("Internal server error")]
     InternalError,
@@ -14,8 +20,36 @@ pub enum CustomError {
     UserExistsError(String),
     #[error("invalid jwt token")]
     InvalidJWTTokenError,
+    #[error("jwt token expired")]
+    ExpiredJWTTokenError,
+    #[error("jwt token not issued for this service")]
+    InvalidJWTAudienceError,
     #[error("not authorized")]
     NotAuthorizedError,
+    #[error("too many requests")]
+    TooManyRequestsError(RateLimitDecision),
+    #[error("too many failed login attempts, try again later")]
+    AccountLockedError(u64),
+    #[error("invalid request: {0}")]
+    InvalidRequestError(String),
+    #[error("invalid refresh token")]
+    InvalidRefreshTokenError,
+    #[error("invalid api key")]
+    InvalidApiKeyError,
+    #[error("email address not verified")]
+    EmailNotVerifiedError,
+    #[error("missing or invalid CSRF token")]
+    CsrfError,
+    #[error("external login failed: {0}")]
+    ExternalLoginError(String),
+    #[error("password does not meet the password policy")]
+    PasswordPolicyError(Vec<PolicyViolation>),
+    // Error code from RFC 6749 section 5.2, with a readable description
+    #[error("{error}: {description}")]
+    OAuthError {
+        error: &'static str,
+        description: String,
+    },
 }
 
 impl warp::reject::Reject for CustomError {}
@@ -32,45 +66,156 @@ pub fn reply_with_status(status_code: StatusCode, message: &str) -> WithStatus<i
         message: message.to_string(),
     });
 
-    return warp::reply::with_status(json, status_code);
+    warp::reply::with_status(json, status_code)
 }
 
-pub async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply, Infallible> {
+#[derive(Serialize, Debug)]
+struct PolicyErrorResponse<'a> {
+    message: String,
+    status: String,
+    violations: &'a [PolicyViolation],
+}
+
+// Lists every broken rule so clients can show them all at once
+fn password_policy(e: &CustomError, violations: &[PolicyViolation]) -> Response {
+    let status_code = StatusCode::UNPROCESSABLE_ENTITY;
+    let json = warp::reply::json(&PolicyErrorResponse {
+        status: status_code.to_string(),
+        message: e.to_string(),
+        violations,
+    });
+
+    warp::reply::with_status(json, status_code).into_response()
+}
+
+fn too_many_requests(e: &CustomError, decision: &RateLimitDecision) -> Response {
+    let mut response =
+        reply_with_status(StatusCode::TOO_MANY_REQUESTS, &e.to_string()).into_response();
+    let headers = response.headers_mut();
+    headers.insert("retry-after", HeaderValue::from(decision.retry_after_secs));
+    headers.insert("ratelimit-limit", HeaderValue::from(decision.limit));
+    headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+    headers.insert("ratelimit-reset", HeaderValue::from(decision.reset_secs));
+    if let Ok(policy) = format!("{};w={}", decision.limit, decision.period_secs).parse() {
+        headers.insert("ratelimit-policy", policy);
+    }
+    response
+}
+
+#[derive(Serialize, Debug)]
+struct OAuthErrorResponse<'a> {
+    error: &'a str,
+    error_description: &'a str,
+}
+
+// OAuth clients expect the RFC 6749 error body rather than ours. A client
+// that failed to authenticate gets 401 with a Basic challenge
+fn oauth_error(error: &str, description: &str) -> Response {
+    let status_code = if error == "invalid_client" {
+        StatusCode::UNAUTHORIZED
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    let json = warp::reply::json(&OAuthErrorResponse {
+        error,
+        error_description: description,
+    });
+
+    let mut response = warp::reply::with_status(json, status_code).into_response();
+    let headers = response.headers_mut();
+    headers.insert("cache-control", HeaderValue::from_static("no-store"));
+    if status_code == StatusCode::UNAUTHORIZED {
+        headers.insert(
+            "www-authenticate",
+            HeaderValue::from_static("Basic realm=\"oauth\""),
+        );
+    }
+    response
+}
+
+// RFC 6750 challenge, so Bearer clients can tell a token to refresh from
+// one that will never be accepted
+fn invalid_token(e: &CustomError) -> Response {
+    let mut response = reply_with_status(StatusCode::UNAUTHORIZED, &e.to_string()).into_response();
+    let challenge = format!(
+        "Bearer error=\"invalid_token\", error_description=\"{}\"",
+        e
+    );
+    if let Ok(challenge) = challenge.parse() {
+        response.headers_mut().insert("www-authenticate", challenge);
+    }
+    response
+}
+
+pub async fn handle_rejection(err: Rejection) -> std::result::Result<Response, Infallible> {
+    Ok(rejection_response(err))
+}
+
+fn rejection_response(err: Rejection) -> Response {
     if err.is_not_found() {
-        return Ok(reply_with_status(StatusCode::NOT_FOUND, "Not Found"));
+        return reply_with_status(StatusCode::NOT_FOUND, "Not Found").into_response();
     }
 
     if let Some(e) = err.find::<CustomError>() {
         match e {
             CustomError::InvalidCredentialsError => {
-                return Ok(reply_with_status(StatusCode::FORBIDDEN, &e.to_string()));
+                return reply_with_status(StatusCode::FORBIDDEN, &e.to_string()).into_response();
             }
             CustomError::UserExistsError(username) => {
-                return Ok(reply_with_status(
+                return reply_with_status(
                     StatusCode::BAD_REQUEST,
                     &format!("User: {} already exists", username),
-                ));
+                )
+                .into_response();
             }
+            // Authenticated, but no role grants the required permission
             CustomError::NotAuthorizedError => {
-                return Ok(reply_with_status(StatusCode::UNAUTHORIZED, &e.to_string()));
+                return reply_with_status(StatusCode::FORBIDDEN, &e.to_string()).into_response();
+            }
+            // Cookie-authenticated request without the page's CSRF token
+            CustomError::CsrfError => {
+                return reply_with_status(StatusCode::FORBIDDEN, &e.to_string()).into_response();
+            }
+            // Right password, but login requires a verified address
+            CustomError::EmailNotVerifiedError => {
+                return reply_with_status(StatusCode::FORBIDDEN, &e.to_string()).into_response();
+            }
+            CustomError::InvalidJWTTokenError
+            | CustomError::ExpiredJWTTokenError
+            | CustomError::InvalidJWTAudienceError => {
+                return invalid_token(e);
+            }
+            CustomError::InvalidRefreshTokenError
+            | CustomError::InvalidApiKeyError
+            | CustomError::ExternalLoginError(_) => {
+                return reply_with_status(StatusCode::UNAUTHORIZED, &e.to_string()).into_response();
+            }
+            CustomError::PasswordPolicyError(violations) => {
+                return password_policy(e, violations);
+            }
+            CustomError::OAuthError { error, description } => {
+                return oauth_error(error, description);
+            }
+            CustomError::TooManyRequestsError(decision) => {
+                return too_many_requests(e, decision);
             }
-            CustomError::InvalidJWTTokenError => {
-                return Ok(reply_with_status(StatusCode::UNAUTHORIZED, &e.to_string()));
+            CustomError::AccountLockedError(retry_after) => {
+                let mut response = reply_with_status(StatusCode::TOO_MANY_REQUESTS, &e.to_string())
+                    .into_response();
+                response
+                    .headers_mut()
+                    .insert("retry-after", HeaderValue::from(*retry_after));
+                return response;
             }
             _ => {
-                return Ok(reply_with_status(StatusCode::BAD_REQUEST, &e.to_string()));
+                return reply_with_status(StatusCode::BAD_REQUEST, &e.to_string()).into_response();
             }
         }
     } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
-        return Ok(reply_with_status(
-            StatusCode::METHOD_NOT_ALLOWED,
-            "Method Not Allowed",
-        ));
+        return reply_with_status(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed")
+            .into_response();
     }
 
     error!("unhandled error: {:?}", err);
-    return Ok(reply_with_status(
-        StatusCode::INTERNAL_SERVER_ERROR,
-        "Internal Server Error",
-    ));
+    reply_with_status(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
 }
@@ -1,15 +1,1003 @@
-use crate::{db, errors, models, security, Result};
+use crate::{
+    api_keys, audit, csrf, db, email_verification, errors, hashing, login_guard, mailer, models,
+    oauth, oidc, password_policy, password_reset, roles, security, security_headers, tokens,
+    two_factor, Result,
+};
+use chrono::Utc;
 use cookie::{Cookie, SameSite};
-use log::{error, info};
-use std::{fs, path::Path};
+use log::{error, info, warn};
+use std::{fs, path::Path, sync::Arc};
 use warp::{
     http::{Response, StatusCode},
     reject, Reply,
 };
 
-pub async fn create_user(user: models::CreateUser, db_pool: db::DbPool) -> Result<impl Reply> {
-    info!("Create user, received UserData: {:?}", user);
+// Shared by public registration and admin user creation
+fn insert_user(
+    conn: &mut db::DbConnection,
+    username: &str,
+    password: &str,
+    email: Option<&str>,
+    role_names: &[String],
+) -> Result<models::UserWithRoles> {
+    // Check if user exists
+    if db::UserRepository::find_by_username(conn, username).is_some() {
+        error!("User already exists");
+        return Err(reject::custom(errors::CustomError::UserExistsError(
+            username.to_string(),
+        )));
+    }
+
+    // The address is optional, but without one the password cannot be reset
+    let email = match email {
+        Some(email) => {
+            let email = mailer::normalize_address(email).ok_or_else(|| {
+                reject::custom(errors::CustomError::InvalidRequestError(
+                    "invalid email address".to_string(),
+                ))
+            })?;
+            match db::UserRepository::find_by_email(conn, &email) {
+                Ok(None) => Some(email),
+                Ok(Some(_)) => {
+                    return Err(reject::custom(errors::CustomError::InvalidRequestError(
+                        "email address already in use".to_string(),
+                    )))
+                }
+                Err(e) => {
+                    error!("Failed to look up email address: {}", e);
+                    return Err(reject::custom(errors::CustomError::InternalError));
+                }
+            }
+        }
+        None => None,
+    };
+
+    password_policy::check(password, username)?;
+
+    // Only roles defined in the database can be granted
+    let granted = roles::resolve(conn, role_names)?;
+
+    // Hash the password
+    let hashed_password = hashing::hash_password(password).map_err(|e| {
+        error!("{}", e);
+        reject::custom(errors::CustomError::InternalError)
+    })?;
+
+    // Create new user object
+    let new_user = models::NewUser {
+        username: username.to_string(),
+        password: hashed_password,
+        email,
+    };
+
+    // Insert user into database
+    match db::UserRepository::create_user_with_roles(conn, &new_user, &granted) {
+        Ok(created_user) => {
+            let roles = granted
+                .into_iter()
+                .map(|role| role.name)
+                .collect::<Vec<_>>();
+            info!("User {} added with roles {}.", username, roles.join(", "));
+            Ok(models::UserWithRoles {
+                user: created_user,
+                roles,
+            })
+        }
+        Err(e) => {
+            error!("Failed to create user: {}", e);
+            Err(reject::custom(errors::CustomError::InternalError))
+        }
+    }
+}
+
+// The account exists either way; a failed mail can be resent later
+fn send_verification(
+    conn: &mut db::DbConnection,
+    mailer: Arc<dyn mailer::Mailer>,
+    user: &models::User,
+) {
+    if email_verification::send_verification(conn, mailer, user).is_err() {
+        error!("Failed to send verification mail to {}", user.username);
+    }
+}
+
+pub async fn create_user(
+    user: models::CreateUser,
+    db_pool: db::DbPool,
+    mailer: Arc<dyn mailer::Mailer>,
+    audit: audit::Audit,
+) -> Result<impl Reply> {
+    info!("Create user, received registration for: {}", user.username);
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    // Self-registration can never grant elevated roles
+    let created_user = insert_user(
+        &mut conn,
+        &user.username,
+        &user.password,
+        user.email.as_deref(),
+        &[security::USER_ROLE.to_string()],
+    )?;
+    audit.success(
+        &mut conn,
+        "user.create",
+        None,
+        Some(&created_user.user.username),
+        Some(format!("roles: {}", created_user.roles.join(", "))),
+    );
+    send_verification(&mut conn, mailer, &created_user.user);
+
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .body(serde_json::to_string(&created_user).unwrap()))
+}
+
+pub async fn admin_create_user(
+    user: models::AdminCreateUser,
+    db_pool: db::DbPool,
+    mailer: Arc<dyn mailer::Mailer>,
+    audit: audit::Audit,
+    admin: String,
+) -> Result<impl Reply> {
+    info!(
+        "Admin {} creating user {} with roles {}",
+        admin,
+        user.username,
+        user.roles.join(", ")
+    );
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let created_user = insert_user(
+        &mut conn,
+        &user.username,
+        &user.password,
+        user.email.as_deref(),
+        &user.roles,
+    )?;
+    audit.success(
+        &mut conn,
+        "user.create",
+        Some(&admin),
+        Some(&created_user.user.username),
+        Some(format!("roles: {}", created_user.roles.join(", "))),
+    );
+    send_verification(&mut conn, mailer, &created_user.user);
+    warn!(
+        target: "security",
+        "Admin {} created user {} with roles {}",
+        admin,
+        created_user.user.username,
+        created_user.roles.join(", ")
+    );
+
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .body(serde_json::to_string(&created_user).unwrap()))
+}
+
+pub async fn login(
+    client_ip: String,
+    accept: Option<String>,
+    login_user: models::LoginUser,
+    db_pool: db::DbPool,
+    login_guard: Arc<login_guard::LoginGuard>,
+    audit: audit::Audit,
+) -> Result<impl Reply> {
+    info!("Received login request...");
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    // Refuse early while the username or client is locked out
+    if let Err(rejection) = login_guard.check(&mut conn, &login_user.username, &client_ip) {
+        let reason = Some("locked out".to_string());
+        audit.failure(&mut conn, "login", None, Some(&login_user.username), reason);
+        return Err(rejection);
+    }
+
+    // Find user in database
+    let user = match db::UserRepository::find_by_username(&mut conn, &login_user.username) {
+        Some(user) => user,
+        None => {
+            error!("User '{}' not found in database", &login_user.username);
+            let reason = Some("unknown user".to_string());
+            audit.failure(&mut conn, "login", None, Some(&login_user.username), reason);
+            hashing::verify_dummy_password(&login_user.password);
+            let delay = login_guard.record_failure(&mut conn, &login_user.username, &client_ip);
+            drop(conn);
+            tokio::time::sleep(delay).await;
+            return Err(reject::custom(errors::CustomError::InvalidCredentialsError));
+        }
+    };
+
+    info!("User found, verifying password...");
+    // A hash that cannot be read fails the login instead of the server
+    let verification = hashing::verify_password(&login_user.password, &user.password)
+        .unwrap_or_else(|e| {
+            warn!(target: "security", "Unusable password hash for {}: {}", user.username, e);
+            hashing::Verification::Mismatch
+        });
+
+    let needs_rehash = match verification {
+        hashing::Verification::Match { needs_rehash } => needs_rehash,
+        hashing::Verification::Mismatch => {
+            error!("Password incorrect for user: {}", &login_user.username);
+            let reason = Some("wrong password".to_string());
+            audit.failure(&mut conn, "login", None, Some(&user.username), reason);
+            let delay = login_guard.record_failure(&mut conn, &login_user.username, &client_ip);
+            drop(conn);
+            tokio::time::sleep(delay).await;
+            return Err(reject::custom(errors::CustomError::InvalidCredentialsError));
+        }
+    };
+    if needs_rehash {
+        upgrade_password_hash(&mut conn, &user, &login_user.password);
+    }
+
+    email_verification::check_login(&user)?;
+
+    // With 2FA the password alone proves nothing yet; failures are only
+    // reset once the second factor has been passed too
+    if let Some(challenge) = two_factor::login_challenge(&mut conn, &user)? {
+        info!(
+            "Password accepted for {}, awaiting second factor",
+            user.username
+        );
+        return Ok(warp::reply::json(&challenge).into_response());
+    }
+
+    login_guard.record_success(&mut conn, &login_user.username);
+    let method = Some("password".to_string());
+    audit.success(
+        &mut conn,
+        "login",
+        Some(&user.username),
+        Some(&user.username),
+        method,
+    );
+
+    info!("Login success!");
+    complete_login(&mut conn, &user, accept.as_deref(), None)
+}
+
+// Re-hash a just verified password with the current algorithm and
+// parameters. Failing to is not a reason to fail the login
+fn upgrade_password_hash(conn: &mut db::DbConnection, user: &models::User, password: &str) {
+    let result = hashing::hash_password(password)
+        .map_err(|e| e.to_string())
+        .and_then(|hash| {
+            db::UserRepository::update_password(conn, user.id, &hash).map_err(|e| e.to_string())
+        });
+
+    match result {
+        Ok(_) => info!("Upgraded password hash for {}", user.username),
+        Err(e) => error!(
+            "Failed to upgrade password hash for {}: {}",
+            user.username, e
+        ),
+    }
+}
+
+// Issue the access and refresh tokens that finish a login
+fn complete_login(
+    conn: &mut db::DbConnection,
+    user: &models::User,
+    accept: Option<&str>,
+    recovery_codes: Option<Vec<String>>,
+) -> Result<warp::reply::Response> {
+    let token = security::get_jwt_for_user(user, roles::effective_roles_for(conn, user)?)?;
+    let refresh_token = tokens::issue_refresh_token(conn, user)?;
+
+    Ok(token_response(
+        token,
+        refresh_token,
+        accept,
+        "Login successful",
+        recovery_codes,
+    ))
+}
+
+pub async fn login_two_factor(
+    client_ip: String,
+    accept: Option<String>,
+    request: models::TwoFactorLogin,
+    db_pool: db::DbPool,
+    login_guard: Arc<login_guard::LoginGuard>,
+    audit: audit::Audit,
+) -> Result<impl Reply> {
+    info!("Received second factor...");
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let user = two_factor::pre_auth_user(&mut conn, &request.pre_auth_token)?;
+
+    // Wrong codes count as failed logins, so guessing codes locks the account
+    login_guard.check(&mut conn, &user.username, &client_ip)?;
+
+    let (accepted, recovery_codes) = match (&request.code, &request.recovery_code) {
+        (Some(code), _) => two_factor::verify_code(&mut conn, &user, code)?,
+        (None, Some(recovery_code)) => (
+            two_factor::use_recovery_code(&mut conn, &user, recovery_code)?,
+            None,
+        ),
+        (None, None) => {
+            return Err(reject::custom(errors::CustomError::InvalidRequestError(
+                "code or recovery_code is required".to_string(),
+            )));
+        }
+    };
+
+    if !accepted {
+        error!("Invalid second factor for user: {}", user.username);
+        let reason = Some("wrong second factor".to_string());
+        audit.failure(&mut conn, "login", None, Some(&user.username), reason);
+        let delay = login_guard.record_failure(&mut conn, &user.username, &client_ip);
+        drop(conn);
+        tokio::time::sleep(delay).await;
+        return Err(reject::custom(errors::CustomError::InvalidCredentialsError));
+    }
+
+    two_factor::consume_pre_auth_token(&request.pre_auth_token);
+    login_guard.record_success(&mut conn, &user.username);
+    let method = Some("password and second factor".to_string());
+    audit.success(
+        &mut conn,
+        "login",
+        Some(&user.username),
+        Some(&user.username),
+        method,
+    );
+
+    info!("Login success with second factor!");
+    complete_login(&mut conn, &user, accept.as_deref(), recovery_codes)
+}
+
+// Enrollment for users whose role requires 2FA before they could ever log in
+pub async fn login_two_factor_enroll(
+    request: models::PreAuthRequest,
+    db_pool: db::DbPool,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let user = two_factor::pre_auth_user(&mut conn, &request.pre_auth_token)?;
+    info!("Two-factor enrollment during login for {}", user.username);
+
+    Ok(warp::reply::json(&two_factor::begin_enrollment(
+        &mut conn, &user,
+    )?))
+}
+
+// Clients asking for JSON (CLIs, mobile apps) get the tokens in the body
+// to send back as a Bearer token; browsers rely on the cookies alone
+fn wants_json(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        accept
+            .split(',')
+            .any(|media| media.split(';').next().unwrap_or("").trim() == "application/json")
+    })
+}
+
+fn token_response(
+    token: String,
+    refresh_token: String,
+    accept: Option<&str>,
+    message: &str,
+    recovery_codes: Option<Vec<String>>,
+) -> warp::reply::Response {
+    let jwt_cookie = jwt_cookie(token.clone()).to_string();
+    let refresh_cookie = refresh_cookie(refresh_token.clone()).to_string();
+
+    // Recovery codes are shown only once, so they force a JSON body
+    let (content_type, body) = if wants_json(accept) || recovery_codes.is_some() {
+        let body = models::LoginResponse {
+            token,
+            token_type: "Bearer".to_string(),
+            expires_in: security::jwt_config().access_token_ttl_secs,
+            refresh_token,
+            recovery_codes,
+        };
+        ("application/json", serde_json::to_string(&body).unwrap())
+    } else {
+        ("text/plain; charset=utf-8", message.to_string())
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", content_type)
+        .header("cache-control", "no-store")
+        .header("set-cookie", jwt_cookie)
+        .header("set-cookie", refresh_cookie)
+        .body(body.into())
+        .unwrap()
+}
+
+// Create an HTTP‑only cookie with SameSite=Lax
+fn jwt_cookie(token: String) -> Cookie<'static> {
+    let mut jwt_cookie = Cookie::new("jwt", token);
+    jwt_cookie.set_path("/");
+    jwt_cookie.set_http_only(true);
+    jwt_cookie.set_same_site(SameSite::Lax);
+    jwt_cookie
+}
+
+// The refresh token is only ever needed by the token endpoints, so it is
+// kept away from cross-site requests entirely
+fn refresh_cookie(token: String) -> Cookie<'static> {
+    let mut refresh_cookie = Cookie::new("refresh_token", token);
+    refresh_cookie.set_path("/");
+    refresh_cookie.set_http_only(true);
+    refresh_cookie.set_same_site(SameSite::Strict);
+    refresh_cookie.set_max_age(cookie::time::Duration::seconds(
+        security::jwt_config().refresh_token_ttl_secs,
+    ));
+    refresh_cookie
+}
+
+pub async fn refresh_token(
+    accept: Option<String>,
+    cookie_token: Option<String>,
+    body: hyper::body::Bytes,
+    db_pool: db::DbPool,
+) -> Result<impl Reply> {
+    info!("Received token refresh request...");
+
+    // The token may come from the cookie or, for non-browser clients, a JSON body
+    let presented = match cookie_token {
+        Some(token) => token,
+        None => serde_json::from_slice::<models::RefreshRequest>(&body)
+            .ok()
+            .and_then(|request| request.refresh_token)
+            .ok_or_else(|| reject::custom(errors::CustomError::InvalidRefreshTokenError))?,
+    };
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let (user, refresh_token) = tokens::rotate_refresh_token(&mut conn, &presented)?;
+    // Roles are looked up again so changes apply from the next refresh
+    let token = security::get_jwt_for_user(&user, roles::effective_roles_for(&mut conn, &user)?)?;
+    info!("Issued refreshed tokens for {}", user.username);
+
+    Ok(token_response(
+        token,
+        refresh_token,
+        accept.as_deref(),
+        "Token refreshed",
+        None,
+    ))
+}
+
+// Ties an OIDC login to the browser that started it
+fn oidc_binding_cookie(value: String) -> Cookie<'static> {
+    let mut cookie = Cookie::new("oidc_login", value);
+    cookie.set_path("/auth/oidc");
+    cookie.set_http_only(true);
+    // Lax, so the cookie comes along on the provider's redirect back to us
+    cookie.set_same_site(SameSite::Lax);
+    cookie
+}
+
+pub async fn oidc_login(provider: Arc<oidc::OidcProvider>) -> Result<impl Reply> {
+    if !provider.enabled() {
+        return Err(warp::reject::not_found());
+    }
+
+    let redirect = provider.begin_login().await?;
+    let mut cookie = oidc_binding_cookie(redirect.browser_binding);
+    cookie.set_max_age(cookie::time::Duration::seconds(
+        provider.config().login_ttl_secs,
+    ));
+
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header("location", redirect.url)
+        .header("cache-control", "no-store")
+        .header("set-cookie", cookie.to_string())
+        .body(String::new())
+        .unwrap())
+}
+
+pub async fn oidc_callback(
+    query: models::OidcCallbackQuery,
+    binding: Option<String>,
+    db_pool: db::DbPool,
+    provider: Arc<oidc::OidcProvider>,
+    audit: audit::Audit,
+) -> Result<impl Reply> {
+    if !provider.enabled() {
+        return Err(warp::reject::not_found());
+    }
+
+    // The provider reports refusals (e.g. the user cancelled) in the query
+    if let Some(error) = query.error {
+        warn!(
+            target: "security",
+            "OIDC provider returned {}: {}",
+            error,
+            query.error_description.unwrap_or_default()
+        );
+        return Err(reject::custom(errors::CustomError::ExternalLoginError(
+            error,
+        )));
+    }
+    let (code, state) = match (query.code, query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => {
+            return Err(reject::custom(errors::CustomError::InvalidRequestError(
+                "code and state are required".to_string(),
+            )))
+        }
+    };
+
+    let claims = provider
+        .complete_login(&code, &state, binding.as_deref())
+        .await?;
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    // Password lockouts do not apply, as no local password was tried
+    let (user, change) = oidc::local_user(&mut conn, provider.config(), &claims)?;
+    let change = match change {
+        Some(oidc::AccountChange::Provisioned(roles)) => Some(("user.create", roles)),
+        Some(oidc::AccountChange::RolesSynced(roles)) => Some(("user.roles", roles)),
+        None => None,
+    };
+    if let Some((action, roles)) = change {
+        let details = format!("oidc ({}); roles: {}", claims.iss, roles.join(", "));
+        audit.success(&mut conn, action, None, Some(&user.username), Some(details));
+    }
+
+    // Whatever the provider checked, local 2FA still applies: the challenge
+    // is answered at /login/2fa just like after a password
+    if let Some(challenge) = two_factor::login_challenge(&mut conn, &user)? {
+        info!(
+            "OIDC identity accepted for {}, awaiting second factor",
+            user.username
+        );
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .header("cache-control", "no-store")
+            .header(
+                "set-cookie",
+                expired_cookie(oidc_binding_cookie(String::new())),
+            )
+            .body(serde_json::to_string(&challenge).unwrap())
+            .unwrap());
+    }
+
+    let token = security::get_jwt_for_user(&user, roles::effective_roles_for(&mut conn, &user)?)?;
+    let refresh_token = tokens::issue_refresh_token(&mut conn, &user)?;
+    let method = Some(format!("oidc ({})", claims.iss));
+    audit.success(
+        &mut conn,
+        "login",
+        Some(&user.username),
+        Some(&user.username),
+        method,
+    );
+    info!("OIDC login success for {}", user.username);
+
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header("location", provider.config().post_login_redirect.as_str())
+        .header("cache-control", "no-store")
+        .header("set-cookie", jwt_cookie(token).to_string())
+        .header("set-cookie", refresh_cookie(refresh_token).to_string())
+        .header(
+            "set-cookie",
+            expired_cookie(oidc_binding_cookie(String::new())),
+        )
+        .body(String::new())
+        .unwrap())
+}
+
+// Anonymous visitors log in first and are sent back here afterwards
+fn login_redirect(raw_query: &str) -> String {
+    let next = format!("/oauth/authorize?{}", raw_query);
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("next", &next)
+        .finish();
+    format!("/?{}", query)
+}
+
+pub async fn oauth_authorize(
+    query: models::AuthorizeQuery,
+    raw_query: String,
+    jwt: Option<String>,
+    db_pool: db::DbPool,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let request = oauth::check_authorize_request(&mut conn, &query)?;
+    let location = match security::session_user(jwt)
+        .and_then(|username| db::UserRepository::find_by_username(&mut conn, &username))
+    {
+        Some(user) => oauth::authorize(&mut conn, &request, &query, &user)?,
+        None => login_redirect(&raw_query),
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header("location", location)
+        .header("cache-control", "no-store")
+        .body(String::new())
+        .unwrap())
+}
+
+pub async fn oauth_token(
+    authorization: Option<String>,
+    request: models::OAuthTokenRequest,
+    db_pool: db::DbPool,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let tokens = oauth::exchange(&mut conn, authorization.as_deref(), request)?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/json")
+        .header("cache-control", "no-store")
+        .header("pragma", "no-cache")
+        .body(serde_json::to_string(&tokens).unwrap())
+        .unwrap())
+}
+
+pub async fn oauth_introspect(
+    authorization: Option<String>,
+    request: models::OAuthTokenHint,
+    db_pool: db::DbPool,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let introspection = oauth::introspect(&mut conn, authorization.as_deref(), request)?;
+
+    Ok(warp::reply::with_header(
+        warp::reply::json(&introspection),
+        "cache-control",
+        "no-store",
+    ))
+}
+
+pub async fn oauth_revoke(
+    authorization: Option<String>,
+    request: models::OAuthTokenHint,
+    db_pool: db::DbPool,
+    audit: audit::Audit,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    if let Some(revoked) = oauth::revoke(&mut conn, authorization.as_deref(), request)? {
+        audit.success(
+            &mut conn,
+            "oauth.revoke",
+            Some(&revoked.client_id),
+            Some(&revoked.owner),
+            Some(revoked.kind.to_string()),
+        );
+    }
+
+    Ok(StatusCode::OK)
+}
+
+pub async fn logout(
+    jwt: Option<String>,
+    refresh_token: Option<String>,
+    db_pool: db::DbPool,
+    audit: audit::Audit,
+) -> Result<impl Reply> {
+    info!("Received logout request...");
+
+    // Tokens that no longer validate are expired anyway and need no revocation
+    let claims = jwt
+        .as_deref()
+        .and_then(|jwt| security::decode_jwt(jwt).ok());
+    if let Some(claims) = &claims {
+        tokens::revoke_access_token(claims);
+        info!("Logged out {}", claims.sub);
+    }
+
+    if claims.is_some() || refresh_token.is_some() {
+        // Get a connection from the pool
+        let mut conn = match db_pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Failed to get database connection: {}", e);
+                return Err(reject::custom(errors::CustomError::InternalError));
+            }
+        };
+
+        if let Some(refresh_token) = refresh_token {
+            tokens::revoke_refresh_token(&mut conn, &refresh_token);
+        }
+        let username = claims.as_ref().map(|claims| claims.sub.as_str());
+        audit.success(&mut conn, "logout", username, username, None);
+    }
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("set-cookie", expired_cookie(jwt_cookie(String::new())))
+        .header("set-cookie", expired_cookie(refresh_cookie(String::new())))
+        .body("Logged out")
+        .unwrap();
+
+    Ok(response)
+}
+
+pub async fn verify_email(
+    query: models::VerifyEmailQuery,
+    db_pool: db::DbPool,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let user = email_verification::verify(&mut conn, &query.token)?;
+    info!("Email address verified for {}", user.username);
+
+    Ok(warp::reply::with_status(
+        "Email address verified",
+        StatusCode::OK,
+    ))
+}
+
+pub async fn resend_verification(
+    request: models::ResendVerificationRequest,
+    db_pool: db::DbPool,
+    mailer: Arc<dyn mailer::Mailer>,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    email_verification::resend(&mut conn, mailer, &request.email)?;
+
+    // Same answer whether or not the address needs verifying
+    Ok(warp::reply::with_status(
+        "If the address awaits verification, a new link has been sent",
+        StatusCode::ACCEPTED,
+    ))
+}
+
+pub async fn forgot_password(
+    request: models::ForgotPasswordRequest,
+    db_pool: db::DbPool,
+    mailer: Arc<dyn mailer::Mailer>,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    password_reset::request_reset(&mut conn, mailer, &request.email)?;
+
+    // Same answer whether or not the address belongs to an account
+    Ok(warp::reply::with_status(
+        "If the address is registered, a reset link has been sent",
+        StatusCode::ACCEPTED,
+    ))
+}
+
+pub async fn reset_password(
+    request: models::ResetPasswordRequest,
+    db_pool: db::DbPool,
+    audit: audit::Audit,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let user = password_reset::reset_password(&mut conn, &request.token, &request.new_password)?;
+    audit.success(
+        &mut conn,
+        "password.reset",
+        None,
+        Some(&user.username),
+        Some("sessions revoked".to_string()),
+    );
+    info!("Password reset for {}", user.username);
+
+    Ok(warp::reply::with_status(
+        "Password has been reset",
+        StatusCode::OK,
+    ))
+}
+
+fn expired_cookie(mut cookie: Cookie<'static>) -> String {
+    cookie.make_removal();
+    cookie.to_string()
+}
+
+pub async fn list_users(db_pool: db::DbPool, admin: String) -> Result<impl Reply> {
+    info!("Admin {} listing users", admin);
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let users = db::UserRepository::list(&mut conn)
+        .and_then(|users| Ok((users, db::RoleRepository::all_grants(&mut conn)?)));
+    match users {
+        Ok((users, grants)) => {
+            let users = users
+                .into_iter()
+                .map(|user| {
+                    let roles = grants
+                        .iter()
+                        .filter(|(user_id, _)| *user_id == user.id)
+                        .map(|(_, role)| role.clone())
+                        .collect();
+                    models::UserWithRoles { user, roles }
+                })
+                .collect::<Vec<_>>();
+            Ok(warp::reply::json(&users))
+        }
+        Err(e) => {
+            error!("Failed to list users: {}", e);
+            Err(reject::custom(errors::CustomError::InternalError))
+        }
+    }
+}
+
+pub async fn set_user_roles(
+    username: String,
+    request: models::SetRolesRequest,
+    db_pool: db::DbPool,
+    audit: audit::Audit,
+    admin: String,
+) -> Result<impl Reply> {
+    info!("Admin {} changing roles of {}", admin, username);
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let user = match db::UserRepository::find_by_username(&mut conn, &username) {
+        Some(user) => user,
+        None => return Err(reject::not_found()),
+    };
+    let granted = roles::resolve(&mut conn, &request.roles)?;
+
+    if let Err(e) = db::RoleRepository::set_user_roles(&mut conn, user.id, &granted) {
+        error!("Failed to set roles of {}: {}", user.username, e);
+        return Err(reject::custom(errors::CustomError::InternalError));
+    }
+
+    // Tokens carry the old roles; make the user refresh to pick up the new set
+    tokens::revoke_access_tokens(&user)?;
+
+    let roles = granted
+        .into_iter()
+        .map(|role| role.name)
+        .collect::<Vec<_>>();
+    audit.success(
+        &mut conn,
+        "user.roles",
+        Some(&admin),
+        Some(&user.username),
+        Some(format!("roles: {}", roles.join(", "))),
+    );
+    warn!(
+        target: "security",
+        "Admin {} set roles of {} to {}", admin, user.username, roles.join(", ")
+    );
+
+    Ok(warp::reply::json(&models::UserWithRoles { user, roles }))
+}
+
+// Look up the authenticated user for the /me endpoints
+fn current_user(conn: &mut db::DbConnection, username: &str) -> Result<models::User> {
+    db::UserRepository::find_by_username(conn, username)
+        .ok_or_else(|| reject::custom(errors::CustomError::InvalidJWTTokenError))
+}
 
+pub async fn get_me(db_pool: db::DbPool, username: String) -> Result<impl Reply> {
     // Get a connection from the pool
     let mut conn = match db_pool.get() {
         Ok(conn) => conn,
@@ -19,41 +1007,289 @@ pub async fn create_user(user: models::CreateUser, db_pool: db::DbPool) -> Resul
         }
     };
 
-    // Check if user exists
-    if let Some(_) = db::UserRepository::find_by_username(&mut conn, &user.username) {
-        error!("User already exists");
-        return Err(reject::custom(errors::CustomError::UserExistsError(
-            user.username,
+    let user = current_user(&mut conn, &username)?;
+    match db::RoleRepository::roles_for_user(&mut conn, user.id) {
+        Ok(roles) => Ok(warp::reply::json(&models::UserWithRoles { user, roles })),
+        Err(e) => {
+            error!("Failed to load roles of {}: {}", user.username, e);
+            Err(reject::custom(errors::CustomError::InternalError))
+        }
+    }
+}
+
+pub async fn enroll_two_factor(db_pool: db::DbPool, username: String) -> Result<impl Reply> {
+    info!("Two-factor enrollment for {}", username);
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let user = current_user(&mut conn, &username)?;
+    Ok(warp::reply::json(&two_factor::begin_enrollment(
+        &mut conn, &user,
+    )?))
+}
+
+pub async fn confirm_two_factor(
+    request: models::TotpCode,
+    db_pool: db::DbPool,
+    username: String,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let user = current_user(&mut conn, &username)?;
+    match two_factor::verify_code(&mut conn, &user, &request.code)? {
+        (true, Some(recovery_codes)) => {
+            Ok(warp::reply::json(&models::RecoveryCodes { recovery_codes }))
+        }
+        (true, None) => Err(reject::custom(errors::CustomError::InvalidRequestError(
+            "two-factor authentication is already enabled".to_string(),
+        ))),
+        (false, _) => Err(reject::custom(errors::CustomError::InvalidCredentialsError)),
+    }
+}
+
+pub async fn regenerate_recovery_codes(
+    request: models::TotpCode,
+    db_pool: db::DbPool,
+    username: String,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let user = current_user(&mut conn, &username)?;
+    match two_factor::verify_code(&mut conn, &user, &request.code)? {
+        (true, None) => {
+            let recovery_codes = two_factor::regenerate_recovery_codes(&mut conn, &user)?;
+            warn!(target: "security", "Recovery codes regenerated for {}", user.username);
+            Ok(warp::reply::json(&models::RecoveryCodes { recovery_codes }))
+        }
+        // Confirming a pending enrollment already produced fresh codes
+        (true, Some(recovery_codes)) => {
+            Ok(warp::reply::json(&models::RecoveryCodes { recovery_codes }))
+        }
+        (false, _) => Err(reject::custom(errors::CustomError::InvalidCredentialsError)),
+    }
+}
+
+pub async fn disable_two_factor(
+    request: models::TotpCode,
+    db_pool: db::DbPool,
+    username: String,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let user = current_user(&mut conn, &username)?;
+    if two_factor::is_required(&mut conn, &user)? {
+        return Err(reject::custom(errors::CustomError::InvalidRequestError(
+            "two-factor authentication is required for your role".to_string(),
         )));
     }
 
-    // Hash the password
-    let hashed_password = security::get_hashed_password(&user.password);
+    let (accepted, _) = two_factor::verify_code(&mut conn, &user, &request.code)?;
+    if !accepted {
+        return Err(reject::custom(errors::CustomError::InvalidCredentialsError));
+    }
 
-    // Create new user object
-    let new_user = models::NewUser {
-        username: user.username.clone(),
-        password: hashed_password,
-        role: user.role.clone(),
+    if let Err(e) = db::TwoFactorRepository::delete(&mut conn, user.id) {
+        error!("Failed to disable 2FA for {}: {}", user.username, e);
+        return Err(reject::custom(errors::CustomError::InternalError));
+    }
+    warn!(target: "security", "Two-factor authentication disabled for {}", user.username);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn change_password(
+    accept: Option<String>,
+    request: models::ChangePasswordRequest,
+    db_pool: db::DbPool,
+    username: String,
+    audit: audit::Audit,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
     };
 
-    // Insert user into database
-    match db::UserRepository::create_user(&mut conn, &new_user) {
-        Ok(created_user) => {
-            info!("User {} added.", &user.username);
-            Ok(Response::builder()
-                .status(StatusCode::CREATED)
-                .body(serde_json::to_string(&created_user).unwrap()))
+    let user = current_user(&mut conn, &username)?;
+
+    // A stolen session alone must not be enough to take over the account
+    let verification = hashing::verify_password(&request.current_password, &user.password)
+        .unwrap_or_else(|e| {
+            warn!(target: "security", "Unusable password hash for {}: {}", user.username, e);
+            hashing::Verification::Mismatch
+        });
+    if verification == hashing::Verification::Mismatch {
+        warn!(target: "security", "Wrong current password in password change for {}", user.username);
+        let reason = Some("wrong current password".to_string());
+        audit.failure(
+            &mut conn,
+            "password.change",
+            Some(&user.username),
+            Some(&user.username),
+            reason,
+        );
+        return Err(reject::custom(errors::CustomError::InvalidCredentialsError));
+    }
+
+    password_policy::check(&request.new_password, &user.username)?;
+    let password_hash = hashing::hash_password(&request.new_password).map_err(|e| {
+        error!("{}", e);
+        reject::custom(errors::CustomError::InternalError)
+    })?;
+
+    if let Err(e) = db::UserRepository::change_password(
+        &mut conn,
+        user.id,
+        &password_hash,
+        Utc::now().naive_utc(),
+    ) {
+        error!("Failed to change password for {}: {}", user.username, e);
+        return Err(reject::custom(errors::CustomError::InternalError));
+    }
+
+    // Sign out everywhere, then hand this client fresh tokens so only the
+    // session that changed the password stays logged in
+    tokens::revoke_user_sessions(&mut conn, &user)?;
+    warn!(target: "security", "Password changed for {}", user.username);
+    audit.success(
+        &mut conn,
+        "password.change",
+        Some(&user.username),
+        Some(&user.username),
+        Some("sessions revoked".to_string()),
+    );
+
+    let token = security::get_jwt_for_user(&user, roles::effective_roles_for(&mut conn, &user)?)?;
+    let refresh_token = tokens::issue_refresh_token(&mut conn, &user)?;
+
+    Ok(token_response(
+        token,
+        refresh_token,
+        accept.as_deref(),
+        "Password changed",
+        None,
+    ))
+}
+
+pub async fn create_api_key(
+    request: models::CreateApiKey,
+    db_pool: db::DbPool,
+    username: String,
+) -> Result<impl Reply> {
+    info!("Creating API key for {}", username);
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
         }
+    };
+
+    let user = current_user(&mut conn, &username)?;
+    let created = api_keys::create(&mut conn, &user, request)?;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&created),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn list_api_keys(db_pool: db::DbPool, username: String) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
         Err(e) => {
-            error!("Failed to create user: {}", e);
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let user = current_user(&mut conn, &username)?;
+    match db::ApiKeyRepository::list_for_user(&mut conn, user.id) {
+        Ok(keys) => Ok(warp::reply::json(&keys)),
+        Err(e) => {
+            error!("Failed to list API keys of {}: {}", user.username, e);
             Err(reject::custom(errors::CustomError::InternalError))
         }
     }
 }
 
-pub async fn login(login_user: models::LoginUser, db_pool: db::DbPool) -> Result<impl Reply> {
-    info!("Received login request...");
+pub async fn revoke_api_key(
+    key_id: i32,
+    db_pool: db::DbPool,
+    audit: audit::Audit,
+    username: String,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let user = current_user(&mut conn, &username)?;
+    match db::ApiKeyRepository::revoke(&mut conn, user.id, key_id, Utc::now().naive_utc()) {
+        Ok(0) => Err(reject::not_found()),
+        Ok(_) => {
+            let details = Some(format!("api key {}", key_id));
+            audit.success(
+                &mut conn,
+                "api_key.revoke",
+                Some(&user.username),
+                Some(&user.username),
+                details,
+            );
+            warn!(target: "security", "API key {} of {} revoked", key_id, user.username);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(e) => {
+            error!("Failed to revoke API key {}: {}", key_id, e);
+            Err(reject::custom(errors::CustomError::InternalError))
+        }
+    }
+}
+
+pub async fn create_oauth_client(
+    request: models::CreateOAuthClient,
+    db_pool: db::DbPool,
+    admin: String,
+) -> Result<impl Reply> {
+    info!("Admin {} registering OAuth client {}", admin, request.name);
 
     // Get a connection from the pool
     let mut conn = match db_pool.get() {
@@ -64,40 +1300,154 @@ pub async fn login(login_user: models::LoginUser, db_pool: db::DbPool) -> Result
         }
     };
 
-    // Find user in database
-    let user = match db::UserRepository::find_by_username(&mut conn, &login_user.username) {
-        Some(user) => user,
-        None => {
-            error!("User '{}' not found in database", &login_user.username);
-            return Err(reject::custom(errors::CustomError::InvalidCredentialsError));
+    let admin = current_user(&mut conn, &admin)?;
+    let created = oauth::create_client(&mut conn, &admin, request)?;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&created),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn list_oauth_clients(db_pool: db::DbPool, admin: String) -> Result<impl Reply> {
+    info!("Admin {} listing OAuth clients", admin);
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
         }
     };
 
-    info!("User found, verifying password...");
-    if !security::verify_password(&login_user.password, &user.password) {
-        error!("Password incorrect for user: {}", &login_user.username);
-        return Err(reject::custom(errors::CustomError::InvalidCredentialsError));
+    match db::OAuthClientRepository::list(&mut conn) {
+        Ok(clients) => Ok(warp::reply::json(&clients)),
+        Err(e) => {
+            error!("Failed to list OAuth clients: {}", e);
+            Err(reject::custom(errors::CustomError::InternalError))
+        }
     }
+}
 
-    info!("Login success!");
-    let token = security::get_jwt_for_user(&user);
+pub async fn delete_oauth_client(
+    client_id: String,
+    db_pool: db::DbPool,
+    admin: String,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
 
-    // Create an HTTP‑only cookie with SameSite=Lax
-    let mut jwt_cookie = Cookie::new("jwt", token);
-    jwt_cookie.set_path("/");
-    jwt_cookie.set_http_only(true);
-    jwt_cookie.set_same_site(SameSite::Lax);
+    match db::OAuthClientRepository::delete(&mut conn, &client_id) {
+        Ok(0) => Err(reject::not_found()),
+        Ok(_) => {
+            warn!(target: "security", "Admin {} removed OAuth client {}", admin, client_id);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(e) => {
+            error!("Failed to remove OAuth client {}: {}", client_id, e);
+            Err(reject::custom(errors::CustomError::InternalError))
+        }
+    }
+}
 
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .header("set-cookie", jwt_cookie.to_string())
-        .body("Login successful")
-        .unwrap();
+pub async fn set_role_two_factor(
+    role_name: String,
+    request: models::RoleTwoFactorRequest,
+    db_pool: db::DbPool,
+    audit: audit::Audit,
+    admin: String,
+) -> Result<impl Reply> {
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
 
-    Ok(response)
+    let role = match db::RoleRepository::find_by_name(&mut conn, &role_name) {
+        Ok(Some(role)) => role,
+        Ok(None) => return Err(reject::not_found()),
+        Err(e) => {
+            error!("Failed to look up role {}: {}", role_name, e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    if let Err(e) = db::RoleRepository::set_require_two_factor(&mut conn, role.id, request.required)
+    {
+        error!("Failed to update role {}: {}", role.name, e);
+        return Err(reject::custom(errors::CustomError::InternalError));
+    }
+    audit.success(
+        &mut conn,
+        "role.two_factor",
+        Some(&admin),
+        Some(&role.name),
+        Some(format!("required: {}", request.required)),
+    );
+    warn!(
+        target: "security",
+        "Admin {} {} two-factor authentication for role {}",
+        admin,
+        if request.required { "required" } else { "stopped requiring" },
+        role.name
+    );
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn revoke_user_sessions(
+    username: String,
+    db_pool: db::DbPool,
+    audit: audit::Audit,
+    admin: String,
+) -> Result<impl Reply> {
+    info!("Admin {} revoking sessions of {}", admin, username);
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    let user = match db::UserRepository::find_by_username(&mut conn, &username) {
+        Some(user) => user,
+        None => return Err(reject::not_found()),
+    };
+
+    tokens::revoke_user_sessions(&mut conn, &user)?;
+    audit.success(
+        &mut conn,
+        "sessions.revoke",
+        Some(&admin),
+        Some(&user.username),
+        None,
+    );
+    warn!(
+        target: "security",
+        "Admin {} revoked all sessions of {}", admin, user.username
+    );
+
+    Ok(StatusCode::NO_CONTENT)
 }
 
-pub async fn get_private(username: String) -> Result<impl Reply> {
+pub async fn get_jwks() -> Result<impl Reply> {
+    Ok(warp::reply::json(&security::key_ring().jwks()))
+}
+
+pub async fn get_private(username: String, csrf_cookie: Option<String>) -> Result<impl Reply> {
     info!("Return private page.");
 
     let template_path =
@@ -105,11 +1455,24 @@ pub async fn get_private(username: String) -> Result<impl Reply> {
 
     match fs::read_to_string(template_path) {
         Ok(template) => {
-            // Replace the placeholder with the username
-            let html = template.replace("{}", &username);
+            // Replace the placeholders with the username, the CSRF token the
+            // page sends back on logout and the script nonce
+            let csrf_token = csrf::page_token(csrf_cookie);
+            let nonce = security_headers::generate_nonce();
+            let html = template
+                .replace("{}", &username)
+                .replace("{{CSRF_TOKEN}}", &csrf_token)
+                .replace("{{CSP_NONCE}}", &nonce);
 
             // Return successful response with the HTML content
-            Ok(warp::reply::html(html))
+            Ok(security_headers::with_nonce(
+                warp::reply::with_header(
+                    warp::reply::html(html),
+                    "set-cookie",
+                    csrf::cookie(csrf_token).to_string(),
+                ),
+                nonce,
+            ))
         }
         Err(_) => {
             // Return error response if template file cannot be read
@@ -118,7 +1481,11 @@ pub async fn get_private(username: String) -> Result<impl Reply> {
     }
 }
 
-pub async fn get_admin_only(db_pool: db::DbPool, username: String) -> Result<impl Reply> {
+pub async fn get_admin_only(
+    db_pool: db::DbPool,
+    audit: audit::Audit,
+    username: String,
+) -> Result<impl Reply> {
     info!("Return admin only page.");
 
     // Get a connection from the pool
@@ -130,6 +1497,14 @@ pub async fn get_admin_only(db_pool: db::DbPool, username: String) -> Result<imp
         }
     };
 
+    audit.success(
+        &mut conn,
+        "admin.access",
+        Some(&username),
+        Some("/admin_only"),
+        None,
+    );
+
     // Count users in database
     let user_count = db::UserRepository::count_users(&mut conn);
 
@@ -154,3 +1529,67 @@ pub async fn get_admin_only(db_pool: db::DbPool, username: String) -> Result<imp
         }
     }
 }
+
+pub async fn unlock_account(
+    unlock: models::UnlockRequest,
+    db_pool: db::DbPool,
+    login_guard: Arc<login_guard::LoginGuard>,
+    audit: audit::Audit,
+    admin: String,
+) -> Result<impl Reply> {
+    info!("Unlock request from admin {}", admin);
+
+    if unlock.username.is_none() && unlock.ip.is_none() {
+        return Err(reject::custom(errors::CustomError::InvalidRequestError(
+            "username or ip is required".to_string(),
+        )));
+    }
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    match login_guard.unlock(&mut conn, unlock.username.as_deref(), unlock.ip.as_deref()) {
+        Ok(_) => {
+            let unlocked = login_guard::describe_unlock(&unlock);
+            audit.success(
+                &mut conn,
+                "account.unlock",
+                Some(&admin),
+                unlock.username.as_deref(),
+                Some(unlocked.clone()),
+            );
+            warn!(target: "security", "Admin {} unlocked {}", admin, unlocked);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(e) => {
+            error!("Failed to unlock: {}", e);
+            Err(reject::custom(errors::CustomError::InternalError))
+        }
+    }
+}
+
+pub async fn list_audit_events(
+    query: models::AuditQuery,
+    db_pool: db::DbPool,
+    audit_log: Arc<audit::AuditLog>,
+    admin: String,
+) -> Result<impl Reply> {
+    info!("Admin {} querying the audit log", admin);
+
+    // Get a connection from the pool
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    };
+
+    Ok(warp::reply::json(&audit_log.search(&mut conn, &query)?))
+}
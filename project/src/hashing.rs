@@ -0,0 +1,327 @@
+use argon2::Argon2;
+use log::warn;
+use scrypt::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Scrypt,
+};
+use std::sync::OnceLock;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum HashError {
+    #[error("malformed password hash: {0}")]
+    Corrupt(String),
+    #[error("unsupported password hash algorithm: {0}")]
+    Unsupported(String),
+    #[error("failed to hash password: {0}")]
+    Hashing(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Argon2id,
+    Scrypt,
+    Bcrypt,
+}
+
+/// Settings from the `[password_hashing]` section of the server config.
+/// Only new hashes use them; existing ones are upgraded on the next login
+#[derive(Clone, Debug)]
+pub struct HashingConfig {
+    pub algorithm: Algorithm,
+    pub argon2: argon2::Params,
+    pub scrypt: scrypt::Params,
+    pub bcrypt_cost: u32,
+}
+
+impl Default for HashingConfig {
+    fn default() -> Self {
+        HashingConfig {
+            algorithm: Algorithm::Argon2id,
+            argon2: argon2::Params::default(),
+            scrypt: scrypt::Params::recommended(),
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+        }
+    }
+}
+
+impl HashingConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = HashingConfig::default();
+        let section = match config.get("password_hashing") {
+            Some(section) => section,
+            None => return defaults,
+        };
+        let integer = |name: &str| section.get(name).and_then(|v| v.as_integer());
+
+        let algorithm = match section.get("algorithm").and_then(|v| v.as_str()) {
+            Some("argon2id") | None => Algorithm::Argon2id,
+            Some("scrypt") => Algorithm::Scrypt,
+            Some("bcrypt") => Algorithm::Bcrypt,
+            Some(other) => {
+                warn!(
+                    "Unknown password hashing algorithm '{}', using argon2id",
+                    other
+                );
+                Algorithm::Argon2id
+            }
+        };
+
+        let argon2 = argon2::Params::new(
+            integer("argon2_memory_kib")
+                .map(|v| v as u32)
+                .unwrap_or(defaults.argon2.m_cost()),
+            integer("argon2_iterations")
+                .map(|v| v as u32)
+                .unwrap_or(defaults.argon2.t_cost()),
+            integer("argon2_parallelism")
+                .map(|v| v as u32)
+                .unwrap_or(defaults.argon2.p_cost()),
+            None,
+        )
+        .unwrap_or_else(|e| {
+            warn!("Invalid argon2 parameters ({}), using defaults", e);
+            defaults.argon2.clone()
+        });
+
+        let scrypt = scrypt::Params::new(
+            integer("scrypt_log_n")
+                .map(|v| v as u8)
+                .unwrap_or(defaults.scrypt.log_n()),
+            integer("scrypt_r")
+                .map(|v| v as u32)
+                .unwrap_or(defaults.scrypt.r()),
+            integer("scrypt_p")
+                .map(|v| v as u32)
+                .unwrap_or(defaults.scrypt.p()),
+            scrypt::Params::RECOMMENDED_LEN,
+        )
+        .unwrap_or_else(|e| {
+            warn!("Invalid scrypt parameters ({}), using defaults", e);
+            defaults.scrypt
+        });
+
+        HashingConfig {
+            algorithm,
+            argon2,
+            scrypt,
+            bcrypt_cost: integer("bcrypt_cost")
+                .map(|v| v.clamp(4, 31) as u32)
+                .unwrap_or(defaults.bcrypt_cost),
+        }
+    }
+}
+
+static CONFIG: OnceLock<HashingConfig> = OnceLock::new();
+
+/// Install the hashing settings used for new password hashes
+pub fn init_config(config: HashingConfig) {
+    if CONFIG.set(config).is_err() {
+        warn!("Password hashing config already initialized");
+    }
+}
+
+pub fn config() -> &'static HashingConfig {
+    CONFIG.get_or_init(HashingConfig::default)
+}
+
+/// Outcome of checking a password against a stored hash
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    Mismatch,
+    // The hash should be replaced, as it was made with another algorithm
+    // or other parameters than are configured now
+    Match { needs_rehash: bool },
+}
+
+/// Hash a password with the configured algorithm and parameters
+pub fn hash_password(password: &str) -> Result<String, HashError> {
+    let config = config();
+    let hashing = |e: argon2::password_hash::Error| HashError::Hashing(e.to_string());
+
+    match config.algorithm {
+        Algorithm::Argon2id => {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::new(
+                argon2::Algorithm::Argon2id,
+                argon2::Version::V0x13,
+                config.argon2.clone(),
+            )
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(hashing)
+        }
+        Algorithm::Scrypt => {
+            let salt = SaltString::generate(&mut OsRng);
+            Scrypt
+                .hash_password_customized(password.as_bytes(), None, None, config.scrypt, &salt)
+                .map(|hash| hash.to_string())
+                .map_err(hashing)
+        }
+        // bcrypt only looks at the first 72 bytes of a password
+        Algorithm::Bcrypt => bcrypt::hash(password, config.bcrypt_cost)
+            .map_err(|e| HashError::Hashing(e.to_string())),
+    }
+}
+
+// bcrypt hashes use the older modular crypt format rather than PHC
+fn is_bcrypt(stored: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| stored.starts_with(prefix))
+}
+
+/// Check a password against a stored hash of any supported algorithm,
+/// picked from the hash itself. Malformed hashes are an error, not a panic
+pub fn verify_password(password: &str, stored: &str) -> Result<Verification, HashError> {
+    let config = config();
+
+    if is_bcrypt(stored) {
+        let parts = stored
+            .parse::<bcrypt::HashParts>()
+            .map_err(|e| HashError::Corrupt(e.to_string()))?;
+        let matched =
+            bcrypt::verify(password, stored).map_err(|e| HashError::Corrupt(e.to_string()))?;
+        return Ok(match matched {
+            false => Verification::Mismatch,
+            true => Verification::Match {
+                needs_rehash: config.algorithm != Algorithm::Bcrypt
+                    || parts.get_cost() != config.bcrypt_cost,
+            },
+        });
+    }
+
+    let parsed = PasswordHash::new(stored).map_err(|e| HashError::Corrupt(e.to_string()))?;
+    let corrupt = |e: argon2::password_hash::Error| HashError::Corrupt(e.to_string());
+    // Without an output the verifiers report a wrong password instead
+    if parsed.hash.is_none() {
+        return Err(HashError::Corrupt("missing hash output".to_string()));
+    }
+
+    let (result, current) = match parsed.algorithm.as_str() {
+        "argon2id" | "argon2i" | "argon2d" => {
+            let params = argon2::Params::try_from(&parsed).map_err(corrupt)?;
+            let current = config.algorithm == Algorithm::Argon2id
+                && parsed.algorithm == argon2::Algorithm::Argon2id.ident()
+                && parsed.version == Some(argon2::Version::V0x13.into())
+                && params.m_cost() == config.argon2.m_cost()
+                && params.t_cost() == config.argon2.t_cost()
+                && params.p_cost() == config.argon2.p_cost();
+            (
+                Argon2::default().verify_password(password.as_bytes(), &parsed),
+                current,
+            )
+        }
+        "scrypt" => {
+            let params = scrypt::Params::try_from(&parsed).map_err(corrupt)?;
+            let current = config.algorithm == Algorithm::Scrypt
+                && params.log_n() == config.scrypt.log_n()
+                && params.r() == config.scrypt.r()
+                && params.p() == config.scrypt.p();
+            (
+                Scrypt.verify_password(password.as_bytes(), &parsed),
+                current,
+            )
+        }
+        other => return Err(HashError::Unsupported(other.to_string())),
+    };
+
+    match result {
+        Ok(()) => Ok(Verification::Match {
+            needs_rehash: !current,
+        }),
+        Err(argon2::password_hash::Error::Password) => Ok(Verification::Mismatch),
+        Err(e) => Err(corrupt(e)),
+    }
+}
+
+// Checked against when a login names an unknown user, so that the response
+// takes as long as it would for a wrong password
+fn dummy_password_hash() -> Option<&'static str> {
+    static DUMMY_HASH: OnceLock<Option<String>> = OnceLock::new();
+    DUMMY_HASH
+        .get_or_init(|| hash_password("dummy-password").ok())
+        .as_deref()
+}
+
+pub fn verify_dummy_password(password: &str) {
+    if let Some(hash) = dummy_password_hash() {
+        let _ = verify_password(password, hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests run with the default config, so argon2id is what new hashes use
+
+    #[test]
+    fn current_hash_matches_only_its_password() {
+        let stored = hash_password("correct horse").unwrap();
+        assert_eq!(
+            verify_password("correct horse", &stored).unwrap(),
+            Verification::Match {
+                needs_rehash: false
+            }
+        );
+        assert_eq!(
+            verify_password("battery staple", &stored).unwrap(),
+            Verification::Mismatch
+        );
+    }
+
+    #[test]
+    fn hashes_of_other_algorithms_match_but_need_rehash() {
+        let salt = SaltString::generate(&mut OsRng);
+        let params = scrypt::Params::new(4, 8, 1, scrypt::Params::RECOMMENDED_LEN).unwrap();
+        let scrypt = Scrypt
+            .hash_password_customized(b"correct horse", None, None, params, &salt)
+            .unwrap()
+            .to_string();
+        let bcrypt = bcrypt::hash("correct horse", 4).unwrap();
+
+        for stored in [scrypt, bcrypt] {
+            assert_eq!(
+                verify_password("correct horse", &stored).unwrap(),
+                Verification::Match { needs_rehash: true },
+                "{}",
+                stored
+            );
+            assert_eq!(
+                verify_password("battery staple", &stored).unwrap(),
+                Verification::Mismatch,
+                "{}",
+                stored
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_hashes_are_corrupt() {
+        for stored in [
+            "",
+            "not a hash",
+            "$argon2id$v=19$m=oops",
+            "$scrypt$ln=4,r=8,p=1$c2FsdA",
+            "$2b$04$tooshort",
+        ] {
+            assert!(
+                matches!(
+                    verify_password("correct horse", stored),
+                    Err(HashError::Corrupt(_))
+                ),
+                "{}",
+                stored
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_algorithms_are_unsupported() {
+        assert!(matches!(
+            verify_password("correct horse", "$pbkdf2-sha256$i=1000$c2FsdHNhbHQ$aGFzaGhhc2hoYXNoaGFzaGhhc2hoYXNoaGFzaGhhc2g"),
+            Err(HashError::Unsupported(algorithm)) if algorithm == "pbkdf2-sha256"
+        ));
+    }
+}
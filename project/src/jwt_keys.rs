@@ -0,0 +1,248 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use serde_json::{json, Value};
+use std::{collections::HashMap, env, fs};
+use thiserror::Error;
+
+// Key id used for the shared-secret key when none is configured
+const DEFAULT_KID: &str = "default";
+
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("JWT_SECRET must be set when signing with HS256")]
+    MissingSecret,
+    #[error("unsupported JWT algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("{0} is required for {1} keys")]
+    MissingSetting(&'static str, String),
+    #[error("failed to read key file {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("invalid key {0}: {1}")]
+    InvalidKey(String, String),
+}
+
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub encoding_key: EncodingKey,
+}
+
+pub struct VerificationKey {
+    pub algorithm: Algorithm,
+    pub decoding_key: DecodingKey,
+    // Public JWK; None for shared secrets, which must never be published
+    jwk: Option<Value>,
+}
+
+/// The key used to sign new tokens plus every key still accepted when
+/// verifying, indexed by `kid`. Keeping retired public keys here lets tokens
+/// signed before a rotation stay valid until they expire
+pub struct KeyRing {
+    pub signing: SigningKey,
+    verification: HashMap<String, VerificationKey>,
+}
+
+fn parse_algorithm(name: &str) -> Result<Algorithm, KeyError> {
+    match name.to_uppercase().as_str() {
+        "HS256" => Ok(Algorithm::HS256),
+        "RS256" => Ok(Algorithm::RS256),
+        "ES256" => Ok(Algorithm::ES256),
+        "EDDSA" => Ok(Algorithm::EdDSA),
+        _ => Err(KeyError::UnsupportedAlgorithm(name.to_string())),
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, KeyError> {
+    fs::read(path).map_err(|e| KeyError::Io(path.to_string(), e))
+}
+
+fn setting<'a>(
+    section: &'a toml::Value,
+    name: &'static str,
+    algorithm: Algorithm,
+) -> Result<&'a str, KeyError> {
+    section
+        .get(name)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| KeyError::MissingSetting(name, format!("{:?}", algorithm)))
+}
+
+fn invalid(kid: &str, e: impl ToString) -> KeyError {
+    KeyError::InvalidKey(kid.to_string(), e.to_string())
+}
+
+// Build the public JWK for an asymmetric key from its PEM-encoded public key
+fn public_jwk(kid: &str, algorithm: Algorithm, pem: &str) -> Result<Value, KeyError> {
+    let mut jwk = match algorithm {
+        Algorithm::RS256 => {
+            use rsa::{
+                pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, traits::PublicKeyParts,
+                RsaPublicKey,
+            };
+
+            let key = RsaPublicKey::from_public_key_pem(pem)
+                .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+                .map_err(|e| invalid(kid, e))?;
+            json!({
+                "kty": "RSA",
+                "n": URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+                "e": URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+            })
+        }
+        Algorithm::ES256 => {
+            use p256::{elliptic_curve::sec1::ToEncodedPoint, pkcs8::DecodePublicKey, PublicKey};
+
+            let key = PublicKey::from_public_key_pem(pem).map_err(|e| invalid(kid, e))?;
+            let point = key.to_encoded_point(false);
+            let (x, y) = match (point.x(), point.y()) {
+                (Some(x), Some(y)) => (x, y),
+                _ => return Err(invalid(kid, "EC point is not uncompressed")),
+            };
+            json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "x": URL_SAFE_NO_PAD.encode(x),
+                "y": URL_SAFE_NO_PAD.encode(y),
+            })
+        }
+        Algorithm::EdDSA => {
+            use ed25519_dalek::{pkcs8::DecodePublicKey, VerifyingKey};
+
+            let key = VerifyingKey::from_public_key_pem(pem).map_err(|e| invalid(kid, e))?;
+            json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "x": URL_SAFE_NO_PAD.encode(key.as_bytes()),
+            })
+        }
+        other => return Err(KeyError::UnsupportedAlgorithm(format!("{:?}", other))),
+    };
+
+    jwk["kid"] = json!(kid);
+    jwk["alg"] = json!(format!("{:?}", algorithm));
+    jwk["use"] = json!("sig");
+    Ok(jwk)
+}
+
+fn load_verification_key(
+    kid: &str,
+    algorithm: Algorithm,
+    public_key_path: &str,
+) -> Result<VerificationKey, KeyError> {
+    let pem = read_pem(public_key_path)?;
+    let decoding_key = match algorithm {
+        Algorithm::RS256 => DecodingKey::from_rsa_pem(&pem),
+        Algorithm::ES256 => DecodingKey::from_ec_pem(&pem),
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+        other => return Err(KeyError::UnsupportedAlgorithm(format!("{:?}", other))),
+    }
+    .map_err(|e| invalid(kid, e))?;
+
+    let pem = String::from_utf8_lossy(&pem);
+    Ok(VerificationKey {
+        algorithm,
+        decoding_key,
+        jwk: Some(public_jwk(kid, algorithm, &pem)?),
+    })
+}
+
+impl KeyRing {
+    /// Load keys from the `[jwt]` section of the server config. Without an
+    /// `algorithm` setting tokens are signed with HS256 and `JWT_SECRET`
+    pub fn from_toml(config: &toml::Value) -> Result<KeyRing, KeyError> {
+        let empty = toml::Value::Table(Default::default());
+        let section = config.get("jwt").unwrap_or(&empty);
+
+        let algorithm = parse_algorithm(
+            section
+                .get("algorithm")
+                .and_then(|v| v.as_str())
+                .unwrap_or("HS256"),
+        )?;
+        let kid = section
+            .get("kid")
+            .and_then(|v| v.as_str())
+            .unwrap_or(DEFAULT_KID)
+            .to_string();
+
+        let mut verification = HashMap::new();
+        let signing = if algorithm == Algorithm::HS256 {
+            let secret = env::var("JWT_SECRET")
+                .ok()
+                .filter(|s| !s.is_empty())
+                .ok_or(KeyError::MissingSecret)?;
+            verification.insert(
+                kid.clone(),
+                VerificationKey {
+                    algorithm,
+                    decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+                    jwk: None,
+                },
+            );
+            SigningKey {
+                kid,
+                algorithm,
+                encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            }
+        } else {
+            let private_pem = read_pem(setting(section, "private_key", algorithm)?)?;
+            let encoding_key = match algorithm {
+                Algorithm::RS256 => EncodingKey::from_rsa_pem(&private_pem),
+                Algorithm::ES256 => EncodingKey::from_ec_pem(&private_pem),
+                _ => EncodingKey::from_ed_pem(&private_pem),
+            }
+            .map_err(|e| invalid(&kid, e))?;
+
+            let public_key_path = setting(section, "public_key", algorithm)?;
+            verification.insert(
+                kid.clone(),
+                load_verification_key(&kid, algorithm, public_key_path)?,
+            );
+            SigningKey {
+                kid,
+                algorithm,
+                encoding_key,
+            }
+        };
+
+        // Previous keys that are no longer used for signing but still verify
+        if let Some(keys) = section.get("verification_keys").and_then(|v| v.as_array()) {
+            for key in keys {
+                let kid = key
+                    .get("kid")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| KeyError::MissingSetting("kid", "verification".to_string()))?;
+                let algorithm =
+                    parse_algorithm(key.get("algorithm").and_then(|v| v.as_str()).ok_or_else(
+                        || KeyError::MissingSetting("algorithm", "verification".to_string()),
+                    )?)?;
+                let public_key_path = setting(key, "public_key", algorithm)?;
+                verification.insert(
+                    kid.to_string(),
+                    load_verification_key(kid, algorithm, public_key_path)?,
+                );
+            }
+        }
+
+        Ok(KeyRing {
+            signing,
+            verification,
+        })
+    }
+
+    /// Key to verify a token with, picked by the `kid` in its header. Tokens
+    /// without a `kid` are checked against the current signing key
+    pub fn verification_key(&self, kid: Option<&str>) -> Option<&VerificationKey> {
+        self.verification.get(kid.unwrap_or(&self.signing.kid))
+    }
+
+    /// JSON Web Key Set with every published public key
+    pub fn jwks(&self) -> Value {
+        let keys = self
+            .verification
+            .values()
+            .filter_map(|key| key.jwk.clone())
+            .collect::<Vec<_>>();
+        json!({ "keys": keys })
+    }
+}
@@ -0,0 +1,255 @@
+use crate::{db, errors, models};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::Connection;
+use log::{error, warn};
+use warp::{reject, Rejection};
+
+// Lockout events go to their own log target so they can be routed separately
+const SECURITY_LOG: &str = "security";
+
+/// Settings from the `[login_protection]` section of the server config
+#[derive(Clone, Debug)]
+pub struct LoginGuardConfig {
+    pub enabled: bool,
+    pub max_failures: i32,
+    pub ip_max_failures: i32,
+    pub failure_window_secs: i64,
+    pub lockout_secs: i64,
+    pub delay_after: i32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for LoginGuardConfig {
+    fn default() -> Self {
+        LoginGuardConfig {
+            enabled: true,
+            max_failures: 5,
+            ip_max_failures: 20,
+            failure_window_secs: 900,
+            lockout_secs: 900,
+            delay_after: 2,
+            base_delay_ms: 250,
+            max_delay_ms: 5000,
+        }
+    }
+}
+
+impl LoginGuardConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = LoginGuardConfig::default();
+        let section = match config.get("login_protection") {
+            Some(section) => section,
+            None => return defaults,
+        };
+        let int = |name: &str, default: i64| {
+            section
+                .get(name)
+                .and_then(|v| v.as_integer())
+                .unwrap_or(default)
+                .max(0)
+        };
+
+        LoginGuardConfig {
+            enabled: section
+                .get("enabled")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.enabled),
+            max_failures: int("max_failures", defaults.max_failures as i64).max(1) as i32,
+            ip_max_failures: int("ip_max_failures", defaults.ip_max_failures as i64).max(1) as i32,
+            failure_window_secs: int("failure_window_secs", defaults.failure_window_secs),
+            lockout_secs: int("lockout_secs", defaults.lockout_secs),
+            delay_after: int("delay_after", defaults.delay_after as i64) as i32,
+            base_delay_ms: int("base_delay_ms", defaults.base_delay_ms as i64) as u64,
+            max_delay_ms: int("max_delay_ms", defaults.max_delay_ms as i64) as u64,
+        }
+    }
+}
+
+fn user_subject(username: &str) -> String {
+    format!("user:{}", username.to_lowercase())
+}
+
+fn ip_subject(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Tracks failed logins per username and per client IP in the database.
+/// Usernames are tracked whether or not the account exists, so lockouts do
+/// not reveal which usernames are registered
+pub struct LoginGuard {
+    config: LoginGuardConfig,
+}
+
+impl LoginGuard {
+    pub fn new(config: LoginGuardConfig) -> Self {
+        LoginGuard { config }
+    }
+
+    /// Reject the attempt if the username or the client IP is currently locked out
+    pub fn check(
+        &self,
+        conn: &mut db::DbConnection,
+        username: &str,
+        ip: &str,
+    ) -> Result<(), Rejection> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+
+        let now = Utc::now().naive_utc();
+        let subjects = [user_subject(username), ip_subject(ip)];
+        let locked = match db::LoginThrottleRepository::find_locked(conn, &subjects, now) {
+            Ok(locked) => locked,
+            Err(e) => {
+                error!("Failed to read login throttles: {}", e);
+                return Err(reject::custom(errors::CustomError::InternalError));
+            }
+        };
+
+        match locked.iter().filter_map(|t| t.locked_until).max() {
+            Some(locked_until) => {
+                warn!(
+                    target: SECURITY_LOG,
+                    "Rejected login for '{}' from {} while locked out", username, ip
+                );
+                Err(reject::custom(errors::CustomError::AccountLockedError(
+                    (locked_until - now).num_seconds().max(1) as u64,
+                )))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Record a failed attempt and return how long the response should be delayed
+    pub fn record_failure(
+        &self,
+        conn: &mut db::DbConnection,
+        username: &str,
+        ip: &str,
+    ) -> std::time::Duration {
+        if !self.config.enabled {
+            return std::time::Duration::ZERO;
+        }
+
+        let now = Utc::now().naive_utc();
+        let user_failures =
+            self.increment(conn, &user_subject(username), self.config.max_failures, now);
+        let ip_failures = self.increment(conn, &ip_subject(ip), self.config.ip_max_failures, now);
+
+        if user_failures == Some(self.config.max_failures) {
+            warn!(
+                target: SECURITY_LOG,
+                "Account '{}' locked for {}s after {} failed logins (last from {})",
+                username,
+                self.config.lockout_secs,
+                self.config.max_failures,
+                ip
+            );
+        }
+        if ip_failures == Some(self.config.ip_max_failures) {
+            warn!(
+                target: SECURITY_LOG,
+                "Client {} locked for {}s after {} failed logins",
+                ip,
+                self.config.lockout_secs,
+                self.config.ip_max_failures
+            );
+        }
+
+        self.delay_for(user_failures.unwrap_or(0).max(ip_failures.unwrap_or(0)))
+    }
+
+    /// Forget the failures for a username after a successful login
+    pub fn record_success(&self, conn: &mut db::DbConnection, username: &str) {
+        if !self.config.enabled {
+            return;
+        }
+
+        if let Err(e) = db::LoginThrottleRepository::clear(conn, &[user_subject(username)]) {
+            error!("Failed to reset login failures for '{}': {}", username, e);
+        }
+    }
+
+    /// Lift the lockout on a username and/or client IP
+    pub fn unlock(
+        &self,
+        conn: &mut db::DbConnection,
+        username: Option<&str>,
+        ip: Option<&str>,
+    ) -> Result<usize, diesel::result::Error> {
+        let subjects = username
+            .map(user_subject)
+            .into_iter()
+            .chain(ip.map(ip_subject))
+            .collect::<Vec<_>>();
+
+        db::LoginThrottleRepository::clear(conn, &subjects)
+    }
+
+    // Bump the failure count for a subject, starting over if the last failure
+    // fell outside the window, and lock it once the threshold is reached
+    fn increment(
+        &self,
+        conn: &mut db::DbConnection,
+        subject: &str,
+        max_failures: i32,
+        now: NaiveDateTime,
+    ) -> Option<i32> {
+        let window_start = now - Duration::seconds(self.config.failure_window_secs);
+        let lockout_secs = self.config.lockout_secs;
+
+        let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let mut throttle = db::LoginThrottleRepository::lock(conn, subject, now)?;
+
+            let lock_expired = throttle.locked_until.is_some_and(|until| until <= now);
+            if throttle.last_failed_at < window_start || lock_expired {
+                throttle.failed_count = 0;
+                throttle.locked_until = None;
+            }
+
+            throttle.failed_count += 1;
+            throttle.last_failed_at = now;
+            if throttle.failed_count >= max_failures {
+                throttle.locked_until = Some(now + Duration::seconds(lockout_secs));
+            }
+
+            db::LoginThrottleRepository::save(conn, &throttle)?;
+            Ok(throttle.failed_count)
+        });
+
+        match result {
+            Ok(count) => Some(count),
+            Err(e) => {
+                error!("Failed to record login failure for {}: {}", subject, e);
+                None
+            }
+        }
+    }
+
+    // Exponential back-off once more than `delay_after` failures have piled up
+    fn delay_for(&self, failures: i32) -> std::time::Duration {
+        if failures <= self.config.delay_after {
+            return std::time::Duration::ZERO;
+        }
+
+        let exponent = (failures - self.config.delay_after - 1).min(16) as u32;
+        let delay_ms = self
+            .config
+            .base_delay_ms
+            .saturating_mul(1u64 << exponent)
+            .min(self.config.max_delay_ms);
+
+        std::time::Duration::from_millis(delay_ms)
+    }
+}
+
+// Human readable summary of what an unlock request targets, for the logs
+pub fn describe_unlock(request: &models::UnlockRequest) -> String {
+    match (&request.username, &request.ip) {
+        (Some(username), Some(ip)) => format!("user '{}' and client {}", username, ip),
+        (Some(username), None) => format!("user '{}'", username),
+        (None, Some(ip)) => format!("client {}", ip),
+        (None, None) => "nothing".to_string(),
+    }
+}
@@ -0,0 +1,199 @@
+use chrono::Utc;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    Message, SmtpTransport, Transport,
+};
+use log::{error, info, warn};
+use std::{env, fs, path::PathBuf, sync::Arc};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum MailError {
+    #[error("invalid address {0}: {1}")]
+    Address(String, String),
+    #[error("failed to build message: {0}")]
+    Build(#[from] lettre::error::Error),
+    #[error("SMTP error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("failed to write mail file: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Outgoing mail. Implementations block, so callers should send from a
+/// blocking task
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+fn mailbox(address: &str) -> Result<Mailbox, MailError> {
+    address.parse().map_err(|e: lettre::address::AddressError| {
+        MailError::Address(address.to_string(), e.to_string())
+    })
+}
+
+/// Validate an address given by a user and bring it to the form it is
+/// stored in, so lookups do not depend on the case it was typed in
+pub fn normalize_address(address: &str) -> Option<String> {
+    let address = address.trim().to_lowercase();
+    if address.len() > 255 {
+        return None;
+    }
+    address.parse::<lettre::Address>().ok().map(|_| address)
+}
+
+/// Delivers mail through an SMTP relay
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        port: u16,
+        starttls: bool,
+        credentials: Option<Credentials>,
+        from: &str,
+    ) -> Result<Self, MailError> {
+        // Without STARTTLS the connection is plain text; only for local relays
+        let builder = if starttls {
+            SmtpTransport::starttls_relay(host)?
+        } else {
+            SmtpTransport::builder_dangerous(host)
+        };
+        let builder = builder.port(port);
+        let builder = match credentials {
+            Some(credentials) => builder.credentials(credentials),
+            None => builder,
+        };
+
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from: mailbox(from)?,
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(mailbox(&email.to)?)
+            .subject(email.subject.clone())
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body.clone())?;
+
+        self.transport.send(&message)?;
+        Ok(())
+    }
+}
+
+/// Writes each message to a file in a directory and logs it, so mail flows
+/// can be exercised without any outside service
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileMailer { dir: dir.into() }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        fs::create_dir_all(&self.dir)?;
+
+        let file_name = format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+            email
+                .to
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect::<String>()
+        );
+        let path = self.dir.join(file_name);
+        fs::write(
+            &path,
+            format!(
+                "To: {}\nSubject: {}\n\n{}\n",
+                email.to, email.subject, email.body
+            ),
+        )?;
+
+        info!("Mail to {} written to {}", email.to, path.display());
+        Ok(())
+    }
+}
+
+/// Build the mailer configured in the `[mail]` section of the server config.
+/// Defaults to the file mailer; an unusable SMTP setup falls back to it too
+pub fn from_config(config: &toml::Value) -> Arc<dyn Mailer> {
+    let empty = toml::Value::Table(Default::default());
+    let section = config.get("mail").unwrap_or(&empty);
+    let string = |name: &str| section.get(name).and_then(|v| v.as_str());
+
+    let file_mailer = || -> Arc<dyn Mailer> {
+        let dir = string("file_dir").unwrap_or("mail");
+        info!("Outgoing mail is written to {}", dir);
+        Arc::new(FileMailer::new(dir))
+    };
+
+    match string("backend").unwrap_or("file") {
+        "smtp" => {
+            let host = string("smtp_host").unwrap_or("localhost");
+            let port = section
+                .get("smtp_port")
+                .and_then(|v| v.as_integer())
+                .unwrap_or(587) as u16;
+            let starttls = section
+                .get("smtp_starttls")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(true);
+            let from = string("from").unwrap_or("no-reply@localhost");
+
+            // The password comes from the environment, never the config file
+            let credentials = string("smtp_username").map(|username| {
+                Credentials::new(
+                    username.to_string(),
+                    env::var("SMTP_PASSWORD").unwrap_or_default(),
+                )
+            });
+
+            match SmtpMailer::new(host, port, starttls, credentials, from) {
+                Ok(mailer) => {
+                    info!("Outgoing mail is sent through {}:{}", host, port);
+                    Arc::new(mailer)
+                }
+                Err(e) => {
+                    error!("Failed to set up SMTP mailer: {}", e);
+                    file_mailer()
+                }
+            }
+        }
+        "file" => file_mailer(),
+        other => {
+            warn!("Unknown mail backend '{}', using file", other);
+            file_mailer()
+        }
+    }
+}
+
+/// Send without blocking the caller; failures are only logged so responses
+/// do not reveal whether mail was sent
+pub fn send_in_background(mailer: Arc<dyn Mailer>, email: Email) {
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = mailer.send(&email) {
+            error!("Failed to send mail to {}: {}", email.to, e);
+        }
+    });
+}
@@ -1,15 +1,41 @@
+// The combined warp filter type of all routes nests deeper than the default allows
+#![recursion_limit = "256"]
+
 use log::info;
 use std::{fs, sync::Arc};
 use warp::{Filter, Rejection};
 
+mod api_keys;
+mod audit;
+mod bootstrap;
+mod compression;
+mod cors;
+mod csrf;
 mod db;
+mod email_verification;
 mod errors;
 mod handlers;
+mod hashing;
+mod jwt_keys;
+mod login_guard;
+mod mailer;
 mod models;
+mod oauth;
+mod oidc;
+mod password_policy;
+mod password_reset;
 mod proxy_server;
+mod rate_limit;
+mod roles;
 mod schema;
 mod security;
+mod security_headers;
+mod state_store;
 mod template_handler;
+#[cfg(test)]
+mod test_support;
+mod tokens;
+mod two_factor;
 
 type Result<T> = std::result::Result<T, Rejection>;
 
@@ -23,9 +49,6 @@ async fn main() {
     let db_pool = db::init_pool();
     info!("Database connection pool initialized");
 
-    // Start the proxy server in a separate task
-    tokio::spawn(proxy_server::start_proxy_server());
-
     // Load server config with more resilient path handling
     let config_content = match fs::read_to_string(
         "E:/Rust/Multithreaded-WebServer-In-Rust/project/src/config/server_config.toml",
@@ -41,6 +64,20 @@ async fn main() {
     let config: toml::Value =
         toml::from_str(&config_content).expect("Failed to parse server configuration");
 
+    // Needed by `create-admin` as well as by the server
+    password_policy::init(password_policy::PasswordPolicyConfig::from_toml(&config));
+    hashing::init_config(hashing::HashingConfig::from_toml(&config));
+
+    // Commands record the admins they create, so the audit log is needed first
+    let audit_log = Arc::new(audit::AuditLog::new(audit::AuditConfig::from_toml(&config)));
+    let bootstrap_audit = audit::Audit::without_request(audit_log.clone());
+
+    // One-off commands such as `create-admin` run instead of the server
+    if bootstrap::run_command(&db_pool, &bootstrap_audit) {
+        return;
+    }
+    bootstrap::ensure_admin_from_env(&db_pool, &bootstrap_audit);
+
     // Setup load balancer if enabled
     let load_balancer = if config["load_balancing"]["enabled"]
         .as_bool()
@@ -58,6 +95,64 @@ async fn main() {
         None
     };
 
+    csrf::init_config(csrf::CsrfConfig::from_toml(&config));
+    let cors_config = Arc::new(cors::CorsConfig::from_toml(&config));
+    let security_headers_config =
+        Arc::new(security_headers::SecurityHeadersConfig::from_toml(&config));
+
+    let compression_config = Arc::new(compression::CompressionConfig::from_toml(&config));
+    info!(
+        "Response compression {}",
+        if compression_config.enabled {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+
+    // Rate counters and other shared state live in the configured store so
+    // that every instance enforces the same limits
+    let state_store = state_store::from_config(&config, &db_pool);
+
+    // Start the proxy server in a separate task
+    tokio::spawn(proxy_server::start_proxy_server(
+        db_pool.clone(),
+        state_store.clone(),
+    ));
+
+    let rate_limiter = Arc::new(rate_limit::RateLimiter::new(
+        rate_limit::RateLimitConfig::from_toml(&config),
+        state_store.clone(),
+        db_pool.clone(),
+    ));
+
+    security::init_jwt_config(security::JwtConfig::from_toml(&config));
+    security::init_key_ring(
+        jwt_keys::KeyRing::from_toml(&config).expect("Failed to load JWT signing keys"),
+    );
+    tokens::init_revocation_store(state_store.clone());
+    roles::init_hierarchy(roles::RoleHierarchy::from_toml(&config));
+    api_keys::init_config(api_keys::ApiKeyConfig::from_toml(&config));
+    two_factor::init(
+        two_factor::TwoFactorConfig::from_toml(&config),
+        state_store.clone(),
+    );
+
+    email_verification::init_config(email_verification::EmailVerificationConfig::from_toml(
+        &config,
+    ));
+    password_reset::init_config(password_reset::PasswordResetConfig::from_toml(&config));
+    oauth::init_config(oauth::OAuthConfig::from_toml(&config));
+    let mailer = mailer::from_config(&config);
+    let oidc_provider = Arc::new(oidc::OidcProvider::new(
+        oidc::OidcConfig::from_toml(&config),
+        state_store.clone(),
+    ));
+
+    let login_guard = Arc::new(login_guard::LoginGuard::new(
+        login_guard::LoginGuardConfig::from_toml(&config),
+    ));
+
     let num_threads = 4;
     let base_port = 8447;
 
@@ -68,71 +163,404 @@ async fn main() {
         let port = base_port + thread_id;
         let db_pool = db_pool.clone();
         let load_balancer = load_balancer.clone();
+        let compression_config = compression_config.clone();
+        let cors_config = cors_config.clone();
+        let security_headers_config = security_headers_config.clone();
+        let rate_limiter = rate_limiter.clone();
+        let login_guard = login_guard.clone();
+        let audit_log = audit_log.clone();
+        let mailer = mailer.clone();
+        let oidc_provider = oidc_provider.clone();
 
         // Create a new route for each port
         let join_handle = tokio::spawn(async move {
             // Load the HTML file and replace placeholders
-            let root = warp::path::end().map(move || {
-                let server_address = format!("127.0.0.1:{}", port);
-
-                // Create template values
-                let template_values = template_handler::create_template_values(
-                    port,
-                    &thread_id.to_string(),
-                    &server_address,
-                );
-
-                // Get upstream server if load balancing is enabled
-                let upstream_server = if let Some(lb) = &load_balancer {
-                    Some(lb.get_next_server())
-                } else {
-                    None
-                };
-
-                // Process the template
-                let html_content = template_handler::process_template(
-                    "./login_page.html",
-                    template_values,
-                    load_balancer.is_some(),
-                    upstream_server,
-                );
-
-                warp::reply::html(html_content)
-            });
+            let root = warp::path::end()
+                .and(warp::cookie::optional::<String>(csrf::COOKIE_NAME))
+                .map(move |csrf_cookie: Option<String>| {
+                    let server_address = format!("127.0.0.1:{}", port);
+
+                    // Create template values
+                    let mut template_values = template_handler::create_template_values(
+                        port,
+                        &thread_id.to_string(),
+                        &server_address,
+                    );
+
+                    // The page sends this back in X-CSRF-Token on every POST
+                    let csrf_token = csrf::page_token(csrf_cookie);
+                    template_values.insert("CSRF_TOKEN".to_string(), csrf_token.clone());
+                    // Marks the page's inline scripts as allowed by the CSP
+                    let nonce = security_headers::generate_nonce();
+                    template_values.insert("CSP_NONCE".to_string(), nonce.clone());
+
+                    // Get upstream server if load balancing is enabled
+                    let upstream_server = load_balancer.as_ref().map(|lb| lb.get_next_server());
+
+                    // Process the template
+                    let html_content = template_handler::process_template(
+                        "./login_page.html",
+                        template_values,
+                        load_balancer.is_some(),
+                        upstream_server,
+                    );
+
+                    security_headers::with_nonce(
+                        warp::reply::with_header(
+                            warp::reply::html(html_content),
+                            "set-cookie",
+                            csrf::cookie(csrf_token).to_string(),
+                        ),
+                        nonce,
+                    )
+                });
+
+            let login_routes = login_routes(
+                db_pool.clone(),
+                rate_limiter.clone(),
+                login_guard.clone(),
+                audit_log.clone(),
+            );
 
             // Filter for passing db pool to handlers
+            let auth_pool = db_pool.clone();
+            let permission_pool = db_pool.clone();
+            let with_permission =
+                move |permission| security::with_permission(permission_pool.clone(), permission);
             let db_filter = warp::any().map(move || db_pool.clone());
+            let login_guard_filter = warp::any().map(move || login_guard.clone());
+            let audit_filter = audit::with_audit(audit_log.clone(), rate_limiter.clone());
+            let audit_log_filter = warp::any().map(move || audit_log.clone());
+            let mailer_filter = warp::any().map(move || mailer.clone());
+            let oidc_filter = warp::any().map(move || oidc_provider.clone());
 
             let user_route = warp::path("user")
                 .and(warp::post())
+                .and(rate_limit::with_rate_limit(rate_limiter.clone(), "user"))
                 .and(warp::body::json())
                 .and(db_filter.clone())
+                .and(mailer_filter.clone())
+                .and(audit_filter.clone())
                 .and_then(handlers::create_user);
 
-            let login_route = warp::path("login")
+            let oidc_login_route = warp::path!("auth" / "oidc" / "login")
+                .and(warp::get())
+                .and(rate_limit::with_rate_limit(rate_limiter.clone(), "login"))
+                .and(oidc_filter.clone())
+                .and_then(handlers::oidc_login);
+
+            let oidc_callback_route = warp::path!("auth" / "oidc" / "callback")
+                .and(warp::get())
+                .and(rate_limit::with_rate_limit(rate_limiter.clone(), "login"))
+                .and(warp::query::<models::OidcCallbackQuery>())
+                .and(warp::cookie::optional::<String>("oidc_login"))
+                .and(db_filter.clone())
+                .and(oidc_filter.clone())
+                .and(audit_filter.clone())
+                .and_then(handlers::oidc_callback);
+
+            let enroll_two_factor_route = warp::path!("me" / "2fa" / "enroll")
+                .and(warp::post())
+                .and(db_filter.clone())
+                .and(security::with_session())
+                .and_then(handlers::enroll_two_factor);
+
+            let confirm_two_factor_route = warp::path!("me" / "2fa" / "confirm")
+                .and(warp::post())
+                .and(warp::body::json())
+                .and(db_filter.clone())
+                .and(security::with_session())
+                .and_then(handlers::confirm_two_factor);
+
+            let recovery_codes_route = warp::path!("me" / "2fa" / "recovery-codes")
+                .and(warp::post())
+                .and(warp::body::json())
+                .and(db_filter.clone())
+                .and(security::with_session())
+                .and_then(handlers::regenerate_recovery_codes);
+
+            let disable_two_factor_route = warp::path!("me" / "2fa")
+                .and(warp::delete())
+                .and(warp::body::json())
+                .and(db_filter.clone())
+                .and(security::with_session())
+                .and_then(handlers::disable_two_factor);
+
+            let me_route = warp::path("me")
+                .and(warp::path::end())
+                .and(warp::get())
+                .and(db_filter.clone())
+                .and(security::with_auth(auth_pool.clone()))
+                .and_then(handlers::get_me);
+
+            let change_password_route = warp::path!("me" / "password")
+                .and(warp::post())
+                .and(rate_limit::with_rate_limit(
+                    rate_limiter.clone(),
+                    "password",
+                ))
+                .and(warp::header::optional::<String>("accept"))
+                .and(warp::body::json())
+                .and(db_filter.clone())
+                .and(security::with_session())
+                .and(audit_filter.clone())
+                .and_then(handlers::change_password);
+
+            let create_api_key_route = warp::path!("me" / "api-keys")
+                .and(warp::post())
+                .and(warp::body::json())
+                .and(db_filter.clone())
+                .and(security::with_session())
+                .and_then(handlers::create_api_key);
+
+            let list_api_keys_route = warp::path!("me" / "api-keys")
+                .and(warp::get())
+                .and(db_filter.clone())
+                .and(security::with_session())
+                .and_then(handlers::list_api_keys);
+
+            let revoke_api_key_route = warp::path!("me" / "api-keys" / i32)
+                .and(warp::delete())
+                .and(db_filter.clone())
+                .and(audit_filter.clone())
+                .and(security::with_session())
+                .and_then(handlers::revoke_api_key);
+
+            let verify_email_route = warp::path("verify")
+                .and(warp::path::end())
+                .and(warp::get())
+                .and(warp::query::<models::VerifyEmailQuery>())
+                .and(db_filter.clone())
+                .and_then(handlers::verify_email);
+
+            let resend_verification_route = warp::path!("verify" / "resend")
+                .and(warp::post())
+                .and(rate_limit::with_rate_limit(rate_limiter.clone(), "email"))
+                .and(warp::body::json())
+                .and(db_filter.clone())
+                .and(mailer_filter.clone())
+                .and_then(handlers::resend_verification);
+
+            let forgot_password_route = warp::path!("password" / "forgot")
+                .and(warp::post())
+                .and(rate_limit::with_rate_limit(
+                    rate_limiter.clone(),
+                    "password",
+                ))
+                .and(warp::body::json())
+                .and(db_filter.clone())
+                .and(mailer_filter.clone())
+                .and_then(handlers::forgot_password);
+
+            let reset_password_route = warp::path!("password" / "reset")
                 .and(warp::post())
+                .and(rate_limit::with_rate_limit(
+                    rate_limiter.clone(),
+                    "password",
+                ))
                 .and(warp::body::json())
                 .and(db_filter.clone())
-                .and_then(handlers::login);
+                .and(audit_filter.clone())
+                .and_then(handlers::reset_password);
+
+            let refresh_route = warp::path!("token" / "refresh")
+                .and(warp::post())
+                .and(rate_limit::with_rate_limit(
+                    rate_limiter.clone(),
+                    "token_refresh",
+                ))
+                .and(warp::header::optional::<String>("accept"))
+                .and(warp::cookie::optional::<String>("refresh_token"))
+                .and(warp::body::bytes())
+                .and(db_filter.clone())
+                .and_then(handlers::refresh_token);
+
+            let logout_route = warp::path("logout")
+                .and(warp::post())
+                .and(security::with_token())
+                .and(warp::cookie::optional::<String>("refresh_token"))
+                .and(db_filter.clone())
+                .and(audit_filter.clone())
+                .and_then(handlers::logout);
+
+            let oauth_authorize_route = warp::path!("oauth" / "authorize")
+                .and(warp::get())
+                .and(warp::query::<models::AuthorizeQuery>())
+                .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+                .and(security::with_token())
+                .and(db_filter.clone())
+                .and_then(handlers::oauth_authorize);
+
+            let oauth_token_route = warp::path!("oauth" / "token")
+                .and(warp::post())
+                .and(rate_limit::with_rate_limit(rate_limiter.clone(), "oauth"))
+                .and(warp::header::optional::<String>("authorization"))
+                .and(warp::body::form())
+                .and(db_filter.clone())
+                .and_then(handlers::oauth_token);
+
+            let oauth_introspect_route = warp::path!("oauth" / "introspect")
+                .and(warp::post())
+                .and(rate_limit::with_rate_limit(rate_limiter.clone(), "oauth"))
+                .and(warp::header::optional::<String>("authorization"))
+                .and(warp::body::form())
+                .and(db_filter.clone())
+                .and_then(handlers::oauth_introspect);
+
+            let oauth_revoke_route = warp::path!("oauth" / "revoke")
+                .and(warp::post())
+                .and(rate_limit::with_rate_limit(rate_limiter.clone(), "oauth"))
+                .and(warp::header::optional::<String>("authorization"))
+                .and(warp::body::form())
+                .and(db_filter.clone())
+                .and(audit_filter.clone())
+                .and_then(handlers::oauth_revoke);
+
+            let jwks_route = warp::path!(".well-known" / "jwks.json")
+                .and(warp::get())
+                .and_then(handlers::get_jwks);
 
             let private_route = warp::path("private")
                 .and(warp::get())
-                .and(security::with_auth(security::Role::User))
+                .and(rate_limit::with_user_rate_limit(
+                    with_permission("private:read"),
+                    rate_limiter.clone(),
+                    "private",
+                ))
+                .and(warp::cookie::optional::<String>(csrf::COOKIE_NAME))
                 .and_then(handlers::get_private);
 
             let admin_only_route = warp::path("admin_only")
                 .and(warp::get())
                 .and(db_filter.clone())
-                .and(security::with_auth(security::Role::Admin))
+                .and(audit_filter.clone())
+                .and(rate_limit::with_user_rate_limit(
+                    with_permission("admin:read"),
+                    rate_limiter.clone(),
+                    "admin_only",
+                ))
                 .and_then(handlers::get_admin_only);
 
+            let unlock_route = warp::path!("admin" / "unlock")
+                .and(warp::post())
+                .and(warp::body::json())
+                .and(db_filter.clone())
+                .and(login_guard_filter.clone())
+                .and(audit_filter.clone())
+                .and(with_permission("accounts:unlock"))
+                .and_then(handlers::unlock_account);
+
+            let admin_user_route = warp::path!("admin" / "users")
+                .and(warp::post())
+                .and(warp::body::json())
+                .and(db_filter.clone())
+                .and(mailer_filter.clone())
+                .and(audit_filter.clone())
+                .and(with_permission("users:write"))
+                .and_then(handlers::admin_create_user);
+
+            let list_users_route = warp::path!("admin" / "users")
+                .and(warp::get())
+                .and(db_filter.clone())
+                .and(with_permission("users:read"))
+                .and_then(handlers::list_users);
+
+            let set_roles_route = warp::path!("admin" / "users" / String / "roles")
+                .and(warp::put())
+                .and(warp::body::json())
+                .and(db_filter.clone())
+                .and(audit_filter.clone())
+                .and(with_permission("users:write"))
+                .and_then(handlers::set_user_roles);
+
+            let role_two_factor_route = warp::path!("admin" / "roles" / String / "2fa")
+                .and(warp::put())
+                .and(warp::body::json())
+                .and(db_filter.clone())
+                .and(audit_filter.clone())
+                .and(with_permission("roles:write"))
+                .and_then(handlers::set_role_two_factor);
+
+            let create_oauth_client_route = warp::path!("admin" / "oauth" / "clients")
+                .and(warp::post())
+                .and(warp::body::json())
+                .and(db_filter.clone())
+                .and(with_permission("oauth:clients"))
+                .and_then(handlers::create_oauth_client);
+
+            let list_oauth_clients_route = warp::path!("admin" / "oauth" / "clients")
+                .and(warp::get())
+                .and(db_filter.clone())
+                .and(with_permission("oauth:clients"))
+                .and_then(handlers::list_oauth_clients);
+
+            let delete_oauth_client_route = warp::path!("admin" / "oauth" / "clients" / String)
+                .and(warp::delete())
+                .and(db_filter.clone())
+                .and(with_permission("oauth:clients"))
+                .and_then(handlers::delete_oauth_client);
+
+            let revoke_sessions_route = warp::path!("admin" / "users" / String / "sessions")
+                .and(warp::delete())
+                .and(db_filter.clone())
+                .and(audit_filter.clone())
+                .and(with_permission("sessions:revoke"))
+                .and_then(handlers::revoke_user_sessions);
+
+            let audit_route = warp::path!("admin" / "audit")
+                .and(warp::get())
+                .and(warp::query::<models::AuditQuery>())
+                .and(db_filter.clone())
+                .and(audit_log_filter.clone())
+                .and(with_permission("audit:read"))
+                .and_then(handlers::list_audit_events);
+
             let routes = root
                 .or(user_route)
-                .or(login_route)
+                .or(login_routes)
+                .or(oidc_login_route)
+                .or(oidc_callback_route)
+                .or(enroll_two_factor_route)
+                .or(confirm_two_factor_route)
+                .or(recovery_codes_route)
+                .or(disable_two_factor_route)
+                .or(me_route)
+                .or(change_password_route)
+                .or(create_api_key_route)
+                .or(list_api_keys_route)
+                .or(revoke_api_key_route)
+                .or(verify_email_route)
+                .or(resend_verification_route)
+                .or(forgot_password_route)
+                .or(reset_password_route)
+                .or(refresh_route)
+                .or(logout_route)
+                .or(oauth_authorize_route)
+                .or(oauth_token_route)
+                .or(oauth_introspect_route)
+                .or(oauth_revoke_route)
+                .or(jwks_route)
                 .or(private_route)
                 .or(admin_only_route)
-                .with(warp::cors().allow_any_origin())
+                .or(unlock_route)
+                .or(admin_user_route)
+                .or(list_users_route)
+                .or(set_roles_route)
+                .or(role_two_factor_route)
+                .or(create_oauth_client_route)
+                .or(list_oauth_clients_route)
+                .or(delete_oauth_client_route)
+                .or(revoke_sessions_route)
+                .or(audit_route);
+
+            // Boxed so the wrappers below do not nest the full route type,
+            // which otherwise exhausts the compiler's memory
+            let routes = csrf::with_csrf_protection().and(routes).boxed();
+
+            let routes = compression::with_compression(routes, compression_config)
                 .recover(errors::handle_rejection);
+            let routes = security_headers::with_security_headers(routes, security_headers_config);
+            let routes = cors::with_cors(routes, cors_config);
 
             info!("Thread {} starting server on port {}", thread_id, port);
             warp::serve(routes).run(([127, 0, 0, 1], port)).await;
@@ -148,3 +576,100 @@ async fn main() {
         }
     }
 }
+
+/// `POST /login` and the second step for accounts using two-factor
+/// authentication. Each path must end where it is declared, or `/login`
+/// would also take the JSON bodies meant for `/login/2fa`
+fn login_routes(
+    db_pool: db::DbPool,
+    rate_limiter: Arc<rate_limit::RateLimiter>,
+    login_guard: Arc<login_guard::LoginGuard>,
+    audit_log: Arc<audit::AuditLog>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = Rejection> + Clone {
+    let db_filter = warp::any().map(move || db_pool.clone());
+    let login_guard_filter = warp::any().map(move || login_guard.clone());
+    let audit_filter = audit::with_audit(audit_log, rate_limiter.clone());
+
+    let login_route = warp::path!("login")
+        .and(warp::post())
+        .and(rate_limit::with_rate_limit(rate_limiter.clone(), "login"))
+        .and(rate_limit::with_client_ip(rate_limiter.clone()))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and(login_guard_filter.clone())
+        .and(audit_filter.clone())
+        .and_then(handlers::login);
+
+    let login_two_factor_route = warp::path!("login" / "2fa")
+        .and(warp::post())
+        .and(rate_limit::with_rate_limit(rate_limiter.clone(), "login"))
+        .and(rate_limit::with_client_ip(rate_limiter.clone()))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and(login_guard_filter)
+        .and(audit_filter)
+        .and_then(handlers::login_two_factor);
+
+    let login_enroll_route = warp::path!("login" / "2fa" / "enroll")
+        .and(warp::post())
+        .and(rate_limit::with_rate_limit(rate_limiter, "login"))
+        .and(warp::body::json())
+        .and(db_filter)
+        .and_then(handlers::login_two_factor_enroll);
+
+    login_route
+        .or(login_two_factor_route)
+        .or(login_enroll_route)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::StatusCode;
+
+    // Requests for the second step must reach their own handlers, which
+    // refuse an unknown pre-auth token with 403, rather than being taken by
+    // `/login` and failing on the body
+    #[tokio::test]
+    async fn second_factor_paths_reach_their_handlers() {
+        let pool = match test_support::test_pool() {
+            Some(pool) => pool,
+            None => return,
+        };
+        let store: Arc<dyn state_store::StateStore> =
+            Arc::new(state_store::MemoryStateStore::new());
+        two_factor::init(two_factor::TwoFactorConfig::default(), store.clone());
+        let routes = login_routes(
+            pool.clone(),
+            Arc::new(rate_limit::RateLimiter::new(
+                rate_limit::RateLimitConfig::default(),
+                store,
+                pool,
+            )),
+            Arc::new(login_guard::LoginGuard::new(
+                login_guard::LoginGuardConfig::default(),
+            )),
+            Arc::new(audit::AuditLog::new(audit::AuditConfig::default())),
+        )
+        .recover(errors::handle_rejection);
+
+        for (path, body) in [
+            (
+                "/login/2fa",
+                r#"{"pre_auth_token": "unknown", "code": "123456"}"#,
+            ),
+            ("/login/2fa/enroll", r#"{"pre_auth_token": "unknown"}"#),
+        ] {
+            let response = warp::test::request()
+                .method("POST")
+                .path(path)
+                .header("content-type", "application/json")
+                .body(body)
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", path);
+        }
+    }
+}
@@ -1,6 +1,11 @@
-use crate::schema::users;
+use crate::schema::{
+    api_keys, audit_events, email_verification_tokens, external_identities, login_throttles,
+    oauth_authorization_codes, oauth_clients, oauth_refresh_tokens, password_reset_tokens,
+    rate_limit_buckets, recovery_codes, refresh_tokens, roles, state_entries, user_roles,
+    user_totp, users,
+};
 use chrono::NaiveDateTime;
-use diesel::{Identifiable, Insertable, Queryable};
+use diesel::{AsChangeset, Associations, Identifiable, Insertable, Queryable};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Queryable, Identifiable, Clone)]
@@ -8,9 +13,12 @@ use serde::{Deserialize, Serialize};
 pub struct User {
     pub id: i32,
     pub username: String,
+    #[serde(skip_serializing)]
     pub password: String,
-    pub role: String,
     pub created_at: NaiveDateTime,
+    pub email: Option<String>,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub password_changed_at: Option<NaiveDateTime>,
 }
 
 #[derive(Debug, Insertable)]
@@ -18,14 +26,45 @@ pub struct User {
 pub struct NewUser {
     pub username: String,
     pub password: String,
-    pub role: String,
+    pub email: Option<String>,
 }
 
+// A user together with the roles granted to it directly
+#[derive(Debug, Serialize)]
+pub struct UserWithRoles {
+    #[serde(flatten)]
+    pub user: User,
+    pub roles: Vec<String>,
+}
+
+// Public self-registration; always creates a `User` account
 #[derive(Debug, Deserialize, Clone)]
 pub struct CreateUser {
     pub username: String,
     pub password: String,
-    pub role: String,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+// Admin-only user creation, allowed to pick the roles
+#[derive(Debug, Deserialize, Clone)]
+pub struct AdminCreateUser {
+    pub username: String,
+    pub password: String,
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SetRolesRequest {
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -37,11 +76,499 @@ pub struct LoginUser {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Claims {
     pub sub: String,
-    pub role: String,
+    // Effective roles, including those inherited through the hierarchy
+    pub roles: Vec<String>,
+    pub iss: String,
+    pub aud: String,
     pub exp: usize,
+    pub nbf: usize,
+    pub iat: usize,
+    // Unique token id, used to revoke a single token on logout
+    pub jti: String,
+    // OAuth client the token was issued to; absent for our own sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    // Space-separated permissions an OAuth client was granted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct LoginResponse {
     pub token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub refresh_token: String,
+    // Only present right after 2FA enrollment completed during login
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_codes: Option<Vec<String>>,
+}
+
+// Sent instead of tokens when the password was right but a second factor
+// is still needed
+#[derive(Serialize)]
+pub struct TwoFactorChallenge {
+    pub two_factor_required: bool,
+    // The user must enroll first, via /login/2fa/enroll
+    pub enrollment_required: bool,
+    pub pre_auth_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TwoFactorLogin {
+    pub pre_auth_token: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PreAuthRequest {
+    pub pre_auth_token: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollment {
+    // Base32 secret for manual entry
+    pub secret: String,
+    // otpauth:// URI; doubles as the payload to render as a QR code
+    pub provisioning_uri: String,
+    pub qr_payload: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RoleTwoFactorRequest {
+    pub required: bool,
+}
+
+#[derive(Debug, Queryable, Insertable, AsChangeset)]
+#[diesel(table_name = rate_limit_buckets)]
+pub struct RateLimitBucket {
+    pub key: String,
+    pub tokens: f64,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Queryable, Insertable, AsChangeset)]
+#[diesel(table_name = state_entries)]
+#[diesel(treat_none_as_null = true)]
+pub struct StateEntry {
+    pub key: String,
+    pub value: String,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Queryable, Insertable, AsChangeset)]
+#[diesel(table_name = login_throttles)]
+#[diesel(treat_none_as_null = true)]
+pub struct LoginThrottle {
+    pub subject: String,
+    pub failed_count: i32,
+    pub last_failed_at: NaiveDateTime,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UnlockRequest {
+    pub username: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations, Clone)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = refresh_tokens)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub family_id: String,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = refresh_tokens)]
+pub struct NewRefreshToken {
+    pub user_id: i32,
+    pub token_hash: String,
+    pub family_id: String,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RefreshRequest {
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Queryable, Identifiable, Clone)]
+#[diesel(table_name = roles)]
+pub struct Role {
+    pub id: i32,
+    pub name: String,
+    pub description: String,
+    pub created_at: NaiveDateTime,
+    pub require_2fa: bool,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = user_roles)]
+pub struct UserRole {
+    pub user_id: i32,
+    pub role_id: i32,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations, Clone)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = user_totp)]
+#[diesel(primary_key(user_id))]
+pub struct UserTotp {
+    pub user_id: i32,
+    pub secret: String,
+    pub confirmed_at: Option<NaiveDateTime>,
+    pub last_used_step: Option<i64>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = recovery_codes)]
+pub struct NewRecoveryCode {
+    pub user_id: i32,
+    pub code_hash: String,
+}
+
+#[derive(Debug, Serialize, Queryable, Identifiable, Associations, Clone)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = api_keys)]
+pub struct ApiKey {
+    pub id: i32,
+    #[serde(skip_serializing)]
+    pub user_id: i32,
+    pub name: String,
+    pub key_prefix: String,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = api_keys)]
+pub struct NewApiKey {
+    pub user_id: i32,
+    pub name: String,
+    pub key_prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CreateApiKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+    // Defaults to [api_keys] default_ttl_days
+    pub expires_in_days: Option<i64>,
+}
+
+// Returned once at creation; the plaintext key cannot be retrieved later
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = password_reset_tokens)]
+pub struct NewPasswordResetToken {
+    pub user_id: i32,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = email_verification_tokens)]
+pub struct NewEmailVerificationToken {
+    pub user_id: i32,
+    pub email: String,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct VerifyEmailQuery {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ResendVerificationRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = external_identities)]
+pub struct NewExternalIdentity {
+    pub user_id: i32,
+    pub issuer: String,
+    pub subject: String,
+}
+
+// Query string the identity provider redirects back with
+#[derive(Debug, Deserialize, Clone)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+#[derive(Debug, Serialize, Queryable, Identifiable, Clone)]
+#[diesel(table_name = oauth_clients)]
+pub struct OAuthClient {
+    #[serde(skip_serializing)]
+    pub id: i32,
+    pub client_id: String,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub secret_hash: Option<String>,
+    pub redirect_uris: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = oauth_clients)]
+pub struct NewOAuthClient {
+    pub client_id: String,
+    pub name: String,
+    pub secret_hash: Option<String>,
+    pub redirect_uris: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CreateOAuthClient {
+    pub name: String,
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub scopes: Vec<String>,
+    // Public clients (SPAs, native apps) get no secret and must use PKCE
+    #[serde(default)]
+    pub public: bool,
+}
+
+// Returned once at creation; the secret cannot be retrieved later
+#[derive(Serialize)]
+pub struct CreatedOAuthClient {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    #[serde(flatten)]
+    pub client: OAuthClient,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations, Clone)]
+#[diesel(belongs_to(OAuthClient, foreign_key = oauth_client_id))]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = oauth_authorization_codes)]
+pub struct OAuthAuthorizationCode {
+    pub id: i32,
+    pub oauth_client_id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    pub code_challenge: Option<String>,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = oauth_authorization_codes)]
+pub struct NewOAuthAuthorizationCode {
+    pub oauth_client_id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    pub code_challenge: Option<String>,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Queryable, Identifiable, Associations, Clone)]
+#[diesel(belongs_to(OAuthClient, foreign_key = oauth_client_id))]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = oauth_refresh_tokens)]
+pub struct OAuthRefreshToken {
+    pub id: i32,
+    pub oauth_client_id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    // Set when the token was exchanged for a new one, as opposed to revoked
+    pub rotated_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = oauth_refresh_tokens)]
+pub struct NewOAuthRefreshToken {
+    pub oauth_client_id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub expires_at: NaiveDateTime,
+}
+
+// Query string of /oauth/authorize (RFC 6749 section 4.1.1, RFC 7636)
+#[derive(Debug, Deserialize, Clone)]
+pub struct AuthorizeQuery {
+    pub response_type: Option<String>,
+    pub client_id: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+// Form body of /oauth/token. Which fields are needed depends on the grant
+#[derive(Debug, Deserialize, Clone)]
+pub struct OAuthTokenRequest {
+    pub grant_type: Option<String>,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    pub scope: Option<String>,
+    // client_secret_post, or just the id for public clients
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct OAuthTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    pub scope: String,
+}
+
+// Form body of /oauth/introspect and /oauth/revoke (RFC 7662, RFC 7009).
+// token_type_hint is not needed: access tokens are JWTs, refresh tokens are not
+#[derive(Debug, Deserialize, Clone)]
+pub struct OAuthTokenHint {
+    pub token: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+// RFC 7662 response; everything but `active` is left out for unusable tokens
+#[derive(Serialize, Default)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+}
+
+#[derive(Debug, Serialize, Queryable, Identifiable, Clone)]
+#[diesel(table_name = audit_events)]
+pub struct AuditEvent {
+    pub id: i32,
+    pub action: String,
+    pub outcome: String,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub details: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = audit_events)]
+pub struct NewAuditEvent {
+    pub action: String,
+    pub outcome: String,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub details: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+}
+
+// Query string of GET /admin/audit; every filter is optional
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct AuditQuery {
+    pub action: Option<String>,
+    pub outcome: Option<String>,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct AuditPage {
+    pub events: Vec<AuditEvent>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
 }
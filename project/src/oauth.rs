@@ -0,0 +1,838 @@
+use crate::{db, errors, models, roles, security, tokens};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::{Duration, Utc};
+use diesel::Connection;
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+use url::Url;
+use warp::{reject, Rejection};
+
+pub const AUTHORIZATION_CODE: &str = "authorization_code";
+pub const REFRESH_TOKEN: &str = "refresh_token";
+pub const CLIENT_CREDENTIALS: &str = "client_credentials";
+
+// Lets secret scanners and humans recognise client ids and leaked secrets
+const CLIENT_ID_PREFIX: &str = "wsc_";
+const CLIENT_SECRET_PREFIX: &str = "wscs_";
+
+/// Settings from the `[oauth]` section of the server config. Access tokens
+/// use the `[jwt]` lifetime and keys
+#[derive(Clone, Debug)]
+pub struct OAuthConfig {
+    // Time a client has to redeem an authorization code
+    pub code_ttl_secs: i64,
+    pub refresh_token_ttl_secs: i64,
+}
+
+impl Default for OAuthConfig {
+    fn default() -> Self {
+        OAuthConfig {
+            code_ttl_secs: 60,
+            refresh_token_ttl_secs: 30 * 24 * 3600,
+        }
+    }
+}
+
+impl OAuthConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = OAuthConfig::default();
+        let section = match config.get("oauth") {
+            Some(section) => section,
+            None => return defaults,
+        };
+
+        OAuthConfig {
+            code_ttl_secs: section
+                .get("code_ttl_secs")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.clamp(10, 600))
+                .unwrap_or(defaults.code_ttl_secs),
+            refresh_token_ttl_secs: section
+                .get("refresh_token_ttl_secs")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.max(60))
+                .unwrap_or(defaults.refresh_token_ttl_secs),
+        }
+    }
+}
+
+static CONFIG: OnceLock<OAuthConfig> = OnceLock::new();
+
+/// Install the OAuth settings
+pub fn init_config(config: OAuthConfig) {
+    if CONFIG.set(config).is_err() {
+        warn!("OAuth config already initialized");
+    }
+}
+
+pub fn config() -> &'static OAuthConfig {
+    CONFIG.get_or_init(OAuthConfig::default)
+}
+
+fn internal_error(context: &str, e: impl std::fmt::Display) -> Rejection {
+    error!("{}: {}", context, e);
+    reject::custom(errors::CustomError::InternalError)
+}
+
+fn oauth_error(error: &'static str, description: &str) -> Rejection {
+    reject::custom(errors::CustomError::OAuthError {
+        error,
+        description: description.to_string(),
+    })
+}
+
+fn invalid_request(message: &str) -> Rejection {
+    reject::custom(errors::CustomError::InvalidRequestError(
+        message.to_string(),
+    ))
+}
+
+// Space-separated scope parameter; None when absent or blank
+fn parse_scope(scope: Option<&str>) -> Option<Vec<String>> {
+    let scopes = scope?
+        .split_whitespace()
+        .map(|scope| scope.to_string())
+        .collect::<Vec<_>>();
+    if scopes.is_empty() {
+        None
+    } else {
+        Some(scopes)
+    }
+}
+
+fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+// Redirect URIs are compared exactly later on, so they must be usable as is
+fn valid_redirect_uri(uri: &str) -> bool {
+    Url::parse(uri).is_ok_and(|url| {
+        matches!(url.scheme(), "http" | "https") && url.host().is_some() && url.fragment().is_none()
+    })
+}
+
+/// Register a client. Its scopes must be permissions the registering admin
+/// holds, so a client can never do more than whoever created it
+pub fn create_client(
+    conn: &mut db::DbConnection,
+    admin: &models::User,
+    request: models::CreateOAuthClient,
+) -> Result<models::CreatedOAuthClient, Rejection> {
+    let name = request.name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return Err(invalid_request("name must be 1 to 100 characters"));
+    }
+
+    if request.grant_types.is_empty() {
+        return Err(invalid_request("at least one grant type is required"));
+    }
+    if let Some(grant) = request
+        .grant_types
+        .iter()
+        .find(|g| ![AUTHORIZATION_CODE, REFRESH_TOKEN, CLIENT_CREDENTIALS].contains(&g.as_str()))
+    {
+        return Err(invalid_request(&format!(
+            "unsupported grant type: {}",
+            grant
+        )));
+    }
+    let allows = |grant: &str| request.grant_types.iter().any(|g| g == grant);
+    if request.public && allows(CLIENT_CREDENTIALS) {
+        return Err(invalid_request(
+            "public clients cannot use the client_credentials grant",
+        ));
+    }
+    if allows(AUTHORIZATION_CODE) && request.redirect_uris.is_empty() {
+        return Err(invalid_request(
+            "the authorization_code grant needs at least one redirect URI",
+        ));
+    }
+    if let Some(uri) = request
+        .redirect_uris
+        .iter()
+        .find(|uri| !valid_redirect_uri(uri))
+    {
+        return Err(invalid_request(&format!("invalid redirect URI: {}", uri)));
+    }
+
+    if request.scopes.is_empty() {
+        return Err(invalid_request("at least one scope is required"));
+    }
+    let effective = roles::effective_roles_for(conn, admin)?;
+    let granted = db::RoleRepository::permissions_for_roles(conn, &effective)
+        .map_err(|e| internal_error("Failed to load permissions", e))?;
+    if let Some(scope) = request.scopes.iter().find(|s| !granted.contains(s)) {
+        return Err(invalid_request(&format!("scope not permitted: {}", scope)));
+    }
+
+    let client_secret = if request.public {
+        None
+    } else {
+        Some(format!(
+            "{}{}",
+            CLIENT_SECRET_PREFIX,
+            security::generate_token()
+        ))
+    };
+    let new_client = models::NewOAuthClient {
+        client_id: format!("{}{}", CLIENT_ID_PREFIX, &security::generate_token()[..24]),
+        name: name.to_string(),
+        secret_hash: client_secret.as_deref().map(security::hash_token),
+        redirect_uris: request.redirect_uris,
+        grant_types: request.grant_types,
+        scopes: request.scopes,
+    };
+
+    let client = db::OAuthClientRepository::create(conn, &new_client)
+        .map_err(|e| internal_error("Failed to store OAuth client", e))?;
+    warn!(
+        target: "security",
+        "OAuth client {} ({}) registered by {} with scopes {}",
+        client.client_id,
+        client.name,
+        admin.username,
+        client.scopes.join(", ")
+    );
+
+    Ok(models::CreatedOAuthClient {
+        client_secret,
+        client,
+    })
+}
+
+// RFC 6749 section 2.3.1. Both parts are meant to be form-encoded first, which
+// changes nothing for the ids and secrets we hand out
+fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (client_id, secret) = decoded.split_once(':')?;
+    Some((client_id.to_string(), secret.to_string()))
+}
+
+/// Identify the calling client from HTTP Basic credentials or, failing
+/// that, the client_id and client_secret form fields. Public clients send
+/// their id alone; confidential ones must send their secret
+pub fn authenticate_client(
+    conn: &mut db::DbConnection,
+    authorization: Option<&str>,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Result<models::OAuthClient, Rejection> {
+    let invalid = || oauth_error("invalid_client", "client authentication failed");
+
+    let (client_id, secret) = match authorization.and_then(basic_credentials) {
+        Some((client_id, secret)) => (client_id, Some(secret)),
+        None => (
+            client_id.ok_or_else(invalid)?.to_string(),
+            client_secret.map(|secret| secret.to_string()),
+        ),
+    };
+    let secret = secret.filter(|secret| !secret.is_empty());
+
+    let client = db::OAuthClientRepository::find(conn, &client_id)
+        .map_err(|e| internal_error("Failed to look up OAuth client", e))?
+        .ok_or_else(invalid)?;
+
+    match (&client.secret_hash, secret) {
+        (Some(hash), Some(secret)) if security::hash_token(&secret) == *hash => Ok(client),
+        (None, None) => Ok(client),
+        _ => {
+            warn!(
+                target: "security",
+                "Failed authentication of OAuth client {}",
+                client.client_id
+            );
+            Err(invalid())
+        }
+    }
+}
+
+/// An authorization request whose client and redirect URI check out, so
+/// further errors can be reported to the client by redirect
+pub struct AuthorizeRequest {
+    pub client: models::OAuthClient,
+    pub redirect_uri: String,
+    state: Option<String>,
+}
+
+impl AuthorizeRequest {
+    fn redirect(&self, params: &[(&str, &str)]) -> String {
+        let mut url = match Url::parse(&self.redirect_uri) {
+            Ok(url) => url,
+            // Checked at registration
+            Err(_) => return self.redirect_uri.clone(),
+        };
+        {
+            let mut query = url.query_pairs_mut();
+            query.extend_pairs(params);
+            if let Some(state) = &self.state {
+                query.append_pair("state", state);
+            }
+        }
+        url.to_string()
+    }
+
+    fn error(&self, error: &str, description: &str) -> String {
+        info!(
+            "Authorization request of {} refused: {}",
+            self.client.client_id, description
+        );
+        self.redirect(&[("error", error), ("error_description", description)])
+    }
+}
+
+/// Check the client and redirect URI of an authorization request. These
+/// errors are shown to the user and never redirected, since an unchecked
+/// redirect URI could send the code anywhere
+pub fn check_authorize_request(
+    conn: &mut db::DbConnection,
+    query: &models::AuthorizeQuery,
+) -> Result<AuthorizeRequest, Rejection> {
+    let client_id = query
+        .client_id
+        .as_deref()
+        .ok_or_else(|| oauth_error("invalid_request", "client_id is required"))?;
+    let client = db::OAuthClientRepository::find(conn, client_id)
+        .map_err(|e| internal_error("Failed to look up OAuth client", e))?
+        .ok_or_else(|| oauth_error("invalid_request", "unknown client"))?;
+
+    // May be left out only when the client registered a single one
+    let redirect_uri = match (&query.redirect_uri, client.redirect_uris.as_slice()) {
+        (Some(uri), registered) if registered.contains(uri) => uri.clone(),
+        (None, [only]) => only.clone(),
+        _ => {
+            return Err(oauth_error(
+                "invalid_request",
+                "redirect_uri is not registered for this client",
+            ))
+        }
+    };
+
+    Ok(AuthorizeRequest {
+        client,
+        redirect_uri,
+        state: query.state.clone(),
+    })
+}
+
+// The requested scopes the user actually holds, with the roles they were
+// looked up from
+fn user_scopes(
+    conn: &mut db::DbConnection,
+    user: &models::User,
+    requested: Vec<String>,
+) -> Result<(Vec<String>, Vec<String>), Rejection> {
+    let effective = roles::effective_roles_for(conn, user)?;
+    let held = db::RoleRepository::permissions_for_roles(conn, &effective)
+        .map_err(|e| internal_error("Failed to load permissions", e))?;
+    let scopes = requested
+        .into_iter()
+        .filter(|scope| held.contains(scope))
+        .collect();
+    Ok((effective, scopes))
+}
+
+/// Issue an authorization code to a logged-in user, yielding where to send
+/// the browser: back to the client with either the code or an error.
+/// Clients are registered by admins for internal apps, so there is no
+/// consent step
+pub fn authorize(
+    conn: &mut db::DbConnection,
+    request: &AuthorizeRequest,
+    query: &models::AuthorizeQuery,
+    user: &models::User,
+) -> Result<String, Rejection> {
+    let client = &request.client;
+
+    if query.response_type.as_deref() != Some("code") {
+        return Ok(request.error(
+            "unsupported_response_type",
+            "only the code response type is supported",
+        ));
+    }
+    if !client.grant_types.iter().any(|g| g == AUTHORIZATION_CODE) {
+        return Ok(request.error(
+            "unauthorized_client",
+            "client may not use the authorization_code grant",
+        ));
+    }
+
+    // RFC 7636; "plain" would offer no protection if the request leaked
+    let code_challenge = match (
+        &query.code_challenge,
+        query.code_challenge_method.as_deref(),
+    ) {
+        (Some(challenge), Some("S256")) => {
+            if !(43..=128).contains(&challenge.len()) {
+                return Ok(request.error("invalid_request", "malformed code_challenge"));
+            }
+            Some(challenge.clone())
+        }
+        (Some(_), _) => {
+            return Ok(request.error("invalid_request", "code_challenge_method must be S256"))
+        }
+        (None, _) if client.secret_hash.is_none() => {
+            return Ok(request.error("invalid_request", "public clients must use PKCE"))
+        }
+        (None, _) => None,
+    };
+
+    let requested = parse_scope(query.scope.as_deref()).unwrap_or_else(|| client.scopes.clone());
+    if requested.iter().any(|scope| !client.scopes.contains(scope)) {
+        return Ok(request.error(
+            "invalid_scope",
+            "scope exceeds what the client is registered for",
+        ));
+    }
+    let (_, scopes) = user_scopes(conn, user, requested)?;
+    if scopes.is_empty() {
+        return Ok(request.error(
+            "access_denied",
+            "user holds none of the requested permissions",
+        ));
+    }
+
+    let code = security::generate_token();
+    let new_code = models::NewOAuthAuthorizationCode {
+        oauth_client_id: client.id,
+        user_id: user.id,
+        code_hash: security::hash_token(&code),
+        redirect_uri: request.redirect_uri.clone(),
+        scopes,
+        code_challenge,
+        expires_at: Utc::now().naive_utc() + Duration::seconds(config().code_ttl_secs),
+    };
+    db::OAuthTokenRepository::create_code(conn, &new_code)
+        .map_err(|e| internal_error("Failed to store authorization code", e))?;
+    info!(
+        "Authorization code issued to {} for {}",
+        client.client_id, user.username
+    );
+
+    Ok(request.redirect(&[("code", &code)]))
+}
+
+fn new_refresh_token(
+    conn: &mut diesel::PgConnection,
+    client: &models::OAuthClient,
+    user_id: i32,
+    scopes: Vec<String>,
+) -> Result<String, diesel::result::Error> {
+    let token = security::generate_token();
+    db::OAuthTokenRepository::create_refresh_token(
+        conn,
+        &models::NewOAuthRefreshToken {
+            oauth_client_id: client.id,
+            user_id,
+            token_hash: security::hash_token(&token),
+            scopes,
+            expires_at: Utc::now().naive_utc() + Duration::seconds(config().refresh_token_ttl_secs),
+        },
+    )?;
+    Ok(token)
+}
+
+fn token_response(
+    access_token: String,
+    refresh_token: Option<String>,
+    scopes: &[String],
+) -> models::OAuthTokenResponse {
+    models::OAuthTokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: security::jwt_config().access_token_ttl_secs,
+        refresh_token,
+        scope: scopes.join(" "),
+    }
+}
+
+/// Handle a request to the token endpoint for any supported grant
+pub fn exchange(
+    conn: &mut db::DbConnection,
+    authorization: Option<&str>,
+    request: models::OAuthTokenRequest,
+) -> Result<models::OAuthTokenResponse, Rejection> {
+    let client = authenticate_client(
+        conn,
+        authorization,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+    )?;
+
+    let grant_type = match request.grant_type.as_deref() {
+        Some(grant @ (AUTHORIZATION_CODE | REFRESH_TOKEN | CLIENT_CREDENTIALS)) => grant,
+        Some(_) => {
+            return Err(oauth_error(
+                "unsupported_grant_type",
+                "grant type is not supported",
+            ))
+        }
+        None => return Err(oauth_error("invalid_request", "grant_type is required")),
+    };
+    if !client.grant_types.iter().any(|g| g == grant_type) {
+        return Err(oauth_error(
+            "unauthorized_client",
+            &format!("client may not use the {} grant", grant_type),
+        ));
+    }
+
+    match grant_type {
+        AUTHORIZATION_CODE => redeem_code(conn, &client, request),
+        REFRESH_TOKEN => refresh(conn, &client, request),
+        _ => client_credentials(&client, request),
+    }
+}
+
+enum RedeemOutcome {
+    Granted(models::OAuthAuthorizationCode),
+    Replayed(models::OAuthAuthorizationCode),
+    Invalid,
+}
+
+// Codes are spent before the PKCE verifier is checked, so a stolen code
+// cannot be tried against more than one guess
+fn redeem_code(
+    conn: &mut db::DbConnection,
+    client: &models::OAuthClient,
+    request: models::OAuthTokenRequest,
+) -> Result<models::OAuthTokenResponse, Rejection> {
+    let invalid_grant = || oauth_error("invalid_grant", "invalid authorization code");
+    let code = request
+        .code
+        .ok_or_else(|| oauth_error("invalid_request", "code is required"))?;
+    let now = Utc::now().naive_utc();
+    let hash = security::hash_token(&code);
+
+    let redeemed = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            let stored = match db::OAuthTokenRepository::lock_code(conn, &hash)? {
+                Some(stored) if stored.oauth_client_id == client.id => stored,
+                _ => return Ok(RedeemOutcome::Invalid),
+            };
+            // A code used twice has leaked; whatever it bought is withdrawn
+            if stored.used_at.is_some() {
+                db::OAuthTokenRepository::revoke_grant(conn, client.id, stored.user_id, now)?;
+                return Ok(RedeemOutcome::Replayed(stored));
+            }
+            if stored.expires_at <= now {
+                return Ok(RedeemOutcome::Invalid);
+            }
+            db::OAuthTokenRepository::mark_code_used(conn, stored.id, now)?;
+            Ok(RedeemOutcome::Granted(stored))
+        })
+        .map_err(|e| internal_error("Failed to redeem authorization code", e))?;
+
+    let stored = match redeemed {
+        RedeemOutcome::Granted(stored) => stored,
+        RedeemOutcome::Replayed(stored) => {
+            warn!(
+                target: "security",
+                "Authorization code reuse by OAuth client {} for user id {}, revoked its tokens",
+                client.client_id,
+                stored.user_id
+            );
+            return Err(invalid_grant());
+        }
+        RedeemOutcome::Invalid => return Err(invalid_grant()),
+    };
+
+    if request.redirect_uri.as_deref() != Some(stored.redirect_uri.as_str()) {
+        return Err(oauth_error("invalid_grant", "redirect_uri does not match"));
+    }
+    let verified = match (&stored.code_challenge, &request.code_verifier) {
+        (Some(challenge), Some(verifier)) => pkce_challenge(verifier) == *challenge,
+        (None, None) => true,
+        _ => false,
+    };
+    if !verified {
+        warn!(
+            target: "security",
+            "PKCE verification failed for OAuth client {}",
+            client.client_id
+        );
+        return Err(oauth_error("invalid_grant", "PKCE verification failed"));
+    }
+
+    let user = db::UserRepository::find_by_id(conn, stored.user_id).ok_or_else(invalid_grant)?;
+    let roles = roles::effective_roles_for(conn, &user)?;
+    let refresh_token = if client.grant_types.iter().any(|g| g == REFRESH_TOKEN) {
+        Some(
+            new_refresh_token(conn, client, user.id, stored.scopes.clone())
+                .map_err(|e| internal_error("Failed to store refresh token", e))?,
+        )
+    } else {
+        None
+    };
+
+    let access_token =
+        security::get_jwt_for_client(&client.client_id, Some((&user, roles)), &stored.scopes)?;
+    info!(
+        "Issued tokens to OAuth client {} for {}",
+        client.client_id, user.username
+    );
+
+    Ok(token_response(access_token, refresh_token, &stored.scopes))
+}
+
+enum RotateOutcome {
+    Rotated(models::OAuthRefreshToken, Vec<String>, String),
+    Reused(models::OAuthRefreshToken),
+    InvalidScope,
+    Invalid,
+}
+
+// Refresh tokens are rotated on every use. Presenting one that was already
+// rotated means it leaked, so everything the user granted the client is
+// withdrawn; one that was merely revoked is just refused
+fn refresh(
+    conn: &mut db::DbConnection,
+    client: &models::OAuthClient,
+    request: models::OAuthTokenRequest,
+) -> Result<models::OAuthTokenResponse, Rejection> {
+    let invalid_grant = || oauth_error("invalid_grant", "invalid refresh token");
+    let presented = request
+        .refresh_token
+        .ok_or_else(|| oauth_error("invalid_request", "refresh_token is required"))?;
+    let requested = parse_scope(request.scope.as_deref());
+    let now = Utc::now().naive_utc();
+    let hash = security::hash_token(&presented);
+
+    let rotated = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            let stored = match db::OAuthTokenRepository::lock_refresh_token(conn, &hash)? {
+                Some(stored) if stored.oauth_client_id == client.id => stored,
+                _ => return Ok(RotateOutcome::Invalid),
+            };
+            if stored.rotated_at.is_some() {
+                db::OAuthTokenRepository::revoke_grant(conn, client.id, stored.user_id, now)?;
+                return Ok(RotateOutcome::Reused(stored));
+            }
+            if stored.revoked_at.is_some() || stored.expires_at <= now {
+                return Ok(RotateOutcome::Invalid);
+            }
+
+            // A client may narrow its scopes on refresh, never widen them
+            let scopes = match requested {
+                Some(requested) if requested.iter().all(|s| stored.scopes.contains(s)) => requested,
+                Some(_) => return Ok(RotateOutcome::InvalidScope),
+                None => stored.scopes.clone(),
+            };
+
+            db::OAuthTokenRepository::rotate_refresh_token(conn, stored.id, now)?;
+            let token = new_refresh_token(conn, client, stored.user_id, scopes.clone())?;
+            Ok(RotateOutcome::Rotated(stored, scopes, token))
+        })
+        .map_err(|e| internal_error("Failed to rotate refresh token", e))?;
+
+    let (stored, scopes, refresh_token) = match rotated {
+        RotateOutcome::Rotated(stored, scopes, token) => (stored, scopes, token),
+        RotateOutcome::Reused(stored) => {
+            warn!(
+                target: "security",
+                "Refresh token reuse by OAuth client {} for user id {}, revoked its tokens",
+                client.client_id,
+                stored.user_id
+            );
+            return Err(invalid_grant());
+        }
+        RotateOutcome::InvalidScope => {
+            return Err(oauth_error(
+                "invalid_scope",
+                "scope exceeds the original grant",
+            ))
+        }
+        RotateOutcome::Invalid => return Err(invalid_grant()),
+    };
+
+    let user = db::UserRepository::find_by_id(conn, stored.user_id).ok_or_else(invalid_grant)?;
+    // Permissions the user lost since the grant are dropped from new tokens
+    let (roles, scopes) = user_scopes(conn, &user, scopes)?;
+    let access_token =
+        security::get_jwt_for_client(&client.client_id, Some((&user, roles)), &scopes)?;
+    info!(
+        "Refreshed tokens of OAuth client {} for {}",
+        client.client_id, user.username
+    );
+
+    Ok(token_response(access_token, Some(refresh_token), &scopes))
+}
+
+// The client acts for itself with the scopes it was registered with
+fn client_credentials(
+    client: &models::OAuthClient,
+    request: models::OAuthTokenRequest,
+) -> Result<models::OAuthTokenResponse, Rejection> {
+    if client.secret_hash.is_none() {
+        return Err(oauth_error(
+            "unauthorized_client",
+            "public clients cannot use the client_credentials grant",
+        ));
+    }
+
+    let scopes = parse_scope(request.scope.as_deref()).unwrap_or_else(|| client.scopes.clone());
+    if scopes.iter().any(|scope| !client.scopes.contains(scope)) {
+        return Err(oauth_error(
+            "invalid_scope",
+            "scope exceeds what the client is registered for",
+        ));
+    }
+
+    let access_token = security::get_jwt_for_client(&client.client_id, None, &scopes)?;
+    info!("Issued client credentials token to {}", client.client_id);
+
+    Ok(token_response(access_token, None, &scopes))
+}
+
+/// Describe a token to a confidential client (RFC 7662). Access tokens are
+/// described to any such client, since resource servers check tokens issued
+/// to others; refresh tokens only to the client holding them
+pub fn introspect(
+    conn: &mut db::DbConnection,
+    authorization: Option<&str>,
+    request: models::OAuthTokenHint,
+) -> Result<models::IntrospectionResponse, Rejection> {
+    let client = authenticate_client(
+        conn,
+        authorization,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+    )?;
+    if client.secret_hash.is_none() {
+        return Err(oauth_error(
+            "unauthorized_client",
+            "public clients cannot introspect tokens",
+        ));
+    }
+
+    let inactive = models::IntrospectionResponse::default;
+
+    // Refresh tokens are opaque, so anything that decodes is an access token
+    if let Ok(claims) = security::decode_jwt(&request.token) {
+        if tokens::is_revoked(&claims) {
+            return Ok(inactive());
+        }
+        let username = match &claims.client_id {
+            Some(client_id) if *client_id == claims.sub => None,
+            _ => Some(claims.sub.clone()),
+        };
+        return Ok(models::IntrospectionResponse {
+            active: true,
+            scope: claims.scope,
+            client_id: claims.client_id,
+            username,
+            token_type: Some("Bearer".to_string()),
+            exp: Some(claims.exp as i64),
+            iat: Some(claims.iat as i64),
+            sub: Some(claims.sub),
+            aud: Some(claims.aud),
+            iss: Some(claims.iss),
+            jti: Some(claims.jti),
+        });
+    }
+
+    let now = Utc::now().naive_utc();
+    let stored = match db::OAuthTokenRepository::find_refresh_token(
+        conn,
+        &security::hash_token(&request.token),
+    )
+    .map_err(|e| internal_error("Failed to look up refresh token", e))?
+    {
+        Some(stored)
+            if stored.oauth_client_id == client.id
+                && stored.revoked_at.is_none()
+                && stored.expires_at > now =>
+        {
+            stored
+        }
+        _ => return Ok(inactive()),
+    };
+    let user = match db::UserRepository::find_by_id(conn, stored.user_id) {
+        Some(user) => user,
+        None => return Ok(inactive()),
+    };
+
+    Ok(models::IntrospectionResponse {
+        active: true,
+        scope: Some(stored.scopes.join(" ")),
+        client_id: Some(client.client_id),
+        username: Some(user.username.clone()),
+        exp: Some(stored.expires_at.and_utc().timestamp()),
+        iat: Some(stored.created_at.and_utc().timestamp()),
+        sub: Some(user.username),
+        ..inactive()
+    })
+}
+
+/// A token revoked through `revoke`, for the audit log
+pub struct RevokedToken {
+    pub client_id: String,
+    // "access token" or "refresh token"
+    pub kind: &'static str,
+    // Username the token acted for, or the client id for client credentials
+    pub owner: String,
+}
+
+/// Revoke a token the calling client holds (RFC 7009). Unknown tokens and
+/// tokens of other clients are ignored, so the answer is the same either way
+pub fn revoke(
+    conn: &mut db::DbConnection,
+    authorization: Option<&str>,
+    request: models::OAuthTokenHint,
+) -> Result<Option<RevokedToken>, Rejection> {
+    let client = authenticate_client(
+        conn,
+        authorization,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+    )?;
+
+    if let Ok(claims) = security::decode_jwt(&request.token) {
+        if claims.client_id.as_deref() != Some(client.client_id.as_str()) {
+            return Ok(None);
+        }
+        tokens::revoke_access_token(&claims);
+        info!(
+            "OAuth client {} revoked an access token of {}",
+            client.client_id, claims.sub
+        );
+        return Ok(Some(RevokedToken {
+            client_id: client.client_id,
+            kind: "access token",
+            owner: claims.sub,
+        }));
+    }
+
+    let now = Utc::now().naive_utc();
+    let stored = match db::OAuthTokenRepository::find_refresh_token(
+        conn,
+        &security::hash_token(&request.token),
+    )
+    .map_err(|e| internal_error("Failed to look up refresh token", e))?
+    {
+        Some(stored) if stored.oauth_client_id == client.id => stored,
+        _ => return Ok(None),
+    };
+
+    db::OAuthTokenRepository::revoke_refresh_token(conn, stored.id, now)
+        .map_err(|e| internal_error("Failed to revoke refresh token", e))?;
+    info!(
+        "OAuth client {} revoked a refresh token of user id {}",
+        client.client_id, stored.user_id
+    );
+    let owner = db::UserRepository::find_by_id(conn, stored.user_id)
+        .map(|user| user.username)
+        .unwrap_or_else(|| format!("user id {}", stored.user_id));
+
+    Ok(Some(RevokedToken {
+        client_id: client.client_id,
+        kind: "refresh token",
+        owner,
+    }))
+}
@@ -0,0 +1,1155 @@
+use crate::{db, errors, hashing, models, roles, security, state_store::StateStore};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use diesel::Connection;
+use hyper::{body, client::HttpConnector, Body, Client, Method, Request, StatusCode};
+use hyper_tls::HttpsConnector;
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use log::{error, info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, env, sync::Arc, time::Duration as StdDuration};
+use tokio::sync::RwLock;
+use url::{form_urlencoded, Url};
+use warp::{reject, Rejection};
+
+// Limits how long a login waits on a slow identity provider
+const HTTP_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+/// Settings from the `[oidc]` section of the server config
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    pub enabled: bool,
+    // Discovery happens at <issuer>/.well-known/openid-configuration
+    pub issuer: String,
+    pub client_id: String,
+    // From the OIDC_CLIENT_SECRET env var; public clients rely on PKCE alone
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    // Claim holding the user's groups at the provider
+    pub groups_claim: String,
+    pub group_roles: HashMap<String, Vec<String>>,
+    // Granted when none of the user's groups maps to a role
+    pub default_roles: Vec<String>,
+    // Create a local user on the first login of an unknown identity
+    pub auto_provision: bool,
+    // Link an unknown identity to the local user with the same verified email
+    pub link_by_email: bool,
+    // Replace the user's roles with the mapped ones on every login
+    pub sync_roles: bool,
+    // Time allowed between leaving for the provider and coming back
+    pub login_ttl_secs: i64,
+    pub post_login_redirect: String,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            enabled: false,
+            issuer: String::new(),
+            client_id: String::new(),
+            client_secret: None,
+            redirect_uri: "http://127.0.0.1:8447/auth/oidc/callback".to_string(),
+            scopes: vec![
+                "openid".to_string(),
+                "email".to_string(),
+                "profile".to_string(),
+            ],
+            groups_claim: "groups".to_string(),
+            group_roles: HashMap::new(),
+            default_roles: vec![security::USER_ROLE.to_string()],
+            auto_provision: true,
+            link_by_email: false,
+            sync_roles: true,
+            login_ttl_secs: 600,
+            post_login_redirect: "/private".to_string(),
+        }
+    }
+}
+
+fn string_list(value: &toml::Value) -> Option<Vec<String>> {
+    value.as_array().map(|values| {
+        values
+            .iter()
+            .filter_map(|v| v.as_str().map(|v| v.to_string()))
+            .collect()
+    })
+}
+
+impl OidcConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = OidcConfig::default();
+        let section = match config.get("oidc") {
+            Some(section) => section,
+            None => return defaults,
+        };
+        let string = |name: &str, default: String| {
+            section
+                .get(name)
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string())
+                .unwrap_or(default)
+        };
+        let flag = |name: &str, default: bool| {
+            section
+                .get(name)
+                .and_then(|v| v.as_bool())
+                .unwrap_or(default)
+        };
+
+        let group_roles = section
+            .get("group_roles")
+            .and_then(|v| v.as_table())
+            .map(|table| {
+                table
+                    .iter()
+                    .filter_map(|(group, roles)| {
+                        let roles = string_list(roles);
+                        if roles.is_none() {
+                            warn!("oidc.group_roles.{} must be a list of role names", group);
+                        }
+                        roles.map(|roles| (group.clone(), roles))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        OidcConfig {
+            enabled: flag("enabled", defaults.enabled),
+            issuer: string("issuer", defaults.issuer)
+                .trim_end_matches('/')
+                .to_string(),
+            client_id: string("client_id", defaults.client_id),
+            client_secret: env::var("OIDC_CLIENT_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+            redirect_uri: string("redirect_uri", defaults.redirect_uri),
+            scopes: section
+                .get("scopes")
+                .and_then(string_list)
+                .unwrap_or(defaults.scopes),
+            groups_claim: string("groups_claim", defaults.groups_claim),
+            group_roles,
+            default_roles: section
+                .get("default_roles")
+                .and_then(string_list)
+                .unwrap_or(defaults.default_roles),
+            auto_provision: flag("auto_provision", defaults.auto_provision),
+            link_by_email: flag("link_by_email", defaults.link_by_email),
+            sync_roles: flag("sync_roles", defaults.sync_roles),
+            login_ttl_secs: section
+                .get("login_ttl_secs")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.clamp(60, 3600))
+                .unwrap_or(defaults.login_ttl_secs),
+            post_login_redirect: string("post_login_redirect", defaults.post_login_redirect),
+        }
+    }
+}
+
+// The parts of the provider's discovery document we use
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug)]
+struct Discovered {
+    metadata: ProviderMetadata,
+    jwks: JwkSet,
+}
+
+// Kept in the state store from the redirect to the provider until the callback
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingLogin {
+    nonce: String,
+    code_verifier: String,
+    // Hash of the cookie set in the browser that started the login
+    browser_binding: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The claims of a verified ID token that drive provisioning
+#[derive(Debug, Deserialize)]
+pub struct IdentityClaims {
+    pub iss: String,
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub preferred_username: Option<String>,
+    // Everything else, including the configured groups claim
+    #[serde(flatten)]
+    pub other: HashMap<String, serde_json::Value>,
+}
+
+impl IdentityClaims {
+    fn groups(&self, claim: &str) -> Vec<String> {
+        match self.other.get(claim) {
+            Some(serde_json::Value::Array(groups)) => groups
+                .iter()
+                .filter_map(|g| g.as_str().map(|g| g.to_string()))
+                .collect(),
+            Some(serde_json::Value::String(group)) => vec![group.clone()],
+            _ => Vec::new(),
+        }
+    }
+
+    fn verified_email(&self) -> Option<&str> {
+        match self.email_verified {
+            Some(true) => self.email.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Where to send the browser to log in at the provider, and the cookie value
+/// binding the login to that browser
+pub struct LoginRedirect {
+    pub url: String,
+    pub browser_binding: String,
+}
+
+fn internal_error(context: &str, e: impl std::fmt::Display) -> Rejection {
+    error!("{}: {}", context, e);
+    reject::custom(errors::CustomError::InternalError)
+}
+
+fn login_failed(reason: &str) -> Rejection {
+    warn!(target: "security", "OIDC login failed: {}", reason);
+    reject::custom(errors::CustomError::ExternalLoginError(reason.to_string()))
+}
+
+fn pending_key(state: &str) -> String {
+    format!("oidc:{}", security::hash_token(state))
+}
+
+// RFC 7636 S256 challenge for a code verifier
+fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// Client for the configured OpenID Connect provider. Discovery and keys
+/// are fetched on first use and the keys again when the provider rotates them
+pub struct OidcProvider {
+    config: OidcConfig,
+    http: Client<HttpsConnector<HttpConnector>>,
+    store: Arc<dyn StateStore>,
+    discovered: RwLock<Option<Arc<Discovered>>>,
+}
+
+impl OidcProvider {
+    pub fn new(config: OidcConfig, store: Arc<dyn StateStore>) -> Self {
+        if config.enabled {
+            info!("OIDC login enabled for issuer {}", config.issuer);
+        }
+        OidcProvider {
+            config,
+            http: Client::builder().build(HttpsConnector::new()),
+            store,
+            discovered: RwLock::new(None),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn config(&self) -> &OidcConfig {
+        &self.config
+    }
+
+    async fn send(&self, request: Request<Body>) -> Result<body::Bytes, String> {
+        let uri = request.uri().to_string();
+        let response = tokio::time::timeout(HTTP_TIMEOUT, self.http.request(request))
+            .await
+            .map_err(|_| format!("{} timed out", uri))?
+            .map_err(|e| format!("{}: {}", uri, e))?;
+
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| format!("{}: {}", uri, e))?;
+        if status != StatusCode::OK {
+            return Err(format!(
+                "{} answered {}: {}",
+                uri,
+                status,
+                String::from_utf8_lossy(&bytes)
+            ));
+        }
+        Ok(bytes)
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, url: &str) -> Result<T, String> {
+        let request = Request::get(url)
+            .header("accept", "application/json")
+            .body(Body::empty())
+            .map_err(|e| e.to_string())?;
+        let bytes = self.send(request).await?;
+        serde_json::from_slice(&bytes).map_err(|e| format!("{}: {}", url, e))
+    }
+
+    async fn discover(&self) -> Result<Discovered, String> {
+        let url = format!("{}/.well-known/openid-configuration", self.config.issuer);
+        let metadata = self.get_json::<ProviderMetadata>(&url).await?;
+
+        // OpenID Connect Discovery 1.0, section 4.3
+        if metadata.issuer.trim_end_matches('/') != self.config.issuer {
+            return Err(format!(
+                "discovery document names issuer {}, expected {}",
+                metadata.issuer, self.config.issuer
+            ));
+        }
+
+        let jwks = self.get_json::<JwkSet>(&metadata.jwks_uri).await?;
+        Ok(Discovered { metadata, jwks })
+    }
+
+    async fn discovered(&self, refresh: bool) -> Result<Arc<Discovered>, Rejection> {
+        if !refresh {
+            if let Some(discovered) = self.discovered.read().await.as_ref() {
+                return Ok(discovered.clone());
+            }
+        }
+
+        let discovered = Arc::new(
+            self.discover()
+                .await
+                .map_err(|e| internal_error("OIDC discovery failed", e))?,
+        );
+        *self.discovered.write().await = Some(discovered.clone());
+        Ok(discovered)
+    }
+
+    /// Start a login: remember state, nonce and PKCE verifier, and build the
+    /// provider's authorization URL
+    pub async fn begin_login(&self) -> Result<LoginRedirect, Rejection> {
+        let discovered = self.discovered(false).await?;
+
+        let state = security::generate_token();
+        let browser_binding = security::generate_token();
+        let pending = PendingLogin {
+            nonce: security::generate_token(),
+            code_verifier: security::generate_token(),
+            browser_binding: security::hash_token(&browser_binding),
+        };
+
+        self.store
+            .set(
+                &pending_key(&state),
+                &serde_json::to_string(&pending)
+                    .map_err(|e| internal_error("Failed to encode OIDC login", e))?,
+                Some(Duration::seconds(self.config.login_ttl_secs)),
+            )
+            .map_err(|e| internal_error("Failed to store OIDC login", e))?;
+
+        let url = Url::parse_with_params(
+            &discovered.metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", self.config.redirect_uri.as_str()),
+                ("scope", self.config.scopes.join(" ").as_str()),
+                ("state", state.as_str()),
+                ("nonce", pending.nonce.as_str()),
+                (
+                    "code_challenge",
+                    code_challenge(&pending.code_verifier).as_str(),
+                ),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| internal_error("Invalid OIDC authorization endpoint", e))?;
+
+        Ok(LoginRedirect {
+            url: url.to_string(),
+            browser_binding,
+        })
+    }
+
+    /// Finish a login: check state against the browser that started it,
+    /// redeem the code and verify the returned ID token
+    pub async fn complete_login(
+        &self,
+        code: &str,
+        state: &str,
+        browser_binding: Option<&str>,
+    ) -> Result<IdentityClaims, Rejection> {
+        // Each state is good for a single callback
+        let key = pending_key(state);
+        let pending = self
+            .store
+            .get(&key)
+            .map_err(|e| internal_error("Failed to read OIDC login", e))?
+            .and_then(|pending| serde_json::from_str::<PendingLogin>(&pending).ok())
+            .ok_or_else(|| login_failed("unknown or expired state"))?;
+        if let Err(e) = self.store.delete(&key) {
+            error!("Failed to delete OIDC login: {}", e);
+        }
+
+        // Stops a callback URL from another browser logging this one in
+        if browser_binding.map(security::hash_token) != Some(pending.browser_binding) {
+            return Err(login_failed("login was started in another browser"));
+        }
+
+        let discovered = self.discovered(false).await?;
+        let form = {
+            let mut form = form_urlencoded::Serializer::new(String::new());
+            form.append_pair("grant_type", "authorization_code")
+                .append_pair("code", code)
+                .append_pair("redirect_uri", &self.config.redirect_uri)
+                .append_pair("client_id", &self.config.client_id)
+                .append_pair("code_verifier", &pending.code_verifier);
+            if let Some(secret) = &self.config.client_secret {
+                form.append_pair("client_secret", secret);
+            }
+            form.finish()
+        };
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&discovered.metadata.token_endpoint)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("accept", "application/json")
+            .body(Body::from(form))
+            .map_err(|e| internal_error("Failed to build OIDC token request", e))?;
+        let bytes = self.send(request).await.map_err(|e| {
+            warn!(target: "security", "OIDC code exchange failed: {}", e);
+            login_failed("authorization code was not accepted")
+        })?;
+        let tokens = serde_json::from_slice::<TokenResponse>(&bytes)
+            .map_err(|e| internal_error("Invalid OIDC token response", e))?;
+
+        let claims = self.verify_id_token(&tokens.id_token).await?;
+        if claims.nonce.as_deref() != Some(pending.nonce.as_str()) {
+            return Err(login_failed("ID token nonce does not match"));
+        }
+        Ok(claims)
+    }
+
+    async fn signing_key(&self, kid: Option<&str>) -> Result<Jwk, Rejection> {
+        let find = |jwks: &JwkSet| match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        };
+
+        if let Some(jwk) = find(&self.discovered(false).await?.jwks) {
+            return Ok(jwk);
+        }
+        // The provider may have rotated its keys since they were fetched
+        find(&self.discovered(true).await?.jwks)
+            .ok_or_else(|| login_failed("ID token signed with an unknown key"))
+    }
+
+    async fn verify_id_token(&self, id_token: &str) -> Result<IdentityClaims, Rejection> {
+        let header = decode_header(id_token).map_err(|_| login_failed("ID token is malformed"))?;
+
+        // Only asymmetric algorithms; the client secret is never a signing key here
+        if !matches!(
+            header.alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+                | Algorithm::ES256
+                | Algorithm::ES384
+                | Algorithm::EdDSA
+        ) {
+            return Err(login_failed("ID token uses an unsupported algorithm"));
+        }
+
+        let jwk = self.signing_key(header.kid.as_deref()).await?;
+        let key = DecodingKey::from_jwk(&jwk)
+            .map_err(|e| internal_error("Unusable key in provider JWKS", e))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = security::jwt_config().leeway_secs;
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["sub", "iss", "aud", "exp"]);
+
+        decode::<IdentityClaims>(id_token, &key, &validation)
+            .map(|token_data| token_data.claims)
+            .map_err(|e| {
+                warn!(target: "security", "Rejected OIDC ID token: {}", e);
+                login_failed("ID token is not valid")
+            })
+    }
+}
+
+// Local names the provider's groups map to, or the defaults
+fn mapped_roles(config: &OidcConfig, claims: &IdentityClaims) -> Vec<String> {
+    let mut mapped = claims
+        .groups(&config.groups_claim)
+        .iter()
+        .filter_map(|group| config.group_roles.get(group))
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>();
+    mapped.sort();
+    mapped.dedup();
+
+    if mapped.is_empty() {
+        config.default_roles.clone()
+    } else {
+        mapped
+    }
+}
+
+// A free local username derived from what the provider tells us
+fn provisioned_username(conn: &mut db::DbConnection, claims: &IdentityClaims) -> String {
+    let base = claims
+        .preferred_username
+        .as_deref()
+        .or_else(|| claims.email.as_deref().and_then(|e| e.split('@').next()))
+        .unwrap_or("user")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        .take(40)
+        .collect::<String>();
+    let base = if base.is_empty() {
+        "user".to_string()
+    } else {
+        base
+    };
+
+    let mut candidate = base.clone();
+    while db::UserRepository::find_by_username(conn, &candidate).is_some() {
+        candidate = format!("{}-{}", base, &security::generate_token()[..6]);
+    }
+    candidate
+}
+
+/// What finding the local user changed, so the caller can audit it
+#[derive(Debug, PartialEq)]
+pub enum AccountChange {
+    /// A new account was created with these roles
+    Provisioned(Vec<String>),
+    /// `sync_roles` replaced the linked account's roles with these
+    RolesSynced(Vec<String>),
+}
+
+/// The local user for a verified identity: the linked one, one whose email
+/// both sides have verified, or a newly provisioned one, with roles from the
+/// mapping
+pub fn local_user(
+    conn: &mut db::DbConnection,
+    config: &OidcConfig,
+    claims: &IdentityClaims,
+) -> Result<(models::User, Option<AccountChange>), Rejection> {
+    let granted = roles::resolve(conn, &mapped_roles(config, claims))?;
+    let mut granted_names = granted
+        .iter()
+        .map(|role| role.name.clone())
+        .collect::<Vec<_>>();
+    granted_names.sort();
+
+    let linked = db::ExternalIdentityRepository::find_user(conn, &claims.iss, &claims.sub)
+        .map_err(|e| internal_error("Failed to look up external identity", e))?;
+    if let Some(user) = linked {
+        if !config.sync_roles {
+            return Ok((user, None));
+        }
+        // Only a real change is worth an audit event, not every login
+        let mut current = db::RoleRepository::roles_for_user(conn, user.id)
+            .map_err(|e| internal_error("Failed to load roles", e))?;
+        current.sort();
+        if current == granted_names {
+            return Ok((user, None));
+        }
+        db::RoleRepository::set_user_roles(conn, user.id, &granted)
+            .map_err(|e| internal_error("Failed to sync roles", e))?;
+        warn!(
+            target: "security",
+            "Synced roles of {} from {}: {}",
+            user.username,
+            claims.iss,
+            granted_names.join(", ")
+        );
+        return Ok((user, Some(AccountChange::RolesSynced(granted_names))));
+    }
+
+    let verified_email = claims
+        .verified_email()
+        .and_then(crate::mailer::normalize_address);
+    let identity = |user_id| models::NewExternalIdentity {
+        user_id,
+        issuer: claims.iss.clone(),
+        subject: claims.sub.clone(),
+    };
+
+    let holder = match &verified_email {
+        Some(email) => db::UserRepository::find_by_email(conn, email)
+            .map_err(|e| internal_error("Failed to look up email address", e))?,
+        None => None,
+    };
+    // Only an address the local user proved they own links the accounts;
+    // otherwise whoever registered it first would get the identity. The
+    // first link leaves the user's roles alone, so signing in through the
+    // provider cannot quietly demote (or promote) an existing account
+    let verified_holder = holder
+        .as_ref()
+        .filter(|user| user.email_verified_at.is_some());
+    if let (true, Some(user)) = (config.link_by_email, verified_holder) {
+        db::ExternalIdentityRepository::link(conn, &identity(user.id))
+            .map_err(|e| internal_error("Failed to link external identity", e))?;
+        warn!(
+            target: "security",
+            "Linked {} identity {} to {} by email",
+            claims.iss, claims.sub, user.username
+        );
+        return Ok((user.clone(), None));
+    }
+
+    if !config.auto_provision {
+        return Err(login_failed("no local account for this identity"));
+    }
+
+    // An address another account already holds stays with that account
+    let verified_email = verified_email.filter(|_| holder.is_none());
+
+    // Password logins stay impossible: nobody knows this password
+    let unusable_password = hashing::hash_password(&security::generate_token())
+        .map_err(|e| internal_error("Failed to hash password", e))?;
+    let new_user = models::NewUser {
+        username: provisioned_username(conn, claims),
+        password: unusable_password,
+        email: verified_email.clone(),
+    };
+    let now = Utc::now().naive_utc();
+
+    let user = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            let user = db::UserRepository::create_user_with_roles(conn, &new_user, &granted)?;
+            db::ExternalIdentityRepository::link(conn, &identity(user.id))?;
+            if let Some(email) = &verified_email {
+                db::UserRepository::mark_email_verified(conn, user.id, email, now)?;
+            }
+            Ok(user)
+        })
+        .map_err(|e| internal_error("Failed to provision user", e))?;
+
+    warn!(
+        target: "security",
+        "Provisioned {} for {} identity {} with roles {}",
+        user.username,
+        claims.iss,
+        claims.sub,
+        granted_names.join(", ")
+    );
+    Ok((user, Some(AccountChange::Provisioned(granted_names))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{state_store::MemoryStateStore, test_support};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+    use warp::Filter;
+
+    const CLIENT_ID: &str = "webserver-test";
+
+    // A provider key: its kid, the key tokens are signed with and its JWK
+    struct TestKey {
+        kid: &'static str,
+        signing: ed25519_dalek::SigningKey,
+    }
+
+    impl TestKey {
+        fn new(kid: &'static str, seed: u8) -> Self {
+            TestKey {
+                kid,
+                signing: ed25519_dalek::SigningKey::from_bytes(&[seed; 32]),
+            }
+        }
+
+        fn jwk(&self) -> Value {
+            json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "alg": "EdDSA",
+                "use": "sig",
+                "kid": self.kid,
+                "x": URL_SAFE_NO_PAD.encode(self.signing.verifying_key().as_bytes()),
+            })
+        }
+
+        fn sign(&self, claims: &Value) -> String {
+            // PKCS#8 v1 as in RFC 8410, the only form ring reads
+            let mut der = vec![
+                0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22,
+                0x04, 0x20,
+            ];
+            der.extend_from_slice(self.signing.as_bytes());
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = Some(self.kid.to_string());
+            encode(&header, claims, &EncodingKey::from_ed_der(&der)).unwrap()
+        }
+    }
+
+    // What the provider's token endpoint expects and answers with
+    #[derive(Default)]
+    struct Exchange {
+        code: String,
+        code_challenge: String,
+        id_token: String,
+    }
+
+    /// An identity provider served on an ephemeral local port, with
+    /// discovery, JWKS and token endpoints
+    struct FakeProvider {
+        issuer: String,
+        jwks: Arc<Mutex<Vec<Value>>>,
+        exchange: Arc<Mutex<Exchange>>,
+    }
+
+    impl FakeProvider {
+        fn start(keys: &[&TestKey]) -> Self {
+            let jwks = Arc::new(Mutex::new(keys.iter().map(|key| key.jwk()).collect()));
+            let exchange = Arc::new(Mutex::new(Exchange::default()));
+            let issuer = Arc::new(Mutex::new(String::new()));
+
+            let discovery_issuer = issuer.clone();
+            let discovery = warp::path!(".well-known" / "openid-configuration").map(move || {
+                let issuer = discovery_issuer.lock().unwrap().clone();
+                warp::reply::json(&json!({
+                    "issuer": issuer,
+                    "authorization_endpoint": format!("{}/authorize", issuer),
+                    "token_endpoint": format!("{}/token", issuer),
+                    "jwks_uri": format!("{}/jwks", issuer),
+                }))
+            });
+            let served_jwks = jwks.clone();
+            let jwks_route = warp::path("jwks")
+                .map(move || warp::reply::json(&json!({ "keys": *served_jwks.lock().unwrap() })));
+            let served_exchange = exchange.clone();
+            let token = warp::path("token")
+                .and(warp::post())
+                .and(warp::body::form())
+                .map(move |form: HashMap<String, String>| {
+                    let exchange = served_exchange.lock().unwrap();
+                    let field = |name: &str| form.get(name).map(String::as_str).unwrap_or("");
+                    let accepted = field("grant_type") == "authorization_code"
+                        && field("client_id") == CLIENT_ID
+                        && field("code") == exchange.code
+                        && code_challenge(field("code_verifier")) == exchange.code_challenge;
+                    if accepted {
+                        warp::reply::with_status(
+                            warp::reply::json(&json!({
+                                "access_token": "unused",
+                                "token_type": "Bearer",
+                                "id_token": exchange.id_token,
+                            })),
+                            warp::http::StatusCode::OK,
+                        )
+                    } else {
+                        warp::reply::with_status(
+                            warp::reply::json(&json!({ "error": "invalid_grant" })),
+                            warp::http::StatusCode::BAD_REQUEST,
+                        )
+                    }
+                });
+
+            let (addr, server) =
+                warp::serve(discovery.or(jwks_route).or(token)).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+            *issuer.lock().unwrap() = format!("http://{}", addr);
+
+            FakeProvider {
+                issuer: format!("http://{}", addr),
+                jwks,
+                exchange,
+            }
+        }
+
+        fn client(&self) -> OidcProvider {
+            OidcProvider::new(
+                OidcConfig {
+                    enabled: true,
+                    issuer: self.issuer.clone(),
+                    client_id: CLIENT_ID.to_string(),
+                    ..OidcConfig::default()
+                },
+                Arc::new(MemoryStateStore::new()),
+            )
+        }
+
+        // Claims of a valid ID token for the login, which tests then spoil
+        fn claims(&self, nonce: &str) -> Value {
+            let now = Utc::now().timestamp();
+            json!({
+                "iss": self.issuer,
+                "sub": "subject-1",
+                "aud": CLIENT_ID,
+                "iat": now,
+                "exp": now + 300,
+                "nonce": nonce,
+                "email": "ada@example.com",
+                "email_verified": true,
+            })
+        }
+    }
+
+    // A login begun at the provider, as the browser would come back with it
+    struct Started {
+        state: String,
+        nonce: String,
+        browser_binding: String,
+    }
+
+    async fn begin(provider: &FakeProvider, client: &OidcProvider) -> Started {
+        let redirect = client.begin_login().await.unwrap();
+        let url = Url::parse(&redirect.url).unwrap();
+        assert!(url
+            .as_str()
+            .starts_with(&format!("{}/authorize", provider.issuer)));
+        let params = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+        assert_eq!(params["client_id"], CLIENT_ID);
+        assert_eq!(params["code_challenge_method"], "S256");
+
+        let mut exchange = provider.exchange.lock().unwrap();
+        exchange.code = "the-code".to_string();
+        exchange.code_challenge = params["code_challenge"].clone();
+        Started {
+            state: params["state"].clone(),
+            nonce: params["nonce"].clone(),
+            browser_binding: redirect.browser_binding,
+        }
+    }
+
+    fn answer_with(provider: &FakeProvider, id_token: String) {
+        provider.exchange.lock().unwrap().id_token = id_token;
+    }
+
+    async fn complete(
+        client: &OidcProvider,
+        started: &Started,
+    ) -> Result<IdentityClaims, Rejection> {
+        client
+            .complete_login("the-code", &started.state, Some(&started.browser_binding))
+            .await
+    }
+
+    #[tokio::test]
+    async fn completes_login_through_discovery_jwks_and_code_exchange() {
+        let key = TestKey::new("key-1", 1);
+        let provider = FakeProvider::start(&[&key]);
+        let client = provider.client();
+
+        let started = begin(&provider, &client).await;
+        answer_with(&provider, key.sign(&provider.claims(&started.nonce)));
+
+        let claims = complete(&client, &started).await.unwrap();
+        assert_eq!(claims.sub, "subject-1");
+        assert_eq!(claims.verified_email(), Some("ada@example.com"));
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_code() {
+        let key = TestKey::new("key-1", 1);
+        let provider = FakeProvider::start(&[&key]);
+        let client = provider.client();
+
+        let started = begin(&provider, &client).await;
+        answer_with(&provider, key.sign(&provider.claims(&started.nonce)));
+
+        let result = client
+            .complete_login(
+                "another-code",
+                &started.state,
+                Some(&started.browser_binding),
+            )
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_reused_or_foreign_state() {
+        let key = TestKey::new("key-1", 1);
+        let provider = FakeProvider::start(&[&key]);
+        let client = provider.client();
+
+        let started = begin(&provider, &client).await;
+        answer_with(&provider, key.sign(&provider.claims(&started.nonce)));
+
+        let unknown = client
+            .complete_login("the-code", "made-up-state", Some(&started.browser_binding))
+            .await;
+        assert!(unknown.is_err());
+
+        let foreign = client
+            .complete_login("the-code", &started.state, Some("another-browser"))
+            .await;
+        assert!(foreign.is_err());
+
+        // The failed attempt above spent the state
+        assert!(complete(&client, &started).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_nonce() {
+        let key = TestKey::new("key-1", 1);
+        let provider = FakeProvider::start(&[&key]);
+        let client = provider.client();
+
+        let started = begin(&provider, &client).await;
+        answer_with(&provider, key.sign(&provider.claims("another-nonce")));
+
+        assert!(complete(&client, &started).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_id_token() {
+        let key = TestKey::new("key-1", 1);
+        let provider = FakeProvider::start(&[&key]);
+        let client = provider.client();
+
+        let started = begin(&provider, &client).await;
+        let mut claims = provider.claims(&started.nonce);
+        let expired = Utc::now().timestamp() - security::jwt_config().leeway_secs as i64 - 60;
+        claims["exp"] = json!(expired);
+        answer_with(&provider, key.sign(&claims));
+
+        assert!(complete(&client, &started).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_id_token_for_another_audience() {
+        let key = TestKey::new("key-1", 1);
+        let provider = FakeProvider::start(&[&key]);
+        let client = provider.client();
+
+        let started = begin(&provider, &client).await;
+        let mut claims = provider.claims(&started.nonce);
+        claims["aud"] = json!("some-other-client");
+        answer_with(&provider, key.sign(&claims));
+
+        assert!(complete(&client, &started).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refetches_jwks_after_rotation_but_rejects_unknown_keys() {
+        let old_key = TestKey::new("key-1", 1);
+        let new_key = TestKey::new("key-2", 2);
+        let stranger = TestKey::new("key-3", 3);
+        let provider = FakeProvider::start(&[&old_key]);
+        let client = provider.client();
+
+        // Discovery caches the JWKS holding only the old key
+        let started = begin(&provider, &client).await;
+        *provider.jwks.lock().unwrap() = vec![new_key.jwk()];
+        answer_with(&provider, new_key.sign(&provider.claims(&started.nonce)));
+        assert!(complete(&client, &started).await.is_ok());
+
+        let started = begin(&provider, &client).await;
+        answer_with(&provider, stranger.sign(&provider.claims(&started.nonce)));
+        assert!(complete(&client, &started).await.is_err());
+    }
+
+    fn identity(subject: &str, email: Option<&str>, email_verified: bool) -> IdentityClaims {
+        IdentityClaims {
+            iss: "https://idp.test".to_string(),
+            sub: subject.to_string(),
+            nonce: None,
+            email: email.map(str::to_string),
+            email_verified: Some(email_verified),
+            preferred_username: Some(subject.to_string()),
+            other: HashMap::new(),
+        }
+    }
+
+    fn local_account(
+        conn: &mut db::DbConnection,
+        email: &str,
+        verified: bool,
+        role: &str,
+    ) -> models::User {
+        let granted = roles::resolve(conn, &[role.to_string()]).unwrap();
+        let user = db::UserRepository::create_user_with_roles(
+            conn,
+            &models::NewUser {
+                username: test_support::unique("local"),
+                password: "unused".to_string(),
+                email: Some(email.to_string()),
+            },
+            &granted,
+        )
+        .unwrap();
+        if verified {
+            db::UserRepository::mark_email_verified(conn, user.id, email, Utc::now().naive_utc())
+                .unwrap();
+        }
+        user
+    }
+
+    fn roles_of(conn: &mut db::DbConnection, user: &models::User) -> Vec<String> {
+        db::RoleRepository::roles_for_user(conn, user.id).unwrap()
+    }
+
+    #[test]
+    fn auto_provisions_unknown_identity_once() {
+        let pool = match test_support::test_pool() {
+            Some(pool) => pool,
+            None => return,
+        };
+        let conn = &mut pool.get().unwrap();
+        let config = OidcConfig::default();
+        let subject = test_support::unique("provisioned");
+        let email = format!("{}@example.com", subject);
+
+        let (user, change) =
+            local_user(conn, &config, &identity(&subject, Some(&email), true)).unwrap();
+        assert_eq!(user.username, subject);
+        assert_eq!(
+            change,
+            Some(AccountChange::Provisioned(vec![
+                security::USER_ROLE.to_string()
+            ]))
+        );
+        assert_eq!(user.email.as_deref(), Some(email.as_str()));
+        assert_eq!(roles_of(conn, &user), vec![security::USER_ROLE.to_string()]);
+
+        // The identity is linked now, so the next login finds the same user
+        let (again, change) =
+            local_user(conn, &config, &identity(&subject, Some(&email), true)).unwrap();
+        assert_eq!(again.id, user.id);
+        assert_eq!(change, None);
+        assert!(again.email_verified_at.is_some());
+    }
+
+    #[test]
+    fn reports_synced_roles_only_when_they_change() {
+        let pool = match test_support::test_pool() {
+            Some(pool) => pool,
+            None => return,
+        };
+        let conn = &mut pool.get().unwrap();
+        let mut config = OidcConfig::default();
+        let subject = test_support::unique("synced");
+        let user = local_user(conn, &config, &identity(&subject, None, false))
+            .unwrap()
+            .0;
+
+        let mut claims = identity(&subject, None, false);
+        claims.other.insert("groups".to_string(), json!(["admins"]));
+        config
+            .group_roles
+            .insert("admins".to_string(), vec!["Admin".to_string()]);
+        let (synced, change) = local_user(conn, &config, &claims).unwrap();
+        assert_eq!(synced.id, user.id);
+        assert_eq!(
+            change,
+            Some(AccountChange::RolesSynced(vec!["Admin".to_string()]))
+        );
+        assert_eq!(roles_of(conn, &user), vec!["Admin".to_string()]);
+
+        assert_eq!(local_user(conn, &config, &claims).unwrap().1, None);
+    }
+
+    #[test]
+    fn refuses_unknown_identity_without_auto_provisioning() {
+        let pool = match test_support::test_pool() {
+            Some(pool) => pool,
+            None => return,
+        };
+        let conn = &mut pool.get().unwrap();
+        let config = OidcConfig {
+            auto_provision: false,
+            ..OidcConfig::default()
+        };
+
+        let subject = test_support::unique("unknown");
+        assert!(local_user(conn, &config, &identity(&subject, None, false)).is_err());
+    }
+
+    #[test]
+    fn links_by_email_verified_on_both_sides_without_touching_roles() {
+        let pool = match test_support::test_pool() {
+            Some(pool) => pool,
+            None => return,
+        };
+        let conn = &mut pool.get().unwrap();
+        let config = OidcConfig {
+            link_by_email: true,
+            ..OidcConfig::default()
+        };
+        let email = format!("{}@example.com", test_support::unique("admin"));
+        let admin = local_account(conn, &email, true, "Admin");
+
+        let subject = test_support::unique("linked");
+        let (user, change) =
+            local_user(conn, &config, &identity(&subject, Some(&email), true)).unwrap();
+        assert_eq!(user.id, admin.id);
+        assert_eq!(change, None);
+        assert_eq!(roles_of(conn, &user), vec!["Admin".to_string()]);
+    }
+
+    #[test]
+    fn does_not_link_by_unverified_email() {
+        let pool = match test_support::test_pool() {
+            Some(pool) => pool,
+            None => return,
+        };
+        let conn = &mut pool.get().unwrap();
+        let config = OidcConfig {
+            link_by_email: true,
+            ..OidcConfig::default()
+        };
+
+        // Someone registered the address first but never verified it
+        let email = format!("{}@example.com", test_support::unique("squatted"));
+        let squatter = local_account(conn, &email, false, security::USER_ROLE);
+        let subject = test_support::unique("owner");
+        let user = local_user(conn, &config, &identity(&subject, Some(&email), true))
+            .unwrap()
+            .0;
+        assert_ne!(user.id, squatter.id);
+        // The address stays with the account holding it
+        assert_eq!(user.email, None);
+
+        // Nor when the provider has not verified it
+        let email = format!("{}@example.com", test_support::unique("unverified"));
+        let holder = local_account(conn, &email, true, security::USER_ROLE);
+        let subject = test_support::unique("claimant");
+        let user = local_user(conn, &config, &identity(&subject, Some(&email), false))
+            .unwrap()
+            .0;
+        assert_ne!(user.id, holder.id);
+    }
+
+    #[test]
+    fn does_not_link_by_email_unless_enabled() {
+        let pool = match test_support::test_pool() {
+            Some(pool) => pool,
+            None => return,
+        };
+        let conn = &mut pool.get().unwrap();
+        let config = OidcConfig::default();
+
+        let email = format!("{}@example.com", test_support::unique("holder"));
+        let holder = local_account(conn, &email, true, security::USER_ROLE);
+        let subject = test_support::unique("other");
+        let user = local_user(conn, &config, &identity(&subject, Some(&email), true))
+            .unwrap()
+            .0;
+        assert_ne!(user.id, holder.id);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::errors;
+use log::{info, warn};
+use serde::Serialize;
+use std::{collections::HashSet, fs, sync::OnceLock};
+use warp::{reject, Rejection};
+
+/// Rules from the `[password_policy]` section of the server config
+#[derive(Clone, Debug)]
+pub struct PasswordPolicyConfig {
+    pub min_length: usize,
+    // Caps the work a single hash can cost
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    pub disallow_username: bool,
+    // One password per line; blank lines and lines starting with # are skipped
+    pub breached_passwords_file: Option<String>,
+}
+
+impl Default for PasswordPolicyConfig {
+    fn default() -> Self {
+        PasswordPolicyConfig {
+            min_length: 12,
+            max_length: 128,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            disallow_username: true,
+            breached_passwords_file: Some("src/config/common_passwords.txt".to_string()),
+        }
+    }
+}
+
+impl PasswordPolicyConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = PasswordPolicyConfig::default();
+        let section = match config.get("password_policy") {
+            Some(section) => section,
+            None => return defaults,
+        };
+        let flag = |name: &str, default: bool| {
+            section
+                .get(name)
+                .and_then(|v| v.as_bool())
+                .unwrap_or(default)
+        };
+
+        let min_length = section
+            .get("min_length")
+            .and_then(|v| v.as_integer())
+            .map(|v| v.clamp(1, 1024) as usize)
+            .unwrap_or(defaults.min_length);
+
+        PasswordPolicyConfig {
+            min_length,
+            max_length: section
+                .get("max_length")
+                .and_then(|v| v.as_integer())
+                .map(|v| (v.clamp(1, 1024) as usize).max(min_length))
+                .unwrap_or(defaults.max_length.max(min_length)),
+            require_lowercase: flag("require_lowercase", defaults.require_lowercase),
+            require_uppercase: flag("require_uppercase", defaults.require_uppercase),
+            require_digit: flag("require_digit", defaults.require_digit),
+            require_symbol: flag("require_symbol", defaults.require_symbol),
+            disallow_username: flag("disallow_username", defaults.disallow_username),
+            // An empty path turns the list off
+            breached_passwords_file: match section.get("breached_passwords_file") {
+                Some(v) => v
+                    .as_str()
+                    .filter(|path| !path.is_empty())
+                    .map(|path| path.to_string()),
+                None => defaults.breached_passwords_file,
+            },
+        }
+    }
+}
+
+/// A rule the password broke, reported back to the client
+#[derive(Clone, Debug, Serialize)]
+pub struct PolicyViolation {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+pub struct PasswordPolicy {
+    config: PasswordPolicyConfig,
+    // Lowercased, so the check ignores case
+    breached: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    pub fn new(config: PasswordPolicyConfig) -> Self {
+        let breached = match &config.breached_passwords_file {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(content) => {
+                    let breached = content
+                        .lines()
+                        .map(|line| line.trim())
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(|line| line.to_lowercase())
+                        .collect::<HashSet<_>>();
+                    info!("Loaded {} breached passwords from {}", breached.len(), path);
+                    breached
+                }
+                Err(e) => {
+                    warn!("Failed to read breached password list {}: {}", path, e);
+                    HashSet::new()
+                }
+            },
+            None => HashSet::new(),
+        };
+
+        PasswordPolicy { config, breached }
+    }
+
+    /// Every rule the password breaks; empty when it is acceptable
+    pub fn violations(&self, password: &str, username: &str) -> Vec<PolicyViolation> {
+        let config = &self.config;
+        let mut violations = Vec::new();
+        let mut violated = |rule: &'static str, message: String| {
+            violations.push(PolicyViolation { rule, message })
+        };
+
+        let length = password.chars().count();
+        if length < config.min_length {
+            violated(
+                "min_length",
+                format!("must be at least {} characters long", config.min_length),
+            );
+        }
+        if length > config.max_length {
+            violated(
+                "max_length",
+                format!("must be at most {} characters long", config.max_length),
+            );
+        }
+        if config.require_lowercase && !password.chars().any(|c| c.is_lowercase()) {
+            violated("lowercase", "must contain a lowercase letter".to_string());
+        }
+        if config.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+            violated("uppercase", "must contain an uppercase letter".to_string());
+        }
+        if config.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            violated("digit", "must contain a digit".to_string());
+        }
+        if config.require_symbol && !password.chars().any(|c| !c.is_alphanumeric()) {
+            violated("symbol", "must contain a symbol".to_string());
+        }
+
+        let lowered = password.to_lowercase();
+        if config.disallow_username
+            && !username.is_empty()
+            && lowered.contains(&username.to_lowercase())
+        {
+            violated("username", "must not contain the username".to_string());
+        }
+        if self.breached.contains(&lowered) {
+            violated(
+                "breached",
+                "is too common or has appeared in a data breach".to_string(),
+            );
+        }
+
+        violations
+    }
+}
+
+static POLICY: OnceLock<PasswordPolicy> = OnceLock::new();
+
+/// Install the password policy and load its breached password list
+pub fn init(config: PasswordPolicyConfig) {
+    if POLICY.set(PasswordPolicy::new(config)).is_err() {
+        warn!("Password policy already initialized");
+    }
+}
+
+pub fn policy() -> &'static PasswordPolicy {
+    POLICY.get_or_init(|| PasswordPolicy::new(PasswordPolicyConfig::default()))
+}
+
+/// Reject a new password that breaks any rule, listing all of them
+pub fn check(password: &str, username: &str) -> Result<(), Rejection> {
+    let violations = policy().violations(password, username);
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(reject::custom(errors::CustomError::PasswordPolicyError(
+            violations,
+        )))
+    }
+}
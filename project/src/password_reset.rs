@@ -0,0 +1,164 @@
+use crate::{
+    db, errors, hashing,
+    mailer::{self, Email, Mailer},
+    models, password_policy, security, tokens,
+};
+use chrono::{Duration, Utc};
+use diesel::Connection;
+use log::{error, info, warn};
+use std::sync::{Arc, OnceLock};
+use warp::{reject, Rejection};
+
+/// Settings from the `[password_reset]` section of the server config
+#[derive(Clone, Debug)]
+pub struct PasswordResetConfig {
+    pub token_ttl_secs: i64,
+    // Link sent in the email; `{token}` is replaced with the reset token
+    pub reset_url: String,
+}
+
+impl Default for PasswordResetConfig {
+    fn default() -> Self {
+        PasswordResetConfig {
+            token_ttl_secs: 3600,
+            reset_url: "http://127.0.0.1:8447/password/reset?token={token}".to_string(),
+        }
+    }
+}
+
+impl PasswordResetConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = PasswordResetConfig::default();
+        let section = match config.get("password_reset") {
+            Some(section) => section,
+            None => return defaults,
+        };
+
+        PasswordResetConfig {
+            token_ttl_secs: section
+                .get("token_ttl_secs")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.clamp(60, 86400))
+                .unwrap_or(defaults.token_ttl_secs),
+            reset_url: section
+                .get("reset_url")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string())
+                .unwrap_or(defaults.reset_url),
+        }
+    }
+}
+
+static CONFIG: OnceLock<PasswordResetConfig> = OnceLock::new();
+
+/// Install the password reset settings
+pub fn init_config(config: PasswordResetConfig) {
+    if CONFIG.set(config).is_err() {
+        warn!("Password reset config already initialized");
+    }
+}
+
+pub fn config() -> &'static PasswordResetConfig {
+    CONFIG.get_or_init(PasswordResetConfig::default)
+}
+
+fn internal_error(context: &str, e: impl std::fmt::Display) -> Rejection {
+    error!("{}: {}", context, e);
+    reject::custom(errors::CustomError::InternalError)
+}
+
+/// Mail a reset link to the account registered with the address, if any.
+/// Callers answer the same way whether or not an account was found
+pub fn request_reset(
+    conn: &mut db::DbConnection,
+    mailer: Arc<dyn Mailer>,
+    email: &str,
+) -> Result<(), Rejection> {
+    let email = match mailer::normalize_address(email) {
+        Some(email) => email,
+        None => return Ok(()),
+    };
+
+    let user = match db::UserRepository::find_by_email(conn, &email)
+        .map_err(|e| internal_error("Failed to look up email address", e))?
+    {
+        Some(user) => user,
+        None => {
+            info!("Password reset requested for unknown address");
+            return Ok(());
+        }
+    };
+
+    let token = security::generate_token();
+    let now = Utc::now().naive_utc();
+    let new_token = models::NewPasswordResetToken {
+        user_id: user.id,
+        token_hash: security::hash_token(&token),
+        expires_at: now + Duration::seconds(config().token_ttl_secs),
+    };
+    db::PasswordResetRepository::create(conn, &new_token, now)
+        .map_err(|e| internal_error("Failed to store password reset token", e))?;
+
+    warn!(target: "security", "Password reset requested for {}", user.username);
+
+    mailer::send_in_background(
+        mailer,
+        Email {
+            to: email,
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "A password reset was requested for the account {}.\n\n\
+                 Use this link within {} minutes to choose a new password:\n{}\n\n\
+                 If you did not ask for this, you can ignore this message.",
+                user.username,
+                config().token_ttl_secs / 60,
+                config().reset_url.replace("{token}", &token)
+            ),
+        },
+    );
+
+    Ok(())
+}
+
+/// Spend a reset token on a new password. Every existing session of the
+/// account is revoked, since whoever held them may not be the owner
+pub fn reset_password(
+    conn: &mut db::DbConnection,
+    token: &str,
+    new_password: &str,
+) -> Result<models::User, Rejection> {
+    let now = Utc::now().naive_utc();
+    let hash = security::hash_token(token.trim());
+    let invalid_token = || {
+        reject::custom(errors::CustomError::InvalidRequestError(
+            "invalid or expired reset token".to_string(),
+        ))
+    };
+
+    // Check the new password first, so a rejected one does not spend the token
+    let owner = db::PasswordResetRepository::find_valid(conn, &hash, now)
+        .map_err(|e| internal_error("Failed to look up password reset token", e))?
+        .and_then(|user_id| db::UserRepository::find_by_id(conn, user_id))
+        .ok_or_else(invalid_token)?;
+    password_policy::check(new_password, &owner.username)?;
+    let password_hash = hashing::hash_password(new_password)
+        .map_err(|e| internal_error("Failed to hash password", e))?;
+
+    // Spending the token and setting the password succeed or fail together
+    let user = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            let user_id = match db::PasswordResetRepository::consume(conn, &hash, now)? {
+                Some(user_id) => user_id,
+                None => return Ok(None),
+            };
+            db::UserRepository::change_password(conn, user_id, &password_hash, now)?;
+            Ok(db::UserRepository::find_by_id(conn, user_id))
+        })
+        .map_err(|e| internal_error("Failed to reset password", e))?
+        .ok_or_else(invalid_token)?;
+
+    tokens::revoke_user_sessions(conn, &user)?;
+    warn!(target: "security", "Password reset completed for {}", user.username);
+
+    Ok(user)
+}
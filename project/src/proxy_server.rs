@@ -1,7 +1,11 @@
+use crate::{compression, cors, db, errors, rate_limit, state_store};
+use cookie::{Cookie, SameSite};
 use hyper::{Body, Client, Request, Uri};
-use log::info;
+use log::{error, info};
+use rand::RngCore;
 use std::{
     fs,
+    net::SocketAddr,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -26,9 +30,27 @@ impl RoundRobinBalancer {
         let current = self.next.fetch_add(1, Ordering::SeqCst) % self.servers.len();
         self.servers[current].clone()
     }
+
+    pub fn has_server(&self, server: &str) -> bool {
+        self.servers.iter().any(|s| s == server)
+    }
+}
+
+const STICKY_COOKIE: &str = "proxy_session";
+
+// Shared state handed to every proxied request
+struct ProxyContext {
+    balancer: RoundRobinBalancer,
+    client: hyper::Client<hyper::client::HttpConnector>,
+    compression_config: compression::CompressionConfig,
+    store: Arc<dyn state_store::StateStore>,
+    // Lifetime of a sticky-session assignment, `None` when stickiness is off
+    sticky_ttl: Option<chrono::Duration>,
 }
 
-pub async fn start_proxy_server() {
+// The state store is the one the server built, so the proxy and the backends
+// count rate limits together and only one purge task runs
+pub async fn start_proxy_server(db_pool: db::DbPool, store: Arc<dyn state_store::StateStore>) {
     info!("Starting proxy server...");
 
     // Load the proxy configuration
@@ -64,7 +86,25 @@ pub async fn start_proxy_server() {
         .map(|v| v.as_str().unwrap().to_string())
         .collect::<Vec<_>>();
 
-    let balancer = Arc::new(RoundRobinBalancer::new(upstream_servers.clone()));
+    let balancer = RoundRobinBalancer::new(upstream_servers.clone());
+
+    // Sticky sessions pin a client to one upstream through the shared state store
+    let sticky_ttl = if config["load_balancing"]
+        .get("sticky_sessions")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+    {
+        let ttl_secs = config["load_balancing"]
+            .get("sticky_ttl_secs")
+            .and_then(|v| v.as_integer())
+            .unwrap_or(1800);
+        Some(chrono::Duration::seconds(ttl_secs))
+    } else {
+        None
+    };
+
+    // Compression at the proxy only applies to upstream responses that are not already encoded
+    let compression_config = compression::CompressionConfig::from_toml(&config);
 
     info!(
         "Proxy server configured with {} upstream servers:",
@@ -76,17 +116,35 @@ pub async fn start_proxy_server() {
 
     // Set up the HTTP client for proxying requests
     let client = Client::new();
-    let balancer_filter = warp::any().map(move || balancer.clone());
-    let client_filter = warp::any().map(move || client.clone());
+    let context = Arc::new(ProxyContext {
+        balancer,
+        client,
+        compression_config,
+        store: store.clone(),
+        sticky_ttl,
+    });
+    let context_filter = warp::any().map(move || context.clone());
+
+    let rate_limiter = Arc::new(rate_limit::RateLimiter::new(
+        rate_limit::RateLimitConfig::from_toml(&config),
+        store,
+        db_pool,
+    ));
 
     // Create a route that will match any path and proxy the request
-    let proxy_route = warp::path::full()
+    let proxy_route = rate_limit::with_rate_limit(rate_limiter, "proxy")
+        .and(warp::addr::remote())
+        .and(warp::path::full())
         .and(warp::method())
         .and(warp::header::headers_cloned())
         .and(warp::body::bytes())
-        .and(balancer_filter)
-        .and(client_filter)
-        .and_then(handle_proxy_request);
+        .and(context_filter)
+        .and_then(handle_proxy_request)
+        .recover(errors::handle_rejection);
+
+    // The proxy answers preflights itself and applies the same [cors] policy
+    // as the backends, in place of whatever the upstream sent
+    let proxy_route = cors::with_cors(proxy_route, Arc::new(cors::CorsConfig::from_toml(&config)));
 
     info!("Starting reverse proxy server on port {}", proxy_port);
     warp::serve(proxy_route)
@@ -94,16 +152,77 @@ pub async fn start_proxy_server() {
         .await;
 }
 
+fn sticky_session_id(headers: &http::HeaderMap) -> Option<String> {
+    headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| Cookie::split_parse(v.to_string()).filter_map(|c| c.ok()))
+        .find(|c| c.name() == STICKY_COOKIE)
+        .map(|c| c.value().to_string())
+}
+
+fn sticky_key(session_id: &str) -> String {
+    format!("sticky:{}", session_id)
+}
+
+// Pick the upstream for this request. With sticky sessions enabled the choice
+// is remembered under the client's session cookie; a new session id is
+// returned when one had to be issued
+fn select_upstream(context: &ProxyContext, headers: &http::HeaderMap) -> (String, Option<String>) {
+    let ttl = match context.sticky_ttl {
+        Some(ttl) => ttl,
+        None => return (context.balancer.get_next_server(), None),
+    };
+
+    let session_id = sticky_session_id(headers);
+
+    if let Some(id) = &session_id {
+        match context.store.get(&sticky_key(id)) {
+            Ok(Some(server)) if context.balancer.has_server(&server) => {
+                if let Err(e) = context.store.set(&sticky_key(id), &server, Some(ttl)) {
+                    error!("Failed to refresh sticky session: {}", e);
+                }
+                return (server, None);
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to read sticky session: {}", e),
+        }
+    }
+
+    let server = context.balancer.get_next_server();
+    let (id, issued) = match session_id {
+        Some(id) => (id, None),
+        None => {
+            let mut bytes = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let id = hex::encode(bytes);
+            (id.clone(), Some(id))
+        }
+    };
+
+    if let Err(e) = context.store.set(&sticky_key(&id), &server, Some(ttl)) {
+        error!("Failed to store sticky session: {}", e);
+    }
+
+    (server, issued)
+}
+
 async fn handle_proxy_request(
+    remote: Option<SocketAddr>,
     path: warp::path::FullPath,
     method: http::Method,
     headers: http::HeaderMap,
     body: hyper::body::Bytes,
-    balancer: Arc<RoundRobinBalancer>,
-    client: hyper::Client<hyper::client::HttpConnector>,
+    context: Arc<ProxyContext>,
 ) -> Result<impl Reply, Rejection> {
+    let accept_encoding = headers
+        .get(http::header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+
     // Get the next upstream server using the round-robin balancer
-    let upstream_server = balancer.get_next_server();
+    let (upstream_server, issued_session) = select_upstream(&context, &headers);
 
     info!(
         "Proxying request to {} - {}{}",
@@ -132,40 +251,88 @@ async fn handle_proxy_request(
     req_headers.insert("X-Forwarded-By", "Rust-Proxy/1.0".parse().unwrap());
     req_headers.insert("X-Forwarded-Proto", "http".parse().unwrap());
 
+    // Pass the client address on so upstream rate limits apply per client
+    if let Some(addr) = remote {
+        let forwarded_for = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+            Some(existing) => format!("{}, {}", existing, addr.ip()),
+            None => addr.ip().to_string(),
+        };
+        if let Ok(value) = forwarded_for.parse() {
+            req_headers.insert("X-Forwarded-For", value);
+        }
+    }
+
     // Send the request to the upstream server
     let proxy_req = req_builder
         .body(Body::from(body))
         .map_err(|_| warp::reject::reject())?;
 
-    let res = client
-        .request(proxy_req)
-        .await
-        .map_err(|_| warp::reject::reject())?;
+    let res = match context.client.request(proxy_req).await {
+        Ok(res) => res,
+        Err(e) => {
+            error!("Upstream {} failed: {}", upstream_server, e);
+            // Forget the pinned upstream so the client is rebalanced on its next request
+            if context.sticky_ttl.is_some() {
+                if let Some(id) = sticky_session_id(&headers) {
+                    if let Err(e) = context.store.delete(&sticky_key(&id)) {
+                        error!("Failed to clear sticky session: {}", e);
+                    }
+                }
+            }
+            return Err(warp::reject::reject());
+        }
+    };
 
     // Build and return the response
     let (parts, body) = res.into_parts();
     let mut response_builder = http::Response::builder().status(parts.status);
 
-    // Copy the response headers
+    // Copy the response headers, except CORS ones which the proxy sets itself
     let resp_headers = response_builder.headers_mut().unwrap();
     for (key, value) in parts.headers.iter() {
-        resp_headers.insert(key, value.clone());
+        if key.as_str().starts_with("access-control-") {
+            continue;
+        }
+        resp_headers.append(key, value.clone());
     }
 
     // Add a header to indicate the upstream server used
     resp_headers.insert("X-Upstream-Server", upstream_server.parse().unwrap());
 
+    if let Some(session_id) = issued_session {
+        let mut session_cookie = Cookie::new(STICKY_COOKIE, session_id);
+        session_cookie.set_path("/");
+        session_cookie.set_http_only(true);
+        session_cookie.set_same_site(SameSite::Lax);
+        if let Ok(value) = session_cookie.to_string().parse() {
+            resp_headers.append(http::header::SET_COOKIE, value);
+        }
+    }
+
     let body_bytes = hyper::body::to_bytes(body)
         .await
         .map_err(|_| warp::reject::reject())?;
 
+    let compression_config = &context.compression_config;
+    let body_bytes = if compression_config.proxy {
+        compression::encode_body(
+            parts.status,
+            resp_headers,
+            body_bytes,
+            accept_encoding.as_deref(),
+            compression_config,
+        )
+    } else {
+        body_bytes
+    };
+
     // Create response and apply status
     let mut response = warp::reply::Response::new(body_bytes.into());
     *response.status_mut() = parts.status;
 
     // Add all headers from resp_headers
     for (key, value) in resp_headers.iter() {
-        response.headers_mut().insert(key, value.clone());
+        response.headers_mut().append(key, value.clone());
     }
 
     Ok(response)
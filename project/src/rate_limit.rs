@@ -0,0 +1,368 @@
+use crate::{api_keys, db, errors, state_store::StateStore};
+use log::{debug, error, warn};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use warp::{reject, Filter, Rejection};
+
+// Routes limited through `with_user_rate_limit`, the only ones that know the
+// user. Anywhere else `key = "user"` cannot be honoured
+const USER_KEYED_ROUTES: &[&str] = &["private", "admin_only"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyKind {
+    Ip,
+    User,
+    ApiKey,
+}
+
+impl KeyKind {
+    pub fn from_str(kind: &str) -> KeyKind {
+        match kind.to_lowercase().as_str() {
+            "user" => KeyKind::User,
+            "api_key" => KeyKind::ApiKey,
+            _ => KeyKind::Ip,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RouteLimit {
+    pub requests: u32,
+    pub period_secs: u64,
+    pub key: KeyKind,
+}
+
+impl RouteLimit {
+    fn from_toml(value: &toml::Value, fallback: &RouteLimit) -> Self {
+        RouteLimit {
+            requests: value
+                .get("requests")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.max(1) as u32)
+                .unwrap_or(fallback.requests),
+            period_secs: value
+                .get("period_secs")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.max(1) as u64)
+                .unwrap_or(fallback.period_secs),
+            key: value
+                .get("key")
+                .and_then(|v| v.as_str())
+                .map(KeyKind::from_str)
+                .unwrap_or(fallback.key),
+        }
+    }
+
+    // Tokens added back to the bucket per second
+    fn refill_rate(&self) -> f64 {
+        self.requests as f64 / self.period_secs as f64
+    }
+}
+
+/// Settings from the `[rate_limit]` section of the server config
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub default: RouteLimit,
+    pub routes: HashMap<String, RouteLimit>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: false,
+            trusted_proxies: Vec::new(),
+            default: RouteLimit {
+                requests: 120,
+                period_secs: 60,
+                key: KeyKind::Ip,
+            },
+            routes: HashMap::new(),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = RateLimitConfig::default();
+        let section = match config.get("rate_limit") {
+            Some(section) => section,
+            None => return defaults,
+        };
+
+        let default = section
+            .get("default")
+            .map(|v| RouteLimit::from_toml(v, &defaults.default))
+            .unwrap_or(defaults.default);
+
+        let routes = section
+            .get("routes")
+            .and_then(|v| v.as_table())
+            .map(|table| {
+                table
+                    .iter()
+                    .map(|(route, v)| (route.clone(), RouteLimit::from_toml(v, &default)))
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+
+        if default.key == KeyKind::User {
+            warn!(
+                "rate_limit.default has key = \"user\", which only {} honour; other routes are limited by client IP",
+                USER_KEYED_ROUTES.join(" and ")
+            );
+        }
+        for (route, limit) in &routes {
+            if limit.key == KeyKind::User && !USER_KEYED_ROUTES.contains(&route.as_str()) {
+                warn!(
+                    "rate_limit.routes.{} has key = \"user\", but that route does not know the user; it is limited by client IP",
+                    route
+                );
+            }
+        }
+
+        let trusted_proxies = section
+            .get("trusted_proxies")
+            .and_then(|v| v.as_array())
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .filter_map(|v| v.parse::<IpAddr>().ok())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        RateLimitConfig {
+            enabled: section
+                .get("enabled")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.enabled),
+            trusted_proxies,
+            default,
+            routes,
+        }
+    }
+
+    pub fn limit_for(&self, route: &str) -> &RouteLimit {
+        self.routes.get(route).unwrap_or(&self.default)
+    }
+}
+
+/// Outcome of taking a token from a bucket, used to fill the `RateLimit-*` headers
+#[derive(Clone, Debug)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub period_secs: u64,
+    pub remaining: u32,
+    pub reset_secs: u64,
+    pub retry_after_secs: u64,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    store: Arc<dyn StateStore>,
+    // Looks up API keys for routes keyed by them
+    db_pool: db::DbPool,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, store: Arc<dyn StateStore>, db_pool: db::DbPool) -> Self {
+        RateLimiter {
+            config,
+            store,
+            db_pool,
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Take one token from the bucket identified by `route` and `key`. If the
+    /// state store is unreachable the request is let through
+    pub fn check(&self, route: &str, key: &str) -> RateLimitDecision {
+        let limit = self.config.limit_for(route);
+        let capacity = limit.requests as f64;
+        let rate = limit.refill_rate();
+
+        let tokens =
+            match self
+                .store
+                .take_token(&format!("ratelimit:{}:{}", route, key), capacity, rate)
+            {
+                Ok(bucket) if bucket.allowed => bucket.tokens,
+                Ok(bucket) => {
+                    return RateLimitDecision {
+                        allowed: false,
+                        limit: limit.requests,
+                        period_secs: limit.period_secs,
+                        remaining: 0,
+                        reset_secs: ((capacity - bucket.tokens) / rate).ceil() as u64,
+                        retry_after_secs: ((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64,
+                    };
+                }
+                Err(e) => {
+                    error!("Rate limit store unavailable, allowing request: {}", e);
+                    capacity
+                }
+            };
+
+        RateLimitDecision {
+            allowed: true,
+            limit: limit.requests,
+            period_secs: limit.period_secs,
+            remaining: tokens.floor() as u32,
+            reset_secs: ((capacity - tokens) / rate).ceil() as u64,
+            retry_after_secs: 0,
+        }
+    }
+
+    fn enforce(&self, route: &str, key: &str) -> Result<(), Rejection> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+
+        let decision = self.check(route, key);
+        if decision.allowed {
+            debug!(
+                "Rate limit {} for {}: {} of {} remaining",
+                route, key, decision.remaining, decision.limit
+            );
+            Ok(())
+        } else {
+            warn!(
+                "Rate limit exceeded on route {} for {}, retry after {}s",
+                route, key, decision.retry_after_secs
+            );
+            Err(reject::custom(errors::CustomError::TooManyRequestsError(
+                decision,
+            )))
+        }
+    }
+
+    /// Resolve the client address, honouring `X-Forwarded-For` only when the
+    /// request came through one of the trusted proxies
+    pub fn client_ip(&self, remote: Option<SocketAddr>, forwarded_for: Option<&str>) -> String {
+        let remote_ip = remote.map(|addr| addr.ip());
+
+        if let (Some(ip), Some(forwarded)) = (remote_ip, forwarded_for) {
+            if self.config.trusted_proxies.contains(&ip) {
+                // The proxy appends the peer it saw, so the last entry is the one we can trust
+                if let Some(client) = forwarded
+                    .rsplit(',')
+                    .next()
+                    .and_then(|v| v.trim().parse::<IpAddr>().ok())
+                {
+                    return client.to_string();
+                }
+            }
+        }
+
+        remote_ip
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    // Bucket for a presented API key, if it is a valid one. Made-up keys
+    // must not get buckets of their own, or a new key per request would
+    // never be limited
+    fn api_key_bucket(&self, presented: &str) -> Option<String> {
+        let mut conn = match self.db_pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(
+                    "Failed to get a connection for API key rate limiting: {}",
+                    e
+                );
+                return None;
+            }
+        };
+        api_keys::authenticate(&mut conn, presented)
+            .ok()
+            .map(|(_, api_key)| format!("key:{}", api_key.id))
+    }
+}
+
+fn with_limiter(
+    limiter: Arc<RateLimiter>,
+) -> impl Filter<Extract = (Arc<RateLimiter>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
+
+/// Extract the client address, resolved the same way as for IP rate limits
+pub fn with_client_ip(
+    limiter: Arc<RateLimiter>,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(with_limiter(limiter))
+        .map(
+            |remote: Option<SocketAddr>,
+             forwarded_for: Option<String>,
+             limiter: Arc<RateLimiter>| {
+                limiter.client_ip(remote, forwarded_for.as_deref())
+            },
+        )
+}
+
+/// Rate limit a route keyed by client IP, or by the API key in the `X-API-Key`
+/// header when the route is configured with `key = "api_key"` and the key is
+/// valid. Requests with a missing or invalid key share their IP's bucket
+pub fn with_rate_limit(
+    limiter: Arc<RateLimiter>,
+    route: &'static str,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(with_limiter(limiter))
+        .and_then(
+            move |remote: Option<SocketAddr>,
+                  forwarded_for: Option<String>,
+                  api_key: Option<String>,
+                  limiter: Arc<RateLimiter>| async move {
+                // Spares the key lookup when nothing would be enforced
+                if !limiter.config().enabled {
+                    return Ok(());
+                }
+                let key = match (limiter.config().limit_for(route).key, api_key) {
+                    (KeyKind::ApiKey, Some(api_key)) => limiter.api_key_bucket(&api_key),
+                    _ => None,
+                }
+                .unwrap_or_else(|| {
+                    format!("ip:{}", limiter.client_ip(remote, forwarded_for.as_deref()))
+                });
+                limiter.enforce(route, &key)
+            },
+        )
+        .untuple_one()
+}
+
+/// Rate limit a route keyed by the username extracted by `security::with_permission`,
+/// passing the username through to the handler
+pub fn with_user_rate_limit<F>(
+    auth: F,
+    limiter: Arc<RateLimiter>,
+    route: &'static str,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (String,), Error = Rejection> + Clone,
+{
+    debug_assert!(
+        USER_KEYED_ROUTES.contains(&route),
+        "{} is missing from USER_KEYED_ROUTES",
+        route
+    );
+    auth.and(with_limiter(limiter)).and_then(
+        move |username: String, limiter: Arc<RateLimiter>| async move {
+            limiter.enforce(route, &format!("user:{}", username))?;
+            Ok::<_, Rejection>(username)
+        },
+    )
+}
@@ -0,0 +1,130 @@
+use crate::{db, errors, models};
+use log::{error, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::OnceLock,
+};
+use warp::{reject, Rejection};
+
+/// Role inheritance from the `[roles.inherits]` section of the server config.
+/// Each entry lists the roles whose permissions the key role also receives
+#[derive(Clone, Debug, Default)]
+pub struct RoleHierarchy {
+    inherits: HashMap<String, Vec<String>>,
+}
+
+impl RoleHierarchy {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let table = match config
+            .get("roles")
+            .and_then(|roles| roles.get("inherits"))
+            .and_then(|v| v.as_table())
+        {
+            Some(table) => table,
+            None => return RoleHierarchy::default(),
+        };
+
+        let inherits = table
+            .iter()
+            .map(|(role, parents)| {
+                let parents = parents
+                    .as_array()
+                    .map(|parents| {
+                        parents
+                            .iter()
+                            .filter_map(|p| p.as_str().map(|p| p.to_string()))
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_else(|| {
+                        warn!("roles.inherits.{} must be a list of role names", role);
+                        Vec::new()
+                    });
+                (role.clone(), parents)
+            })
+            .collect();
+
+        RoleHierarchy { inherits }
+    }
+
+    /// The given roles plus every role they inherit from, directly or
+    /// transitively. Cycles in the configuration are tolerated
+    pub fn effective_roles(&self, direct: &[String]) -> Vec<String> {
+        let mut seen = HashSet::new();
+        let mut effective = Vec::new();
+        let mut pending = direct.to_vec();
+
+        while let Some(role) = pending.pop() {
+            if !seen.insert(role.clone()) {
+                continue;
+            }
+            if let Some(parents) = self.inherits.get(&role) {
+                pending.extend(parents.iter().cloned());
+            }
+            effective.push(role);
+        }
+
+        effective.sort();
+        effective
+    }
+}
+
+static HIERARCHY: OnceLock<RoleHierarchy> = OnceLock::new();
+
+/// Install the role hierarchy; without it roles inherit nothing
+pub fn init_hierarchy(hierarchy: RoleHierarchy) {
+    if HIERARCHY.set(hierarchy).is_err() {
+        warn!("Role hierarchy already initialized");
+    }
+}
+
+pub fn hierarchy() -> &'static RoleHierarchy {
+    HIERARCHY.get_or_init(RoleHierarchy::default)
+}
+
+/// Roles to put in a new token for the user: those granted to it in
+/// user_roles, expanded through the hierarchy
+pub fn effective_roles_for(
+    conn: &mut db::DbConnection,
+    user: &models::User,
+) -> Result<Vec<String>, Rejection> {
+    match db::RoleRepository::roles_for_user(conn, user.id) {
+        Ok(direct) => Ok(hierarchy().effective_roles(&direct)),
+        Err(e) => {
+            error!("Failed to load roles for {}: {}", user.username, e);
+            Err(reject::custom(errors::CustomError::InternalError))
+        }
+    }
+}
+
+/// Look up every named role, rejecting the request if any is not defined
+pub fn resolve(
+    conn: &mut db::DbConnection,
+    names: &[String],
+) -> Result<Vec<models::Role>, Rejection> {
+    if names.is_empty() {
+        return Err(reject::custom(errors::CustomError::InvalidRequestError(
+            "at least one role is required".to_string(),
+        )));
+    }
+
+    let known = db::RoleRepository::all(conn).map_err(|e| {
+        error!("Failed to load roles: {}", e);
+        reject::custom(errors::CustomError::InternalError)
+    })?;
+
+    names
+        .iter()
+        .map(|name| {
+            known
+                .iter()
+                .find(|role| role.name.eq_ignore_ascii_case(name))
+                .cloned()
+                .ok_or_else(|| {
+                    reject::custom(errors::CustomError::InvalidRequestError(format!(
+                        "unknown role: {}",
+                        name
+                    )))
+                })
+        })
+        .collect()
+}
@@ -1,5 +1,230 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_keys (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 100]
+        name -> Varchar,
+        #[max_length = 16]
+        key_prefix -> Varchar,
+        #[max_length = 64]
+        key_hash -> Varchar,
+        scopes -> Array<Text>,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    audit_events (id) {
+        id -> Int4,
+        #[max_length = 64]
+        action -> Varchar,
+        #[max_length = 16]
+        outcome -> Varchar,
+        #[max_length = 255]
+        actor -> Nullable<Varchar>,
+        #[max_length = 255]
+        target -> Nullable<Varchar>,
+        details -> Nullable<Text>,
+        #[max_length = 64]
+        ip -> Nullable<Varchar>,
+        #[max_length = 512]
+        user_agent -> Nullable<Varchar>,
+        #[max_length = 64]
+        request_id -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    email_verification_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 255]
+        email -> Varchar,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    external_identities (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 255]
+        issuer -> Varchar,
+        #[max_length = 255]
+        subject -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    login_throttles (subject) {
+        #[max_length = 255]
+        subject -> Varchar,
+        failed_count -> Int4,
+        last_failed_at -> Timestamp,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    oauth_authorization_codes (id) {
+        id -> Int4,
+        oauth_client_id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        code_hash -> Varchar,
+        redirect_uri -> Text,
+        scopes -> Array<Text>,
+        #[max_length = 128]
+        code_challenge -> Nullable<Varchar>,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    oauth_clients (id) {
+        id -> Int4,
+        #[max_length = 64]
+        client_id -> Varchar,
+        #[max_length = 100]
+        name -> Varchar,
+        #[max_length = 64]
+        secret_hash -> Nullable<Varchar>,
+        redirect_uris -> Array<Text>,
+        grant_types -> Array<Text>,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    oauth_refresh_tokens (id) {
+        id -> Int4,
+        oauth_client_id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        scopes -> Array<Text>,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        rotated_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    password_reset_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    permissions (id) {
+        id -> Int4,
+        #[max_length = 100]
+        name -> Varchar,
+        description -> Text,
+    }
+}
+
+diesel::table! {
+    rate_limit_buckets (key) {
+        #[max_length = 255]
+        key -> Varchar,
+        tokens -> Float8,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        #[max_length = 64]
+        family_id -> Varchar,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    role_permissions (role_id, permission_id) {
+        role_id -> Int4,
+        permission_id -> Int4,
+    }
+}
+
+diesel::table! {
+    roles (id) {
+        id -> Int4,
+        #[max_length = 50]
+        name -> Varchar,
+        description -> Text,
+        created_at -> Timestamp,
+        require_2fa -> Bool,
+    }
+}
+
+diesel::table! {
+    state_entries (key) {
+        #[max_length = 255]
+        key -> Varchar,
+        value -> Text,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    user_roles (user_id, role_id) {
+        user_id -> Int4,
+        role_id -> Int4,
+    }
+}
+
+diesel::table! {
+    user_totp (user_id) {
+        user_id -> Int4,
+        #[max_length = 64]
+        secret -> Varchar,
+        confirmed_at -> Nullable<Timestamp>,
+        last_used_step -> Nullable<Int8>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Int4,
@@ -7,8 +232,44 @@ diesel::table! {
         username -> Varchar,
         #[max_length = 255]
         password -> Varchar,
-        #[max_length = 50]
-        role -> Varchar,
         created_at -> Timestamp,
+        #[max_length = 255]
+        email -> Nullable<Varchar>,
+        email_verified_at -> Nullable<Timestamp>,
+        password_changed_at -> Nullable<Timestamp>,
     }
 }
+
+diesel::joinable!(api_keys -> users (user_id));
+diesel::joinable!(email_verification_tokens -> users (user_id));
+diesel::joinable!(external_identities -> users (user_id));
+diesel::joinable!(oauth_authorization_codes -> oauth_clients (oauth_client_id));
+diesel::joinable!(oauth_authorization_codes -> users (user_id));
+diesel::joinable!(oauth_refresh_tokens -> oauth_clients (oauth_client_id));
+diesel::joinable!(oauth_refresh_tokens -> users (user_id));
+diesel::joinable!(password_reset_tokens -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
+diesel::joinable!(refresh_tokens -> users (user_id));
+diesel::joinable!(role_permissions -> permissions (permission_id));
+diesel::joinable!(role_permissions -> roles (role_id));
+diesel::joinable!(user_roles -> roles (role_id));
+diesel::joinable!(user_roles -> users (user_id));
+diesel::joinable!(user_totp -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    api_keys,
+    audit_events,
+    email_verification_tokens,
+    external_identities,
+    oauth_authorization_codes,
+    oauth_clients,
+    oauth_refresh_tokens,
+    password_reset_tokens,
+    permissions,
+    rate_limit_buckets,
+    role_permissions,
+    roles,
+    state_entries,
+    user_roles,
+    users,
+);
@@ -1,113 +1,469 @@
-use crate::{errors, models};
+use crate::{api_keys, db, errors, jwt_keys::KeyRing, models, roles, tokens};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use log::debug;
-use scrypt::{
-    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Scrypt,
-};
-use std::fmt;
-use warp::{
-    reject, Filter, Rejection,
-};
-
-#[derive(Clone, PartialEq)]
-pub enum Role {
-    User,
-    Admin,
-}
-
-impl Role {
-    pub fn from_str(role: &str) -> Role {
-        match role.to_lowercase().as_str() {
-            "admin" => Role::Admin,
-            _ => Role::User,
+use jsonwebtoken::{decode, decode_header, encode, errors::ErrorKind, Header, Validation};
+use log::{debug, error};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+use warp::{reject, Filter, Rejection};
+
+// Built-in roles from the roles table. Self-registered accounts get
+// USER_ROLE; the bootstrap account gets ADMIN_ROLE. Other roles are only
+// ever referenced by name from the database and the config
+pub const USER_ROLE: &str = "User";
+pub const ADMIN_ROLE: &str = "Admin";
+
+/// Settings from the `[jwt]` section of the server config
+#[derive(Clone, Debug)]
+pub struct JwtConfig {
+    pub access_token_ttl_secs: i64,
+    pub refresh_token_ttl_secs: i64,
+    // When both are sent, use the Authorization header over the jwt cookie
+    pub prefer_bearer: bool,
+    pub issuer: String,
+    pub audience: String,
+    // Clock skew tolerated when checking exp, nbf and iat
+    pub leeway_secs: u64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            access_token_ttl_secs: 900,
+            refresh_token_ttl_secs: 14 * 24 * 3600,
+            prefer_bearer: true,
+            issuer: "webserver".to_string(),
+            audience: "webserver".to_string(),
+            leeway_secs: 30,
         }
     }
 }
 
-impl fmt::Display for Role {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Role::User => write!(f, "User"),
-            Role::Admin => write!(f, "Admin"),
+impl JwtConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = JwtConfig::default();
+        let section = match config.get("jwt") {
+            Some(section) => section,
+            None => return defaults,
+        };
+
+        JwtConfig {
+            access_token_ttl_secs: section
+                .get("access_token_ttl_secs")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.max(1))
+                .unwrap_or(defaults.access_token_ttl_secs),
+            refresh_token_ttl_secs: section
+                .get("refresh_token_ttl_secs")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.max(1))
+                .unwrap_or(defaults.refresh_token_ttl_secs),
+            prefer_bearer: section
+                .get("token_precedence")
+                .and_then(|v| v.as_str())
+                .map(|v| v.eq_ignore_ascii_case("bearer"))
+                .unwrap_or(defaults.prefer_bearer),
+            issuer: section
+                .get("issuer")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string())
+                .unwrap_or(defaults.issuer),
+            audience: section
+                .get("audience")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string())
+                .unwrap_or(defaults.audience),
+            leeway_secs: section
+                .get("leeway_secs")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.max(0) as u64)
+                .unwrap_or(defaults.leeway_secs),
         }
     }
 }
 
-fn get_secret() -> Vec<u8> {
-    std::env::var("JWT_SECRET").unwrap().into_bytes()
+static JWT_CONFIG: OnceLock<JwtConfig> = OnceLock::new();
+
+/// Install the JWT settings; must be called before any token is issued
+pub fn init_jwt_config(config: JwtConfig) {
+    if JWT_CONFIG.set(config).is_err() {
+        debug!("JWT config already initialized");
+    }
+}
+
+pub fn jwt_config() -> &'static JwtConfig {
+    JWT_CONFIG.get_or_init(JwtConfig::default)
+}
+
+static KEY_RING: OnceLock<KeyRing> = OnceLock::new();
+
+/// Install the signing and verification keys; must be called at startup
+pub fn init_key_ring(key_ring: KeyRing) {
+    if KEY_RING.set(key_ring).is_err() {
+        debug!("JWT keys already initialized");
+    }
 }
 
-pub fn get_hashed_password(password: &str) -> String {
-    let salt = SaltString::generate(&mut OsRng);
+pub fn key_ring() -> &'static KeyRing {
+    KEY_RING
+        .get()
+        .expect("JWT keys must be initialized before tokens are used")
+}
 
-    let password_hash = Scrypt
-        .hash_password(password.as_bytes(), &salt)
-        .unwrap()
-        .to_string();
+/// Random opaque token for refresh tokens and similar one-time secrets
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
 
-    password_hash
+/// Hash under which an opaque token is stored, so a database leak does not
+/// expose usable tokens
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
 }
 
-pub fn verify_password(password: &str, password_hash: &str) -> bool {
-    let parsed_hash = PasswordHash::new(password_hash).unwrap();
+/// Sign an access token for the user carrying its effective roles
+pub fn get_jwt_for_user(user: &models::User, roles: Vec<String>) -> Result<String, Rejection> {
+    sign_access_token(&user.username, roles, None)
+}
 
-    Scrypt
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_ok()
+/// Sign an access token for an OAuth client, limited to `scopes`. The token
+/// acts for the user when there is one, otherwise for the client itself
+pub fn get_jwt_for_client(
+    client_id: &str,
+    user: Option<(&models::User, Vec<String>)>,
+    scopes: &[String],
+) -> Result<String, Rejection> {
+    let (sub, roles) = match user {
+        Some((user, roles)) => (user.username.as_str(), roles),
+        None => (client_id, Vec::new()),
+    };
+    sign_access_token(sub, roles, Some((client_id, scopes.join(" "))))
 }
 
-pub fn get_jwt_for_user(user: &models::User) -> String {
-    let expiration_time = Utc::now()
-        .checked_add_signed(Duration::seconds(60))
+fn sign_access_token(
+    sub: &str,
+    roles: Vec<String>,
+    client: Option<(&str, String)>,
+) -> Result<String, Rejection> {
+    let now = Utc::now();
+    let expiration_time = now
+        .checked_add_signed(Duration::seconds(jwt_config().access_token_ttl_secs))
         .expect("invalid timestamp")
         .timestamp();
-    let user_claims = models::Claims {
-        sub: user.username.clone(),
-        role: user.role.clone(),
+    let config = jwt_config();
+    let (client_id, scope) = match client {
+        Some((client_id, scope)) => (Some(client_id.to_string()), Some(scope)),
+        None => (None, None),
+    };
+    let claims = models::Claims {
+        sub: sub.to_string(),
+        roles,
+        iss: config.issuer.clone(),
+        aud: config.audience.clone(),
         exp: expiration_time as usize,
+        nbf: now.timestamp() as usize,
+        iat: now.timestamp() as usize,
+        jti: generate_token(),
+        client_id,
+        scope,
     };
 
-    let token = match encode(
-        &Header::default(),
-        &user_claims,
-        &EncodingKey::from_secret(&get_secret()),
-    ) {
-        Ok(t) => t,
-        Err(_) => panic!(),
+    let signing = &key_ring().signing;
+    let mut header = Header::new(signing.algorithm);
+    header.kid = Some(signing.kid.clone());
+
+    encode(&header, &claims, &signing.encoding_key).map_err(|e| {
+        error!("Failed to sign token for {}: {}", sub, e);
+        reject::custom(errors::CustomError::InternalError)
+    })
+}
+
+/// Decode and validate a token without any role or revocation checks
+pub fn decode_jwt(jwt: &str) -> std::result::Result<models::Claims, errors::CustomError> {
+    let header = decode_header(jwt).map_err(|_| errors::CustomError::InvalidJWTTokenError)?;
+    let key = key_ring()
+        .verification_key(header.kid.as_deref())
+        .ok_or(errors::CustomError::InvalidJWTTokenError)?;
+
+    // The algorithm comes from our key, never from the token header
+    let config = jwt_config();
+    let mut validation = Validation::new(key.algorithm);
+    validation.leeway = config.leeway_secs;
+    validation.validate_nbf = true;
+    validation.set_issuer(&[&config.issuer]);
+    validation.set_audience(&[&config.audience]);
+    validation.set_required_spec_claims(&["sub", "iss", "aud", "exp", "nbf", "iat"]);
+
+    let claims = decode::<models::Claims>(jwt, &key.decoding_key, &validation)
+        .map(|token_data| token_data.claims)
+        .map_err(|e| match e.kind() {
+            ErrorKind::ExpiredSignature => errors::CustomError::ExpiredJWTTokenError,
+            ErrorKind::InvalidAudience | ErrorKind::InvalidIssuer => {
+                errors::CustomError::InvalidJWTAudienceError
+            }
+            _ => errors::CustomError::InvalidJWTTokenError,
+        })?;
+
+    // jsonwebtoken does not check iat; a token from the future is forged or
+    // signed by a badly skewed clock
+    if claims.iat as u64 > Utc::now().timestamp() as u64 + config.leeway_secs {
+        return Err(errors::CustomError::InvalidJWTTokenError);
+    }
+
+    Ok(claims)
+}
+
+// Three non-empty base64url segments, as in every token we issue. Scripts
+// that send "Bearer null" or "Bearer undefined" for a missing token then
+// fall back to the cookie instead of failing
+fn looks_like_jwt(token: &str) -> bool {
+    let segments = token.split('.').collect::<Vec<_>>();
+    segments.len() == 3
+        && segments.iter().all(|segment| {
+            !segment.is_empty()
+                && segment
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+/// The token of an `Authorization: Bearer` header value, if it is shaped
+/// like a JWT
+pub fn bearer_token(authorization: &str) -> Option<String> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    let token = token.trim();
+    if scheme.eq_ignore_ascii_case("bearer") && looks_like_jwt(token) {
+        Some(token.to_string())
+    } else {
+        None
+    }
+}
+
+/// The access token from the `Authorization: Bearer` header or the `jwt`
+/// cookie, in the order set by `token_precedence`
+pub fn with_token() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::cookie::optional::<String>("jwt")
+        .and(warp::header::optional::<String>("authorization"))
+        .map(|cookie: Option<String>, authorization: Option<String>| {
+            let bearer = authorization.as_deref().and_then(bearer_token);
+            if jwt_config().prefer_bearer {
+                bearer.or(cookie)
+            } else {
+                cookie.or(bearer)
+            }
+        })
+}
+
+// Validate a token and make sure it has not been revoked
+fn claims_from_token(jwt: Option<String>) -> Result<models::Claims, Rejection> {
+    let jwt = match jwt {
+        Some(jwt) => jwt,
+        None => return Err(reject::custom(errors::CustomError::InvalidJWTTokenError)),
     };
 
-    token
+    match decode_jwt(&jwt) {
+        Ok(claims) => {
+            if tokens::is_revoked(&claims) {
+                debug!("Rejected revoked token for {}", claims.sub);
+                return Err(reject::custom(errors::CustomError::InvalidJWTTokenError));
+            }
+            Ok(claims)
+        }
+        Err(e) => {
+            debug!("Rejected token: {}", e);
+            Err(reject::custom(e))
+        }
+    }
+}
+
+// Authenticate the request and yield its validated, unrevoked claims
+fn with_claims() -> impl Filter<Extract = (models::Claims,), Error = Rejection> + Clone {
+    with_token().and_then(|jwt: Option<String>| async move { claims_from_token(jwt) })
+}
+
+/// Require a session token, yielding the username. API keys and tokens
+/// issued to OAuth clients are not accepted, so neither can be used to
+/// manage credentials
+pub fn with_session() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    with_claims().and_then(|claims: models::Claims| async move {
+        match claims.client_id {
+            None => Ok(claims.sub),
+            Some(client_id) => {
+                debug!("Token of OAuth client {} used as a session", client_id);
+                Err(reject::custom(errors::CustomError::NotAuthorizedError))
+            }
+        }
+    })
+}
+
+/// The user behind a valid, unrevoked session token, if there is one. For
+/// pages that send anonymous visitors to log in rather than refusing them
+pub fn session_user(jwt: Option<String>) -> Option<String> {
+    match claims_from_token(jwt) {
+        Ok(claims) if claims.client_id.is_none() => Some(claims.sub),
+        _ => None,
+    }
+}
+
+/// Who a request acts for, established from a token or an API key
+pub struct Principal {
+    // The OAuth client id for client credentials tokens
+    pub username: String,
+    // Effective roles, including inherited ones
+    pub roles: Vec<String>,
+    // Permissions an API key or OAuth token is limited to; None for session tokens
+    pub scopes: Option<Vec<String>>,
+    // An OAuth client acting for itself rather than for a user
+    pub is_client: bool,
+}
+
+impl Principal {
+    fn from_claims(claims: models::Claims) -> Self {
+        Principal {
+            is_client: claims.client_id.as_deref() == Some(claims.sub.as_str()),
+            scopes: claims.client_id.map(|_| {
+                claims
+                    .scope
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(|scope| scope.to_string())
+                    .collect()
+            }),
+            username: claims.sub,
+            roles: claims.roles,
+        }
+    }
+}
+
+// A session token wins over X-API-Key; the key is only looked at when no
+// token was sent
+fn with_principal(
+    db_pool: db::DbPool,
+) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone {
+    with_token()
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and_then(move |jwt: Option<String>, api_key: Option<String>| {
+            let db_pool = db_pool.clone();
+            async move {
+                let api_key = match (jwt, api_key) {
+                    (None, Some(api_key)) => api_key,
+                    (jwt, _) => {
+                        return claims_from_token(jwt).map(Principal::from_claims);
+                    }
+                };
+
+                let mut conn = match db_pool.get() {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        error!("Failed to get database connection: {}", e);
+                        return Err(reject::custom(errors::CustomError::InternalError));
+                    }
+                };
+
+                let (user, key) = api_keys::authenticate(&mut conn, api_key.trim())?;
+                Ok(Principal {
+                    roles: roles::effective_roles_for(&mut conn, &user)?,
+                    username: user.username,
+                    scopes: Some(key.scopes),
+                    is_client: false,
+                })
+            }
+        })
 }
 
+/// Require a session token or API key acting for a user, yielding the username
 pub fn with_auth(
-    required_role: Role,
+    db_pool: db::DbPool,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    with_principal(db_pool).and_then(|principal: Principal| async move {
+        if principal.is_client {
+            return Err(reject::custom(errors::CustomError::NotAuthorizedError));
+        }
+        Ok(principal.username)
+    })
+}
+
+/// Require a token or API key whose roles grant `permission`, yielding the
+/// username. API keys and OAuth tokens must also carry the permission as a
+/// scope; a client acting for itself has no roles and relies on the scope
+/// alone. Role grants are read from the database on every request, while a
+/// token's roles come from the token itself and change when it is reissued
+pub fn with_permission(
+    db_pool: db::DbPool,
+    permission: &'static str,
 ) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
-    warp::cookie("jwt").and_then(move |jwt: String| {
-        let required_role = required_role.clone();
+    with_principal(db_pool.clone()).and_then(move |principal: Principal| {
+        let db_pool = db_pool.clone();
         async move {
-            match decode::<models::Claims>(
-                &jwt,
-                &DecodingKey::from_secret(&get_secret()),
-                &Validation::default(),
+            if principal
+                .scopes
+                .as_ref()
+                .is_some_and(|scopes| !scopes.iter().any(|s| s == permission))
+            {
+                debug!(
+                    "Credential of {} lacks scope {}",
+                    principal.username, permission
+                );
+                return Err(reject::custom(errors::CustomError::NotAuthorizedError));
+            }
+            if principal.is_client {
+                return Ok(principal.username);
+            }
+
+            let mut conn = match db_pool.get() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Failed to get database connection: {}", e);
+                    return Err(reject::custom(errors::CustomError::InternalError));
+                }
+            };
+
+            match db::RoleRepository::roles_grant_permission(
+                &mut conn,
+                &principal.roles,
+                permission,
             ) {
-                Ok(token_data) => {
-                    let claims = token_data.claims;
-                    if is_authorized(required_role.clone(), &claims.role) {
-                        Ok(claims.sub)
-                    } else {
-                        Err(reject::custom(errors::CustomError::NotAuthorizedError))
-                    }
+                Ok(true) => Ok(principal.username),
+                Ok(false) => {
+                    debug!("{} lacks permission {}", principal.username, permission);
+                    Err(reject::custom(errors::CustomError::NotAuthorizedError))
+                }
+                Err(e) => {
+                    error!("Failed to check permission {}: {}", permission, e);
+                    Err(reject::custom(errors::CustomError::InternalError))
                 }
-                Err(_) => Err(reject::custom(errors::CustomError::InvalidJWTTokenError)),
             }
         }
     })
 }
 
-fn is_authorized(required_role: Role, claims_role: &str) -> bool {
-    let claims_role = Role::from_str(claims_role);
-    debug!("needed role: {}, user role: {}", required_role, claims_role);
-    required_role == claims_role || claims_role == Role::Admin
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_token_takes_only_jwt_shaped_values() {
+        assert_eq!(
+            bearer_token("Bearer aGVhZGVy.cGF5bG9hZA.c2ln-_0"),
+            Some("aGVhZGVy.cGF5bG9hZA.c2ln-_0".to_string())
+        );
+        assert_eq!(
+            bearer_token("bearer  aGVhZGVy.cGF5bG9hZA.c2ln "),
+            Some("aGVhZGVy.cGF5bG9hZA.c2ln".to_string())
+        );
+        for value in [
+            "Bearer null",
+            "Bearer undefined",
+            "Bearer ",
+            "Bearer a.b",
+            "Bearer a..c",
+            "Bearer a.b.c.d",
+            "Bearer a.b+.c",
+            "Basic aGVhZGVy.cGF5bG9hZA.c2ln",
+        ] {
+            assert_eq!(bearer_token(value), None, "{}", value);
+        }
+    }
 }
@@ -0,0 +1,230 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use log::warn;
+use rand::{rngs::OsRng, RngCore};
+use std::{convert::Infallible, sync::Arc};
+use warp::{
+    http::{header, HeaderMap, HeaderName, HeaderValue},
+    path::FullPath,
+    reply::Response,
+    Filter, Reply,
+};
+
+// Replaced with the request's nonce in content_security_policy
+const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+/// Values of the protective headers; `None` leaves a header out
+#[derive(Clone, Debug)]
+pub struct HeaderPolicy {
+    pub content_security_policy: Option<String>,
+    pub strict_transport_security: Option<String>,
+    pub content_type_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+    pub frame_options: Option<String>,
+}
+
+impl Default for HeaderPolicy {
+    fn default() -> Self {
+        HeaderPolicy {
+            content_security_policy: Some(
+                "default-src 'self'; script-src 'self' 'nonce-{nonce}'; \
+                 style-src 'self' 'unsafe-inline' https://fonts.googleapis.com; \
+                 font-src 'self' https://fonts.gstatic.com; object-src 'none'; \
+                 base-uri 'none'; frame-ancestors 'none'"
+                    .to_string(),
+            ),
+            strict_transport_security: Some("max-age=31536000; includeSubDomains".to_string()),
+            content_type_options: Some("nosniff".to_string()),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+            permissions_policy: Some("camera=(), microphone=(), geolocation=()".to_string()),
+            frame_options: Some("DENY".to_string()),
+        }
+    }
+}
+
+impl HeaderPolicy {
+    // Keys missing from the table keep the value from `base`; an empty
+    // string turns the header off
+    fn from_toml(table: &toml::Value, base: &HeaderPolicy) -> Self {
+        let value = |name: &str, fallback: &Option<String>| match table
+            .get(name)
+            .and_then(|v| v.as_str())
+        {
+            Some(value) if value.trim().is_empty() => None,
+            Some(value) => Some(value.trim().to_string()),
+            None => fallback.clone(),
+        };
+
+        HeaderPolicy {
+            content_security_policy: value(
+                "content_security_policy",
+                &base.content_security_policy,
+            ),
+            strict_transport_security: value(
+                "strict_transport_security",
+                &base.strict_transport_security,
+            ),
+            content_type_options: value("content_type_options", &base.content_type_options),
+            referrer_policy: value("referrer_policy", &base.referrer_policy),
+            permissions_policy: value("permissions_policy", &base.permissions_policy),
+            frame_options: value("frame_options", &base.frame_options),
+        }
+    }
+
+    fn headers(&self) -> [(HeaderName, &Option<String>); 6] {
+        [
+            (
+                header::CONTENT_SECURITY_POLICY,
+                &self.content_security_policy,
+            ),
+            (
+                header::STRICT_TRANSPORT_SECURITY,
+                &self.strict_transport_security,
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, &self.content_type_options),
+            (header::REFERRER_POLICY, &self.referrer_policy),
+            (
+                HeaderName::from_static("permissions-policy"),
+                &self.permissions_policy,
+            ),
+            (header::X_FRAME_OPTIONS, &self.frame_options),
+        ]
+    }
+}
+
+/// Settings from the `[security_headers]` section of the server config
+#[derive(Clone, Debug)]
+pub struct SecurityHeadersConfig {
+    pub enabled: bool,
+    pub default: HeaderPolicy,
+    // Overrides by path prefix, longest first
+    pub routes: Vec<(String, HeaderPolicy)>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig {
+            enabled: true,
+            default: HeaderPolicy::default(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+impl SecurityHeadersConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = SecurityHeadersConfig::default();
+        let section = match config.get("security_headers") {
+            Some(section) => section,
+            None => return defaults,
+        };
+
+        let default = HeaderPolicy::from_toml(section, &defaults.default);
+        let mut routes = Vec::new();
+        if let Some(table) = section.get("routes").and_then(|v| v.as_table()) {
+            for (prefix, overrides) in table {
+                if !prefix.starts_with('/') {
+                    warn!("Ignoring security header overrides for '{}'", prefix);
+                    continue;
+                }
+                let prefix = prefix.trim_end_matches('/').to_string();
+                routes.push((prefix, HeaderPolicy::from_toml(overrides, &default)));
+            }
+        }
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        SecurityHeadersConfig {
+            enabled: section
+                .get("enabled")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.enabled),
+            default,
+            routes,
+        }
+    }
+
+    /// The policy for a request path: the longest matching route override,
+    /// or the default
+    pub fn policy_for(&self, path: &str) -> &HeaderPolicy {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .map(|(_, policy)| policy)
+            .unwrap_or(&self.default)
+    }
+
+    fn apply(&self, path: &str, nonce: Option<&str>, headers: &mut HeaderMap) {
+        for (name, value) in self.policy_for(path).headers() {
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+            // Pages without inline scripts still get a nonce, just one
+            // nothing on the page carries
+            let value = if value.contains(NONCE_PLACEHOLDER) {
+                let nonce = nonce.map(str::to_string).unwrap_or_else(generate_nonce);
+                value.replace(NONCE_PLACEHOLDER, &nonce)
+            } else {
+                value.clone()
+            };
+            match HeaderValue::from_str(&value) {
+                Ok(value) => {
+                    headers.insert(name, value);
+                }
+                Err(_) => warn!("Invalid value for the {} header", name),
+            }
+        }
+    }
+}
+
+// Travels with a response from the handler that rendered the page to the
+// filter that writes the Content-Security-Policy header
+#[derive(Clone)]
+struct CspNonce(String);
+
+/// A fresh nonce for one rendered page
+pub fn generate_nonce() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Mark a page rendered with `nonce` on its inline scripts, so the
+/// Content-Security-Policy sent with it allows exactly those scripts
+pub fn with_nonce(reply: impl Reply, nonce: String) -> Response {
+    let mut response = reply.into_response();
+    response.extensions_mut().insert(CspNonce(nonce));
+    response
+}
+
+/// Add the configured security headers to every reply, error replies
+/// included. Wraps routes that have already been recovered
+pub fn with_security_headers<F, T>(
+    filter: F,
+    config: Arc<SecurityHeadersConfig>,
+) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (T,), Error = Infallible> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    warp::path::full()
+        .and(filter)
+        .and(warp::any().map(move || config.clone()))
+        .map(
+            |path: FullPath, reply: T, config: Arc<SecurityHeadersConfig>| {
+                let mut response = reply.into_response();
+                if config.enabled {
+                    let nonce = response.extensions().get::<CspNonce>().cloned();
+                    config.apply(
+                        path.as_str(),
+                        nonce.as_ref().map(|nonce| nonce.0.as_str()),
+                        response.headers_mut(),
+                    );
+                }
+                response
+            },
+        )
+}
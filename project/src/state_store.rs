@@ -0,0 +1,273 @@
+use crate::{db, models};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::Connection;
+use log::{error, info};
+use std::{collections::HashMap, sync::Arc, sync::Mutex};
+use thiserror::Error;
+
+// Buckets untouched for this long are dropped by `purge_expired`
+const BUCKET_IDLE_HOURS: i64 = 1;
+
+#[derive(Error, Debug)]
+pub enum StateStoreError {
+    #[error("database error: {0}")]
+    Database(#[from] diesel::result::Error),
+    #[error("connection pool error: {0}")]
+    Pool(#[from] r2d2::Error),
+}
+
+/// State of a token bucket after trying to take a token from it
+#[derive(Clone, Copy, Debug)]
+pub struct TokenBucket {
+    pub allowed: bool,
+    pub tokens: f64,
+}
+
+/// Storage for state that must be shared by every server instance:
+/// rate limit counters, token revocations and sticky-session assignments
+pub trait StateStore: Send + Sync {
+    /// Refill the bucket stored under `key` and take one token from it
+    fn take_token(
+        &self,
+        key: &str,
+        capacity: f64,
+        refill_rate: f64,
+    ) -> Result<TokenBucket, StateStoreError>;
+
+    fn get(&self, key: &str) -> Result<Option<String>, StateStoreError>;
+
+    /// Store `value` under `key`, expiring after `ttl` if one is given
+    fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), StateStoreError>;
+
+    fn delete(&self, key: &str) -> Result<(), StateStoreError>;
+
+    /// Remove expired entries and idle buckets, returning how many were dropped
+    fn purge_expired(&self) -> Result<usize, StateStoreError>;
+}
+
+// Add the tokens earned since `updated_at`, then try to spend one
+fn refill_and_take(
+    tokens: f64,
+    updated_at: NaiveDateTime,
+    now: NaiveDateTime,
+    capacity: f64,
+    refill_rate: f64,
+) -> TokenBucket {
+    let elapsed = (now - updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+    let tokens = (tokens + elapsed * refill_rate).min(capacity);
+
+    if tokens >= 1.0 {
+        TokenBucket {
+            allowed: true,
+            tokens: tokens - 1.0,
+        }
+    } else {
+        TokenBucket {
+            allowed: false,
+            tokens,
+        }
+    }
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+/// State kept in process memory; only suitable for a single instance
+#[derive(Default)]
+pub struct MemoryStateStore {
+    buckets: Mutex<HashMap<String, (f64, NaiveDateTime)>>,
+    entries: Mutex<HashMap<String, (String, Option<NaiveDateTime>)>>,
+}
+
+impl MemoryStateStore {
+    pub fn new() -> Self {
+        MemoryStateStore::default()
+    }
+}
+
+impl StateStore for MemoryStateStore {
+    fn take_token(
+        &self,
+        key: &str,
+        capacity: f64,
+        refill_rate: f64,
+    ) -> Result<TokenBucket, StateStoreError> {
+        let now = now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let (tokens, updated_at) = buckets.entry(key.to_string()).or_insert((capacity, now));
+
+        let bucket = refill_and_take(*tokens, *updated_at, now, capacity, refill_rate);
+        *tokens = bucket.tokens;
+        *updated_at = now;
+
+        Ok(bucket)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>, StateStoreError> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .get(key)
+            .and_then(|(value, expires_at)| match expires_at {
+                Some(expires_at) if *expires_at <= now() => None,
+                _ => Some(value.clone()),
+            }))
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), StateStoreError> {
+        let expires_at = ttl.map(|ttl| now() + ttl);
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), (value.to_string(), expires_at));
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StateStoreError> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn purge_expired(&self) -> Result<usize, StateStoreError> {
+        let now = now();
+        let idle_cutoff = now - Duration::hours(BUCKET_IDLE_HOURS);
+
+        let mut entries = self.entries.lock().unwrap();
+        let entries_before = entries.len();
+        entries.retain(|_, (_, expires_at)| expires_at.is_none_or(|e| e > now));
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets_before = buckets.len();
+        buckets.retain(|_, (_, updated_at)| *updated_at >= idle_cutoff);
+
+        Ok(entries_before - entries.len() + buckets_before - buckets.len())
+    }
+}
+
+/// State kept in Postgres so that all instances sharing the database see it
+pub struct PostgresStateStore {
+    db_pool: db::DbPool,
+}
+
+impl PostgresStateStore {
+    pub fn new(db_pool: db::DbPool) -> Self {
+        PostgresStateStore { db_pool }
+    }
+}
+
+impl StateStore for PostgresStateStore {
+    fn take_token(
+        &self,
+        key: &str,
+        capacity: f64,
+        refill_rate: f64,
+    ) -> Result<TokenBucket, StateStoreError> {
+        let mut conn = self.db_pool.get()?;
+        let now = now();
+
+        let bucket = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let stored = db::StateRepository::lock_bucket(
+                conn,
+                &models::RateLimitBucket {
+                    key: key.to_string(),
+                    tokens: capacity,
+                    updated_at: now,
+                },
+            )?;
+
+            let bucket =
+                refill_and_take(stored.tokens, stored.updated_at, now, capacity, refill_rate);
+
+            db::StateRepository::save_bucket(
+                conn,
+                &models::RateLimitBucket {
+                    key: stored.key,
+                    tokens: bucket.tokens,
+                    updated_at: now,
+                },
+            )?;
+
+            Ok(bucket)
+        })?;
+
+        Ok(bucket)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>, StateStoreError> {
+        let mut conn = self.db_pool.get()?;
+        let entry = db::StateRepository::find_entry(&mut conn, key, now())?;
+        Ok(entry.map(|entry| entry.value))
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), StateStoreError> {
+        let mut conn = self.db_pool.get()?;
+        db::StateRepository::upsert_entry(
+            &mut conn,
+            &models::StateEntry {
+                key: key.to_string(),
+                value: value.to_string(),
+                expires_at: ttl.map(|ttl| now() + ttl),
+            },
+        )?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StateStoreError> {
+        let mut conn = self.db_pool.get()?;
+        db::StateRepository::delete_entry(&mut conn, key)?;
+        Ok(())
+    }
+
+    fn purge_expired(&self) -> Result<usize, StateStoreError> {
+        let mut conn = self.db_pool.get()?;
+        let now = now();
+        let purged =
+            db::StateRepository::purge(&mut conn, now, now - Duration::hours(BUCKET_IDLE_HOURS))?;
+        Ok(purged)
+    }
+}
+
+/// Build the store selected by the `[state_store]` section of the server config
+/// and start its background purge task
+pub fn from_config(config: &toml::Value, db_pool: &db::DbPool) -> Arc<dyn StateStore> {
+    let section = config.get("state_store");
+    let backend = section
+        .and_then(|s| s.get("backend"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("memory");
+    let purge_interval_secs = section
+        .and_then(|s| s.get("purge_interval_secs"))
+        .and_then(|v| v.as_integer())
+        .unwrap_or(60)
+        .max(1) as u64;
+
+    let (store, chosen): (Arc<dyn StateStore>, &str) = match backend {
+        "postgres" => (
+            Arc::new(PostgresStateStore::new(db_pool.clone())),
+            "postgres",
+        ),
+        "memory" => (Arc::new(MemoryStateStore::new()), "memory"),
+        other => {
+            error!("Unknown state store backend '{}', using memory", other);
+            (Arc::new(MemoryStateStore::new()), "memory")
+        }
+    };
+    info!("Using {} state store", chosen);
+
+    let purge_store = store.clone();
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(std::time::Duration::from_secs(purge_interval_secs));
+        loop {
+            interval.tick().await;
+            let store = purge_store.clone();
+            match tokio::task::spawn_blocking(move || store.purge_expired()).await {
+                Ok(Ok(purged)) if purged > 0 => info!("Purged {} expired state entries", purged),
+                Ok(Err(e)) => error!("Failed to purge state store: {}", e),
+                _ => {}
+            }
+        }
+    });
+
+    store
+}
@@ -0,0 +1,56 @@
+//! Helpers shared by the unit tests
+
+use crate::db;
+use diesel::{
+    connection::SimpleConnection,
+    pg::PgConnection,
+    r2d2::{self, ConnectionManager},
+};
+use std::{env, fs, path::Path, sync::OnceLock};
+
+/// Pool for the database named by `TEST_DATABASE_URL`, or `None` when it is
+/// not set so that tests needing a database can be skipped. The database is
+/// wiped and every migration applied on first use, so never point this at
+/// a database holding anything worth keeping
+pub fn test_pool() -> Option<db::DbPool> {
+    static POOL: OnceLock<Option<db::DbPool>> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let url = match env::var("TEST_DATABASE_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("TEST_DATABASE_URL is not set; skipping database tests");
+                return None;
+            }
+        };
+        let pool = r2d2::Pool::builder()
+            .max_size(4)
+            .build(ConnectionManager::<PgConnection>::new(url))
+            .expect("Failed to connect to TEST_DATABASE_URL");
+        migrate(&mut pool.get().unwrap());
+        Some(pool)
+    })
+    .clone()
+}
+
+fn migrate(conn: &mut db::DbConnection) {
+    conn.batch_execute("DROP SCHEMA public CASCADE; CREATE SCHEMA public;")
+        .expect("Failed to reset the test database");
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+    let mut migrations = fs::read_dir(&dir)
+        .expect("Failed to read migrations")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.join("up.sql").exists())
+        .collect::<Vec<_>>();
+    migrations.sort();
+    for migration in migrations {
+        let sql = fs::read_to_string(migration.join("up.sql")).unwrap();
+        conn.batch_execute(&sql)
+            .unwrap_or_else(|e| panic!("Migration {} failed: {}", migration.display(), e));
+    }
+}
+
+/// A name no other test run has used, for rows tests create
+pub fn unique(prefix: &str) -> String {
+    format!("{}-{}", prefix, &crate::security::generate_token()[..10])
+}
@@ -0,0 +1,228 @@
+use crate::{db, errors, models, security, state_store::StateStore};
+use chrono::{Duration, Utc};
+use diesel::Connection;
+use log::{error, warn};
+use std::sync::{Arc, OnceLock};
+use warp::{reject, Rejection};
+
+// Revocations are kept in the shared state store so every instance honours them
+static REVOCATION_STORE: OnceLock<Arc<dyn StateStore>> = OnceLock::new();
+
+/// Install the store holding revoked access tokens
+pub fn init_revocation_store(store: Arc<dyn StateStore>) {
+    if REVOCATION_STORE.set(store).is_err() {
+        warn!("Revocation store already initialized");
+    }
+}
+
+fn jti_key(jti: &str) -> String {
+    format!("revoked:jti:{}", jti)
+}
+
+fn user_key(username: &str) -> String {
+    format!("revoked:user:{}", username)
+}
+
+/// Whether the token was revoked individually, or issued before all of its
+/// user's sessions were revoked. Fails closed if the store cannot be read
+pub fn is_revoked(claims: &models::Claims) -> bool {
+    let store = match REVOCATION_STORE.get() {
+        Some(store) => store,
+        None => return false,
+    };
+
+    let jti_revoked = store.get(&jti_key(&claims.jti));
+    let user_revoked_at = store.get(&user_key(&claims.sub));
+
+    match (jti_revoked, user_revoked_at) {
+        (Ok(Some(_)), _) => true,
+        (Ok(None), Ok(Some(revoked_at))) => revoked_at
+            .parse::<usize>()
+            .map(|revoked_at| claims.iat < revoked_at)
+            .unwrap_or(true),
+        (Ok(None), Ok(None)) => false,
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Failed to check token revocation: {}", e);
+            true
+        }
+    }
+}
+
+/// Revoke a single access token for the rest of its lifetime
+pub fn revoke_access_token(claims: &models::Claims) {
+    let store = match REVOCATION_STORE.get() {
+        Some(store) => store,
+        None => return,
+    };
+
+    // The token is still accepted for the leeway after it expires
+    let remaining =
+        claims.exp as i64 + security::jwt_config().leeway_secs as i64 - Utc::now().timestamp();
+    if remaining <= 0 {
+        return;
+    }
+
+    if let Err(e) = store.set(
+        &jti_key(&claims.jti),
+        "",
+        Some(Duration::seconds(remaining)),
+    ) {
+        error!("Failed to revoke token for {}: {}", claims.sub, e);
+    }
+}
+
+/// Revoke the family of the presented refresh token, if it is known
+pub fn revoke_refresh_token(conn: &mut db::DbConnection, presented: &str) {
+    let now = Utc::now().naive_utc();
+    let result = db::RefreshTokenRepository::find_by_hash(conn, &security::hash_token(presented))
+        .and_then(|stored| match stored {
+            Some(stored) => db::RefreshTokenRepository::revoke_family(conn, &stored.family_id, now),
+            None => Ok(0),
+        });
+
+    if let Err(e) = result {
+        error!("Failed to revoke refresh token: {}", e);
+    }
+}
+
+/// Reject every access token issued to the user so far. Refresh tokens stay
+/// valid, so clients can obtain new access tokens reflecting current roles
+pub fn revoke_access_tokens(user: &models::User) -> Result<(), Rejection> {
+    if let Some(store) = REVOCATION_STORE.get() {
+        // Access tokens issued up to now are rejected until they would have
+        // expired anyway, leeway included
+        let config = security::jwt_config();
+        let ttl = Duration::seconds(config.access_token_ttl_secs + config.leeway_secs as i64);
+        if let Err(e) = store.set(
+            &user_key(&user.username),
+            &Utc::now().timestamp().to_string(),
+            Some(ttl),
+        ) {
+            error!("Failed to revoke sessions for {}: {}", user.username, e);
+            return Err(reject::custom(errors::CustomError::InternalError));
+        }
+    }
+
+    Ok(())
+}
+
+/// Invalidate every access and refresh token issued to the user so far,
+/// including those held by OAuth clients
+pub fn revoke_user_sessions(
+    conn: &mut db::DbConnection,
+    user: &models::User,
+) -> Result<(), Rejection> {
+    revoke_access_tokens(user)?;
+
+    // Grants to OAuth clients go too, as they were made from those sessions
+    let now = Utc::now().naive_utc();
+    db::RefreshTokenRepository::revoke_all_for_user(conn, user.id, now)
+        .and_then(|_| db::OAuthTokenRepository::revoke_all_for_user(conn, user.id, now))
+        .map_err(|e| {
+            error!(
+                "Failed to revoke refresh tokens for {}: {}",
+                user.username, e
+            );
+            reject::custom(errors::CustomError::InternalError)
+        })?;
+
+    Ok(())
+}
+
+enum RotateOutcome {
+    Rotated(models::User, String),
+    Reused(models::RefreshToken),
+    Expired,
+    Unknown,
+}
+
+fn new_refresh_token(
+    conn: &mut diesel::PgConnection,
+    user_id: i32,
+    family_id: String,
+) -> Result<String, diesel::result::Error> {
+    let token = security::generate_token();
+    let ttl = Duration::seconds(security::jwt_config().refresh_token_ttl_secs);
+
+    db::RefreshTokenRepository::create(
+        conn,
+        &models::NewRefreshToken {
+            user_id,
+            token_hash: security::hash_token(&token),
+            family_id,
+            expires_at: Utc::now().naive_utc() + ttl,
+        },
+    )?;
+
+    Ok(token)
+}
+
+/// Start a new refresh token family for a fresh login
+pub fn issue_refresh_token(
+    conn: &mut db::DbConnection,
+    user: &models::User,
+) -> Result<String, Rejection> {
+    new_refresh_token(conn, user.id, security::generate_token()).map_err(|e| {
+        error!("Failed to store refresh token: {}", e);
+        reject::custom(errors::CustomError::InternalError)
+    })
+}
+
+/// Exchange a refresh token for a new one in the same family. Presenting a
+/// token that was already rotated or revoked is treated as theft and revokes
+/// every token in its family
+pub fn rotate_refresh_token(
+    conn: &mut db::DbConnection,
+    presented: &str,
+) -> Result<(models::User, String), Rejection> {
+    let now = Utc::now().naive_utc();
+    let hash = security::hash_token(presented);
+
+    let outcome = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let stored = match db::RefreshTokenRepository::lock_by_hash(conn, &hash)? {
+            Some(stored) => stored,
+            None => return Ok(RotateOutcome::Unknown),
+        };
+
+        if stored.used_at.is_some() || stored.revoked_at.is_some() {
+            db::RefreshTokenRepository::revoke_family(conn, &stored.family_id, now)?;
+            return Ok(RotateOutcome::Reused(stored));
+        }
+
+        if stored.expires_at <= now {
+            return Ok(RotateOutcome::Expired);
+        }
+
+        let user = match db::UserRepository::find_by_id(conn, stored.user_id) {
+            Some(user) => user,
+            None => return Ok(RotateOutcome::Unknown),
+        };
+
+        db::RefreshTokenRepository::mark_used(conn, stored.id, now)?;
+        let token = new_refresh_token(conn, stored.user_id, stored.family_id)?;
+
+        Ok(RotateOutcome::Rotated(user, token))
+    });
+
+    match outcome {
+        Ok(RotateOutcome::Rotated(user, token)) => Ok((user, token)),
+        Ok(RotateOutcome::Reused(stored)) => {
+            warn!(
+                target: "security",
+                "Refresh token reuse detected for user id {}, revoked token family {}",
+                stored.user_id,
+                stored.family_id
+            );
+            Err(reject::custom(
+                errors::CustomError::InvalidRefreshTokenError,
+            ))
+        }
+        Ok(RotateOutcome::Expired) | Ok(RotateOutcome::Unknown) => Err(reject::custom(
+            errors::CustomError::InvalidRefreshTokenError,
+        )),
+        Err(e) => {
+            error!("Failed to rotate refresh token: {}", e);
+            Err(reject::custom(errors::CustomError::InternalError))
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use crate::{db, errors, models, roles, security, state_store::StateStore};
+use chrono::{Duration, Utc};
+use log::{error, warn};
+use rand::RngCore;
+use std::sync::{Arc, OnceLock};
+use totp_rs::{Algorithm, Secret, TOTP};
+use warp::{reject, Rejection};
+
+// RFC 6238 defaults understood by every authenticator app
+const DIGITS: usize = 6;
+const STEP_SECS: u64 = 30;
+
+/// Settings from the `[two_factor]` section of the server config
+#[derive(Clone, Debug)]
+pub struct TwoFactorConfig {
+    // Shown as the account's issuer in authenticator apps
+    pub issuer: String,
+    // Lifetime of the token linking the password step to the code step
+    pub pre_auth_ttl_secs: i64,
+    pub recovery_codes: usize,
+    // Codes from this many steps either side of now are accepted
+    pub allowed_skew_steps: u8,
+}
+
+impl Default for TwoFactorConfig {
+    fn default() -> Self {
+        TwoFactorConfig {
+            issuer: "Webserver".to_string(),
+            pre_auth_ttl_secs: 300,
+            recovery_codes: 10,
+            allowed_skew_steps: 1,
+        }
+    }
+}
+
+impl TwoFactorConfig {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let defaults = TwoFactorConfig::default();
+        let section = match config.get("two_factor") {
+            Some(section) => section,
+            None => return defaults,
+        };
+
+        TwoFactorConfig {
+            issuer: section
+                .get("issuer")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string())
+                .unwrap_or(defaults.issuer),
+            pre_auth_ttl_secs: section
+                .get("pre_auth_ttl_secs")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.max(30))
+                .unwrap_or(defaults.pre_auth_ttl_secs),
+            recovery_codes: section
+                .get("recovery_codes")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.clamp(1, 50) as usize)
+                .unwrap_or(defaults.recovery_codes),
+            allowed_skew_steps: section
+                .get("allowed_skew_steps")
+                .and_then(|v| v.as_integer())
+                .map(|v| v.clamp(0, 5) as u8)
+                .unwrap_or(defaults.allowed_skew_steps),
+        }
+    }
+}
+
+static CONFIG: OnceLock<TwoFactorConfig> = OnceLock::new();
+// Pre-auth tokens are kept in the shared state store so any instance can
+// finish a login another one started
+static PRE_AUTH_STORE: OnceLock<Arc<dyn StateStore>> = OnceLock::new();
+
+/// Install the 2FA settings and the store holding pre-auth tokens
+pub fn init(config: TwoFactorConfig, store: Arc<dyn StateStore>) {
+    if CONFIG.set(config).is_err() || PRE_AUTH_STORE.set(store).is_err() {
+        warn!("Two-factor authentication already initialized");
+    }
+}
+
+pub fn config() -> &'static TwoFactorConfig {
+    CONFIG.get_or_init(TwoFactorConfig::default)
+}
+
+fn internal_error(context: &str, e: impl std::fmt::Display) -> Rejection {
+    error!("{}: {}", context, e);
+    reject::custom(errors::CustomError::InternalError)
+}
+
+fn pre_auth_key(token: &str) -> String {
+    format!("preauth:{}", security::hash_token(token))
+}
+
+fn store() -> Result<&'static Arc<dyn StateStore>, Rejection> {
+    PRE_AUTH_STORE
+        .get()
+        .ok_or_else(|| internal_error("Two-factor authentication", "not initialized"))
+}
+
+// Short-lived opaque token proving the password step succeeded
+fn issue_pre_auth_token(user: &models::User) -> Result<String, Rejection> {
+    let token = security::generate_token();
+    store()?
+        .set(
+            &pre_auth_key(&token),
+            &user.id.to_string(),
+            Some(Duration::seconds(config().pre_auth_ttl_secs)),
+        )
+        .map_err(|e| internal_error("Failed to store pre-auth token", e))?;
+    Ok(token)
+}
+
+/// The user a pre-auth token was issued to, if it is still valid
+pub fn pre_auth_user(conn: &mut db::DbConnection, token: &str) -> Result<models::User, Rejection> {
+    let user_id = store()?
+        .get(&pre_auth_key(token))
+        .map_err(|e| internal_error("Failed to read pre-auth token", e))?
+        .and_then(|id| id.parse::<i32>().ok())
+        .ok_or_else(|| reject::custom(errors::CustomError::InvalidCredentialsError))?;
+
+    db::UserRepository::find_by_id(conn, user_id)
+        .ok_or_else(|| reject::custom(errors::CustomError::InvalidCredentialsError))
+}
+
+/// Invalidate a pre-auth token once the login it belongs to has completed
+pub fn consume_pre_auth_token(token: &str) {
+    if let Ok(store) = store() {
+        if let Err(e) = store.delete(&pre_auth_key(token)) {
+            error!("Failed to delete pre-auth token: {}", e);
+        }
+    }
+}
+
+/// After a correct password, decide whether the login needs a second factor.
+/// Returns the challenge to send instead of tokens, or None to log in now
+pub fn login_challenge(
+    conn: &mut db::DbConnection,
+    user: &models::User,
+) -> Result<Option<models::TwoFactorChallenge>, Rejection> {
+    let enrolled = db::TwoFactorRepository::find(conn, user.id)
+        .map_err(|e| internal_error("Failed to read 2FA settings", e))?
+        .is_some_and(|totp| totp.confirmed_at.is_some());
+
+    let enrollment_required = !enrolled && is_required(conn, user)?;
+    if !enrolled && !enrollment_required {
+        return Ok(None);
+    }
+
+    Ok(Some(models::TwoFactorChallenge {
+        two_factor_required: true,
+        enrollment_required,
+        pre_auth_token: issue_pre_auth_token(user)?,
+        expires_in: config().pre_auth_ttl_secs,
+    }))
+}
+
+/// Whether any of the user's effective roles requires 2FA
+pub fn is_required(conn: &mut db::DbConnection, user: &models::User) -> Result<bool, Rejection> {
+    let effective = roles::effective_roles_for(conn, user)?;
+    db::RoleRepository::any_requires_two_factor(conn, &effective)
+        .map_err(|e| internal_error("Failed to check 2FA requirement", e))
+}
+
+fn totp(secret: &str, username: &str) -> Result<TOTP, Rejection> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| internal_error("Invalid stored TOTP secret", format!("{:?}", e)))?;
+
+    // Skew is handled by `verify_code` so that the matching step can be recorded
+    TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        0,
+        STEP_SECS,
+        secret,
+        Some(config().issuer.clone()),
+        // The label may not contain a colon
+        username.replace(':', "_"),
+    )
+    .map_err(|e| internal_error("Failed to build TOTP", e))
+}
+
+/// Start (or restart) enrollment with a fresh secret. The secret only takes
+/// effect once confirmed with a valid code
+pub fn begin_enrollment(
+    conn: &mut db::DbConnection,
+    user: &models::User,
+) -> Result<models::TotpEnrollment, Rejection> {
+    let existing = db::TwoFactorRepository::find(conn, user.id)
+        .map_err(|e| internal_error("Failed to read 2FA settings", e))?;
+    if existing.is_some_and(|totp| totp.confirmed_at.is_some()) {
+        return Err(reject::custom(errors::CustomError::InvalidRequestError(
+            "two-factor authentication is already enabled".to_string(),
+        )));
+    }
+
+    let secret = Secret::generate_secret().to_encoded().to_string();
+    let totp = totp(&secret, &user.username)?;
+    db::TwoFactorRepository::save_pending(conn, user.id, &secret, Utc::now().naive_utc())
+        .map_err(|e| internal_error("Failed to store TOTP secret", e))?;
+
+    let uri = totp.get_url();
+    Ok(models::TotpEnrollment {
+        secret,
+        provisioning_uri: uri.clone(),
+        qr_payload: uri,
+    })
+}
+
+/// Check a code against the user's secret, confirmed or pending. Returns
+/// whether it was accepted and, if it confirmed a pending enrollment, the
+/// freshly generated recovery codes
+pub fn verify_code(
+    conn: &mut db::DbConnection,
+    user: &models::User,
+    code: &str,
+) -> Result<(bool, Option<Vec<String>>), Rejection> {
+    let stored = match db::TwoFactorRepository::find(conn, user.id)
+        .map_err(|e| internal_error("Failed to read 2FA settings", e))?
+    {
+        Some(stored) => stored,
+        None => return Ok((false, None)),
+    };
+
+    let totp = totp(&stored.secret, &user.username)?;
+    let now = Utc::now().timestamp() as u64;
+    let skew = config().allowed_skew_steps as u64;
+    let current = now / STEP_SECS;
+    let matched = (current.saturating_sub(skew)..=current + skew)
+        .find(|step| totp.check(code.trim(), step * STEP_SECS));
+
+    let step = match matched {
+        Some(step) => step as i64,
+        None => return Ok((false, None)),
+    };
+
+    // A code is only good once, even within its time window
+    let fresh = db::TwoFactorRepository::use_step(conn, user.id, step)
+        .map_err(|e| internal_error("Failed to record TOTP use", e))?;
+    if fresh == 0 {
+        warn!(target: "security", "Replayed TOTP code for {}", user.username);
+        return Ok((false, None));
+    }
+
+    if stored.confirmed_at.is_some() {
+        return Ok((true, None));
+    }
+
+    db::TwoFactorRepository::confirm(conn, user.id, Utc::now().naive_utc())
+        .map_err(|e| internal_error("Failed to confirm TOTP enrollment", e))?;
+    warn!(target: "security", "Two-factor authentication enabled for {}", user.username);
+    Ok((true, Some(regenerate_recovery_codes(conn, user)?)))
+}
+
+fn recovery_code() -> String {
+    let mut bytes = [0u8; 10];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    let code = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}",
+        &code[0..5],
+        &code[5..10],
+        &code[10..15],
+        &code[15..20]
+    )
+}
+
+// Recovery codes are compared without dashes, spaces or case
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Replace the user's recovery codes, returning the new plaintext codes.
+/// They are shown once and only their hashes are kept
+pub fn regenerate_recovery_codes(
+    conn: &mut db::DbConnection,
+    user: &models::User,
+) -> Result<Vec<String>, Rejection> {
+    let codes = (0..config().recovery_codes)
+        .map(|_| recovery_code())
+        .collect::<Vec<_>>();
+    let hashes = codes
+        .iter()
+        .map(|code| security::hash_token(&normalize_recovery_code(code)))
+        .collect::<Vec<_>>();
+
+    db::TwoFactorRepository::replace_recovery_codes(conn, user.id, &hashes)
+        .map_err(|e| internal_error("Failed to store recovery codes", e))?;
+    Ok(codes)
+}
+
+/// Spend a recovery code in place of a TOTP code
+pub fn use_recovery_code(
+    conn: &mut db::DbConnection,
+    user: &models::User,
+    code: &str,
+) -> Result<bool, Rejection> {
+    let hash = security::hash_token(&normalize_recovery_code(code));
+    let used =
+        db::TwoFactorRepository::use_recovery_code(conn, user.id, &hash, Utc::now().naive_utc())
+            .map_err(|e| internal_error("Failed to use recovery code", e))?;
+
+    if used > 0 {
+        warn!(target: "security", "Recovery code used by {}", user.username);
+    }
+    Ok(used > 0)
+}